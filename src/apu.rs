@@ -1,9 +1,67 @@
+use std::mem;
+
+use super::savestate::{Savable, StateError, StateReader, StateWriter};
+
+pub const SAMPLE_RATE: u32 = 1_048_576;
+
+const WAVE_RAM_SIZE: usize = 0x10;
+
+// Bits that always read back as 1, indexed from NR10 (0xFF10) to NR52
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// The four sound generators, for muting or rebalancing while debugging.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Voice {
+    PulseA,
+    PulseB,
+    Wave,
+    Noise,
+}
+
+pub const VOICES: [Voice; 4] = [Voice::PulseA, Voice::PulseB, Voice::Wave, Voice::Noise];
+
+impl Voice {
+    pub fn from_name(name: &str) -> Option<Voice> {
+        match name {
+            "pulse_a" => Some(Voice::PulseA),
+            "pulse_b" => Some(Voice::PulseB),
+            "wave" => Some(Voice::Wave),
+            "noise" => Some(Voice::Noise),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StereoSample {
+    pub left: f32,
+    pub right: f32,
+}
+
 pub struct Apu {
     enabled: bool,
     channel_1: Channel,
     channel_2: Channel,
-    pulse_a: PulseA,
-    pulse_b: PulseB,
+    pulse_a: Pulse,
+    pulse_b: Pulse,
+    sweep: Sweep,
+    wave: Wave,
+    noise: Noise,
+
+    voice_volumes: [f32; 4],
+    voice_muted: [bool; 4],
+
+    frame_step: u8,
+    cycles: u32,
+    samples: Vec<StereoSample>,
+    voice_samples: Option<Vec<[StereoSample; 4]>>,
 }
 
 impl Apu {
@@ -12,35 +70,233 @@ impl Apu {
             enabled: false,
             channel_1: Channel::new(),
             channel_2: Channel::new(),
-            pulse_a: PulseA::new(),
-            pulse_b: PulseB::new(),
+            pulse_a: Pulse::new(),
+            pulse_b: Pulse::new(),
+            sweep: Sweep::new(),
+            wave: Wave::new(),
+            noise: Noise::new(),
+
+            voice_volumes: [1.0; 4],
+            voice_muted: [false; 4],
+
+            frame_step: 0,
+            cycles: 0,
+            samples: Vec::new(),
+            voice_samples: None,
+        }
+    }
+
+    /// Advances every channel by `cycles` T-cycles, producing one stereo
+    /// sample per M-cycle (`SAMPLE_RATE`).
+    pub fn step(&mut self, cycles: u32) {
+        self.cycles += cycles;
+
+        while self.cycles >= 4 {
+            self.cycles -= 4;
+
+            self.pulse_a.step(4);
+            self.pulse_b.step(4);
+            self.wave.step(4);
+            self.noise.step(4);
+
+            let voices = self.mix_voices();
+            let mut sample = StereoSample::default();
+            for voice in voices.iter() {
+                sample.left += voice.left;
+                sample.right += voice.right;
+            }
+            self.samples.push(sample);
+
+            if let Some(ref mut voice_samples) = self.voice_samples {
+                voice_samples.push(voices);
+            }
+        }
+    }
+
+    /// Advances the 512 Hz frame sequencer, clocked by the falling edge of
+    /// DIV bit 4.
+    ///
+    /// Step   Length  Envelope  Sweep
+    /// 0      Clock   -         -
+    /// 1      -       -         -
+    /// 2      Clock   -         Clock
+    /// 3      -       -         -
+    /// 4      Clock   -         -
+    /// 5      -       -         -
+    /// 6      Clock   -         Clock
+    /// 7      -       Clock     -
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.frame_step.is_multiple_of(2) {
+            self.clock_lengths();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.sweep.clock(&mut self.pulse_a);
+        }
+        if self.frame_step == 7 {
+            self.pulse_a.envelope.clock();
+            self.pulse_b.envelope.clock();
+            self.noise.envelope.clock();
+        }
+
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    fn clock_lengths(&mut self) {
+        if self.pulse_a.sound_length.clock() {
+            self.pulse_a.enabled = false;
+        }
+        if self.pulse_b.sound_length.clock() {
+            self.pulse_b.enabled = false;
+        }
+        if self.wave.sound_length.clock() {
+            self.wave.enabled = false;
+        }
+        if self.noise.sound_length.clock() {
+            self.noise.enabled = false;
+        }
+    }
+
+    pub fn set_voice_volume(&mut self, voice: Voice, volume: f32) {
+        self.voice_volumes[voice as usize] = volume;
+    }
+
+    pub fn set_voice_muted(&mut self, voice: Voice, muted: bool) {
+        self.voice_muted[voice as usize] = muted;
+    }
+
+    /// Hands over every sample produced since the last call.
+    pub fn take_samples(&mut self) -> Vec<StereoSample> {
+        mem::take(&mut self.samples)
+    }
+
+    /// Starts or stops keeping each voice's contribution to the mix
+    /// separately, indexed by `Voice`.
+    pub fn set_capture_voices(&mut self, capture: bool) {
+        self.voice_samples = if capture { Some(Vec::new()) } else { None };
+    }
+
+    pub fn take_voice_samples(&mut self) -> Vec<[StereoSample; 4]> {
+        match self.voice_samples {
+            Some(ref mut voice_samples) => mem::take(voice_samples),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        let value = match addr & 0xFF {
+            0x10 => self.sweep.read(),
+            0x11 | 0x16 => {
+                let pulse = if addr & 0xFF == 0x11 { &self.pulse_a } else { &self.pulse_b };
+                pulse.wave_duty.to_u8() << 6
+            },
+            0x12 => self.pulse_a.envelope.read(),
+            0x14 => (self.pulse_a.sound_length.enabled as u8) << 6,
+            0x17 => self.pulse_b.envelope.read(),
+            0x19 => (self.pulse_b.sound_length.enabled as u8) << 6,
+            0x1A => (self.wave.dac_enabled as u8) << 7,
+            0x1C => self.wave.output_level.to_u8() << 5,
+            0x1E => (self.wave.sound_length.enabled as u8) << 6,
+            0x21 => self.noise.envelope.read(),
+            0x22 => {
+                let width = match self.noise.width {
+                    LfsrWidth::FifteenBit => 0,
+                    LfsrWidth::SevenBit => 1,
+                };
+                self.noise.clock_shift << 4 | width << 3 | self.noise.divisor_code
+            },
+            0x23 => (self.noise.sound_length.enabled as u8) << 6,
+            0x24 => {
+                (self.channel_2.vin as u8) << 7 | self.channel_2.volume << 4 |
+                    (self.channel_1.vin as u8) << 3 | self.channel_1.volume
+            },
+            0x25 => self.channel_2.voices.bits() << 4 | self.channel_1.voices.bits(),
+            0x26 => {
+                (self.enabled as u8) << 7 |
+                    (self.noise.enabled as u8) << 3 |
+                    (self.wave.enabled as u8) << 2 |
+                    (self.pulse_b.enabled as u8) << 1 |
+                    (self.pulse_a.enabled as u8)
+            },
+            0x30..=0x3F => return self.wave.read_ram(addr),
+            _ => 0x00,
+        };
+
+        match addr & 0xFF {
+            0x10..=0x26 => value | READ_MASKS[(addr & 0xFF) as usize - 0x10],
+            _ => 0xFF,
         }
     }
 
     pub fn write_byte(&mut self, addr: u16, value: u8) {
+        if !self.enabled {
+            // While powered off only NR52, wave RAM and (on the DMG) the
+            // length counters accept writes.
+            match addr & 0xFF {
+                0x11 => self.pulse_a.sound_length.load(value & 0x3F),
+                0x16 => self.pulse_b.sound_length.load(value & 0x3F),
+                0x1B => self.wave.sound_length.load(value),
+                0x20 => self.noise.sound_length.load(value & 0x3F),
+                0x26 => self.write_power(value),
+                0x30..=0x3F => self.wave.write_ram(addr, value),
+                _ => {}
+            }
+            return;
+        }
+
+        // Enabling length in a step that won't clock it gets an extra clock
+        let extra_length_clock = self.frame_step % 2 == 1;
+
         match addr & 0xFF {
-            0x11 => {
-                self.pulse_a.sound_length = 64 - (value & 0x3F);
-
-                match value >> 6 {
-                    0 => self.pulse_a.wave_duty = WaveDuty::HalfQuarter,
-                    1 => self.pulse_a.wave_duty = WaveDuty::Quarter,
-                    2 => self.pulse_a.wave_duty = WaveDuty::Half,
-                    3 => self.pulse_a.wave_duty = WaveDuty::ThreeQuarters,
-                    _ => panic!()
+            0x10 => {
+                let disable = self.sweep.write(value);
+                if disable {
+                    self.pulse_a.enabled = false;
+                }
+            },
+            0x11 => self.pulse_a.write_length_duty(value),
+            0x12 => self.pulse_a.write_envelope(value),
+            0x13 => self.pulse_a.frequency = (self.pulse_a.frequency & 0x700) | value as u16,
+            0x14 => {
+                let triggered = self.pulse_a.write_control(value, extra_length_clock);
+                if triggered {
+                    self.sweep.trigger(&mut self.pulse_a);
                 }
             },
-            0x12 => {
-                self.pulse_a.envelope.count = value & 0x7;
-                self.pulse_a.envelope.direction = match (value & 0x8) >> 3 {
-                    0 => Direction::Decreasing,
-                    1 => Direction::Increasing,
+            0x16 => self.pulse_b.write_length_duty(value),
+            0x17 => self.pulse_b.write_envelope(value),
+            0x18 => self.pulse_b.frequency = (self.pulse_b.frequency & 0x700) | value as u16,
+            0x19 => {
+                self.pulse_b.write_control(value, extra_length_clock);
+            },
+            0x1A => {
+                self.wave.dac_enabled = value & 0x80 == 0x80;
+                if !self.wave.dac_enabled {
+                    self.wave.enabled = false;
+                }
+            },
+            0x1B => self.wave.sound_length.load(value),
+            0x1C => self.wave.output_level = OutputLevel::from_u8((value >> 5) & 0x3),
+            0x1D => self.wave.frequency = (self.wave.frequency & 0x700) | value as u16,
+            0x1E => self.wave.write_control(value, extra_length_clock),
+            0x20 => self.noise.sound_length.load(value & 0x3F),
+            0x21 => self.noise.write_envelope(value),
+            0x22 => {
+                self.noise.clock_shift = value >> 4;
+                self.noise.width = match (value & 0x8) >> 3 {
+                    0 => LfsrWidth::FifteenBit,
+                    1 => LfsrWidth::SevenBit,
                     _ => panic!("{:#x}", (value & 0x8) >> 3)
                 };
+                self.noise.divisor_code = value & 0x7;
             },
+            0x23 => self.noise.write_control(value, extra_length_clock),
             0x24 => {
-                self.channel_1.vin = (value & 0x8) == 0x8;
-                self.channel_2.vin = (value & 0x8) == 0x8;
+                self.channel_1.vin = (value & 0x08) == 0x08;
+                self.channel_2.vin = (value & 0x80) == 0x80;
 
                 self.channel_1.volume = value & 0x7;
                 self.channel_2.volume = value >> 4 & 0x7;
@@ -49,43 +305,497 @@ impl Apu {
                 self.channel_1.voices = Voices::from_bits_truncate(value);
                 self.channel_2.voices= Voices::from_bits_truncate(value >> 4);
             },
-            0x26 => self.enabled = value >> 7 == 1,
-            _ => panic!("Unimpletemented Apu write at address: {:#x}: {:#x}",addr, value)
+            0x26 => self.write_power(value),
+            0x30..=0x3F => self.wave.write_ram(addr, value),
+            _ => {}
         }
     }
+
+    fn write_power(&mut self, value: u8) {
+        let enabled = value >> 7 == 1;
+
+        if self.enabled && !enabled {
+            self.power_off();
+        } else if !self.enabled && enabled {
+            // The frame sequencer restarts so that its next step is 0
+            self.frame_step = 0;
+        }
+
+        self.enabled = enabled;
+    }
+
+    /// Clears every register. Wave RAM and the length counters survive on the
+    /// DMG.
+    fn power_off(&mut self) {
+        let lengths = [
+            self.pulse_a.sound_length.counter,
+            self.pulse_b.sound_length.counter,
+            self.wave.sound_length.counter,
+            self.noise.sound_length.counter,
+        ];
+        let wave_ram = self.wave.wave_ram;
+
+        self.channel_1 = Channel::new();
+        self.channel_2 = Channel::new();
+        self.pulse_a = Pulse::new();
+        self.pulse_b = Pulse::new();
+        self.sweep = Sweep::new();
+        self.wave = Wave::new();
+        self.noise = Noise::new();
+
+        self.pulse_a.sound_length.counter = lengths[0];
+        self.pulse_b.sound_length.counter = lengths[1];
+        self.wave.sound_length.counter = lengths[2];
+        self.noise.sound_length.counter = lengths[3];
+        self.wave.wave_ram = wave_ram;
+    }
+
+    /// Pans each voice through NR51 and scales it by NR50, giving every
+    /// voice's share of the final mix.
+    fn mix_voices(&self) -> [StereoSample; 4] {
+        let mut mixed = [StereoSample::default(); 4];
+        if !self.enabled {
+            return mixed;
+        }
+
+        let outputs = [
+            (Voices::PULSE_A, self.pulse_a.dac_output()),
+            (Voices::PULSE_B, self.pulse_b.dac_output()),
+            (Voices::WAVE, self.wave.dac_output()),
+            (Voices::NOISE, self.noise.dac_output()),
+        ];
+
+        for (i, &(voice, output)) in outputs.iter().enumerate() {
+            if self.voice_muted[i] {
+                continue;
+            }
+            let output = output * self.voice_volumes[i] / 4.0;
+
+            if self.channel_1.voices.contains(voice) {
+                mixed[i].right = output * self.channel_1.gain();
+            }
+            if self.channel_2.voices.contains(voice) {
+                mixed[i].left = output * self.channel_2.gain();
+            }
+        }
+
+        mixed
+    }
 }
 
-#[derive(Debug)]
-struct PulseA {
-    wave_duty: WaveDuty,
-    sound_length: u8,
-    envelope: Envelope
+/// Converts a 4-bit channel output into the -1.0..1.0 range the way the
+/// DMG's DACs do. A disabled DAC outputs silence rather than -1.0.
+fn dac(dac_enabled: bool, digital: u8) -> f32 {
+    if dac_enabled {
+        digital as f32 / 7.5 - 1.0
+    } else {
+        0.0
+    }
 }
 
-struct PulseB {
+struct Pulse {
+    enabled: bool,
+    dac_enabled: bool,
     wave_duty: WaveDuty,
-    sound_length: u8,
-    envelope: Envelope
+    duty_position: u8,
+    sound_length: SoundLength,
+    envelope: Envelope,
+    frequency: u16,
+    timer: u32,
 }
 
-impl PulseA {
+impl Pulse {
     fn new() -> Self {
-        PulseA {
-            wave_duty: WaveDuty::Half,
-            sound_length: 0,
-            envelope: Envelope::new()
+        Pulse {
+            enabled: false,
+            dac_enabled: false,
+            wave_duty: WaveDuty::HalfQuarter,
+            duty_position: 0,
+            sound_length: SoundLength::new(64),
+            envelope: Envelope::new(),
+            frequency: 0,
+            timer: 0,
+        }
+    }
+
+    fn write_length_duty(&mut self, value: u8) {
+        self.sound_length.load(value & 0x3F);
+
+        match value >> 6 {
+            0 => self.wave_duty = WaveDuty::HalfQuarter,
+            1 => self.wave_duty = WaveDuty::Quarter,
+            2 => self.wave_duty = WaveDuty::Half,
+            3 => self.wave_duty = WaveDuty::ThreeQuarters,
+            _ => panic!()
+        }
+    }
+
+    fn write_envelope(&mut self, value: u8) {
+        self.envelope.write(value);
+        self.dac_enabled = value & 0xF8 != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
+
+    /// Returns true when the write triggered the channel.
+    fn write_control(&mut self, value: u8, extra_length_clock: bool) -> bool {
+        self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x7) << 8);
+
+        if self.sound_length.write_control(value, extra_length_clock) {
+            self.enabled = false;
+        }
+
+        let trigger = value & 0x80 == 0x80;
+        if trigger {
+            self.enabled = self.dac_enabled;
+            self.timer = self.period();
+            self.envelope.trigger();
+        }
+        trigger
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    fn dac_output(&self) -> f32 {
+        let high = self.wave_duty.pattern() >> (7 - self.duty_position) & 0x1 == 0x1;
+        let digital = if self.enabled && high { self.envelope.volume } else { 0 };
+        dac(self.dac_enabled, digital)
+    }
+}
+
+#[derive(Debug)]
+struct Sweep {
+    enabled: bool,
+    period: u8,
+    direction: Direction,
+    shift: u8,
+    timer: u8,
+    shadow_frequency: u16,
+    negated: bool,
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Sweep {
+            enabled: false,
+            period: 0,
+            direction: Direction::Increasing,
+            shift: 0,
+            timer: 0,
+            shadow_frequency: 0,
+            negated: false,
+        }
+    }
+
+    /// Returns true when the write must disable pulse A: leaving negate mode
+    /// after a calculation has been made with it.
+    fn write(&mut self, value: u8) -> bool {
+        self.period = (value >> 4) & 0x7;
+        self.direction = match (value & 0x8) >> 3 {
+            0 => Direction::Increasing,
+            1 => Direction::Decreasing,
+            _ => panic!("{:#x}", (value & 0x8) >> 3)
+        };
+        self.shift = value & 0x7;
+
+        self.negated && self.direction == Direction::Increasing
+    }
+
+    fn read(&self) -> u8 {
+        let negate = match self.direction {
+            Direction::Increasing => 0,
+            Direction::Decreasing => 1,
+        };
+        self.period << 4 | negate << 3 | self.shift
+    }
+
+    fn trigger(&mut self, pulse: &mut Pulse) {
+        self.shadow_frequency = pulse.frequency;
+        self.timer = self.reload();
+        self.enabled = self.period != 0 || self.shift != 0;
+        self.negated = false;
+
+        if self.shift != 0 {
+            self.calculate(pulse);
+        }
+    }
+
+    fn clock(&mut self, pulse: &mut Pulse) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer != 0 {
+            return;
+        }
+
+        self.timer = self.reload();
+
+        if self.enabled && self.period != 0 {
+            let frequency = self.calculate(pulse);
+            if frequency <= 2047 && self.shift != 0 {
+                self.shadow_frequency = frequency;
+                pulse.frequency = frequency;
+
+                // The new frequency is immediately checked for overflow again
+                self.calculate(pulse);
+            }
+        }
+    }
+
+    /// Computes the next frequency, disabling the channel on overflow.
+    fn calculate(&mut self, pulse: &mut Pulse) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        let frequency = match self.direction {
+            Direction::Increasing => self.shadow_frequency + delta,
+            Direction::Decreasing => {
+                self.negated = true;
+                self.shadow_frequency - delta
+            },
+        };
+
+        if frequency > 2047 {
+            pulse.enabled = false;
+        }
+        frequency
+    }
+
+    fn reload(&self) -> u8 {
+        if self.period == 0 { 8 } else { self.period }
+    }
+}
+
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    sound_length: SoundLength,
+    output_level: OutputLevel,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+    sample_buffer: u8,
+    sample_fetched: bool,
+    wave_ram: [u8; WAVE_RAM_SIZE],
+}
+
+impl Wave {
+    fn new() -> Self {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+            sound_length: SoundLength::new(256),
+            output_level: OutputLevel::Mute,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample_buffer: 0,
+            sample_fetched: false,
+            wave_ram: [0; WAVE_RAM_SIZE],
+        }
+    }
+
+    fn write_control(&mut self, value: u8, extra_length_clock: bool) {
+        self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x7) << 8);
+
+        if self.sound_length.write_control(value, extra_length_clock) {
+            self.enabled = false;
+        }
+
+        if value & 0x80 == 0x80 {
+            self.enabled = self.dac_enabled;
+            self.timer = self.period();
+            self.position = 0;
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    /// While the channel plays, the CPU can only reach the byte the channel
+    /// is reading, and only in the same cycle the channel reads it.
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.enabled {
+            self.wave_ram[(addr & 0xF) as usize]
+        } else if self.sample_fetched {
+            self.wave_ram[self.position as usize / 2]
+        } else {
+            0xFF
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        if !self.enabled {
+            self.wave_ram[(addr & 0xF) as usize] = value;
+        } else if self.sample_fetched {
+            self.wave_ram[self.position as usize / 2] = value;
+        }
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        self.sample_fetched = false;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+            self.sample_fetched = true;
+
+            let byte = self.wave_ram[self.position as usize / 2];
+            self.sample_buffer = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0xF };
+        }
+        self.timer -= cycles;
+    }
+
+    fn dac_output(&self) -> f32 {
+        let digital = if self.enabled {
+            self.sample_buffer >> self.output_level.shift()
+        } else {
+            0
+        };
+        dac(self.dac_enabled, digital)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum OutputLevel {
+    Mute,
+    Full,
+    Half,
+    Quarter,
+}
+
+impl OutputLevel {
+    fn to_u8(self) -> u8 {
+        match self {
+            OutputLevel::Mute => 0x00,
+            OutputLevel::Full => 0x01,
+            OutputLevel::Half => 0x02,
+            OutputLevel::Quarter => 0x03,
+        }
+    }
+
+    fn from_u8(value: u8) -> OutputLevel {
+        match value {
+            0x00 => OutputLevel::Mute,
+            0x01 => OutputLevel::Full,
+            0x02 => OutputLevel::Half,
+            0x03 => OutputLevel::Quarter,
+            _ => panic!("Unrecognized output level: {:#X}", value)
+        }
+    }
+
+    fn shift(&self) -> u8 {
+        match *self {
+            OutputLevel::Mute => 4,
+            OutputLevel::Full => 0,
+            OutputLevel::Half => 1,
+            OutputLevel::Quarter => 2,
         }
     }
 }
 
-impl PulseB {
+struct Noise {
+    enabled: bool,
+    dac_enabled: bool,
+    sound_length: SoundLength,
+    envelope: Envelope,
+    clock_shift: u8,
+    width: LfsrWidth,
+    divisor_code: u8,
+    lfsr: u16,
+    timer: u32,
+}
+
+impl Noise {
     fn new() -> Self {
-        PulseB {
-            wave_duty: WaveDuty::Half,
-            sound_length: 0,
-            envelope: Envelope::new()
+        Noise {
+            enabled: false,
+            dac_enabled: false,
+            sound_length: SoundLength::new(64),
+            envelope: Envelope::new(),
+            clock_shift: 0,
+            width: LfsrWidth::FifteenBit,
+            divisor_code: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+        }
+    }
+
+    fn write_envelope(&mut self, value: u8) {
+        self.envelope.write(value);
+        self.dac_enabled = value & 0xF8 != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
+
+    fn write_control(&mut self, value: u8, extra_length_clock: bool) {
+        if self.sound_length.write_control(value, extra_length_clock) {
+            self.enabled = false;
+        }
+
+        if value & 0x80 == 0x80 {
+            self.enabled = self.dac_enabled;
+            self.timer = self.period();
+            self.envelope.trigger();
+            self.lfsr = 0x7FFF;
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+
+            // Shifts 14 and 15 leave the LFSR unclocked
+            if self.clock_shift < 14 {
+                self.clock_lfsr();
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_lfsr(&mut self) {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 0x1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+
+        if let LfsrWidth::SevenBit = self.width {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
         }
     }
+
+    fn dac_output(&self) -> f32 {
+        let digital = if self.enabled && self.lfsr & 0x1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        };
+        dac(self.dac_enabled, digital)
+    }
+}
+
+#[derive(Debug)]
+enum LfsrWidth {
+    FifteenBit,
+    SevenBit,
 }
 
 #[derive(Debug)]
@@ -96,24 +806,144 @@ enum WaveDuty {
     ThreeQuarters,
 }
 
+impl WaveDuty {
+    fn to_u8(&self) -> u8 {
+        match *self {
+            WaveDuty::HalfQuarter => 0,
+            WaveDuty::Quarter => 1,
+            WaveDuty::Half => 2,
+            WaveDuty::ThreeQuarters => 3,
+        }
+    }
+
+    /// Waveform for one period, played most significant bit first.
+    fn pattern(&self) -> u8 {
+        match *self {
+            WaveDuty::HalfQuarter => 0b0000_0001,
+            WaveDuty::Quarter => 0b1000_0001,
+            WaveDuty::Half => 0b1000_0111,
+            WaveDuty::ThreeQuarters => 0b0111_1110,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SoundLength {
+    max: u16,
+    counter: u16,
+    enabled: bool,
+}
+
+impl SoundLength {
+    fn new(max: u16) -> Self {
+        SoundLength {
+            max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    fn load(&mut self, value: u8) {
+        self.counter = self.max - value as u16;
+    }
+
+    /// Returns true when the counter runs out and the channel must stop.
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+
+    /// Handles the length enable and trigger bits of an NRx4 write. Returns
+    /// true when the extra length clock ran the counter out.
+    fn write_control(&mut self, value: u8, extra_length_clock: bool) -> bool {
+        let was_enabled = self.enabled;
+        let trigger = value & 0x80 == 0x80;
+        self.enabled = value & 0x40 == 0x40;
+
+        let mut expired = false;
+        if extra_length_clock && !was_enabled && self.enabled {
+            expired = self.clock() && !trigger;
+        }
+
+        if trigger && self.counter == 0 {
+            self.counter = self.max;
+            if extra_length_clock && self.enabled {
+                self.counter -= 1;
+            }
+        }
+
+        expired
+    }
+}
+
 #[derive(Debug)]
 struct Envelope {
+    initial_volume: u8,
     volume: u8,
     direction: Direction,
     count: u8,
+    timer: u8,
 }
 
 impl Envelope {
     fn new () -> Self {
         Envelope {
+            initial_volume: 0,
             volume: 0,
-            direction: Direction::Increasing,
+            direction: Direction::Decreasing,
             count: 0,
+            timer: 0,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        let direction = match self.direction {
+            Direction::Decreasing => 0,
+            Direction::Increasing => 1,
+        };
+        self.initial_volume << 4 | direction << 3 | self.count
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.count;
+    }
+
+    fn clock(&mut self) {
+        if self.count == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
         }
+        if self.timer == 0 {
+            self.timer = self.count;
+
+            match self.direction {
+                Direction::Increasing if self.volume < 15 => self.volume += 1,
+                Direction::Decreasing if self.volume > 0 => self.volume -= 1,
+                _ => {}
+            }
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.count = value & 0x7;
+        self.direction = match (value & 0x8) >> 3 {
+            0 => Direction::Decreasing,
+            1 => Direction::Increasing,
+            _ => panic!("{:#x}", (value & 0x8) >> 3)
+        };
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 enum Direction {
     Increasing,
     Decreasing,
@@ -134,13 +964,502 @@ impl Channel {
             voices: Voices::empty(),
         }
     }
+
+    fn gain(&self) -> f32 {
+        (self.volume + 1) as f32 / 8.0
+    }
 }
 
 bitflags! {
-    flags Voices: u8 {
-        const PULSE_A = 1 << 0,
-        const PULSE_B = 1 << 1,
-        const WAVE    = 1 << 2,
-        const NOISE   = 1 << 3,
+    struct Voices: u8 {
+        const PULSE_A = 1 << 0;
+        const PULSE_B = 1 << 1;
+        const WAVE    = 1 << 2;
+        const NOISE   = 1 << 3;
+    }
+}
+
+impl Savable for Apu {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        self.channel_1.save(state);
+        self.channel_2.save(state);
+        self.pulse_a.save(state);
+        self.pulse_b.save(state);
+        self.sweep.save(state);
+        self.wave.save(state);
+        self.noise.save(state);
+        state.write_u8(self.frame_step);
+        state.write_u32(self.cycles);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.channel_1.load(state)?;
+        self.channel_2.load(state)?;
+        self.pulse_a.load(state)?;
+        self.pulse_b.load(state)?;
+        self.sweep.load(state)?;
+        self.wave.load(state)?;
+        self.noise.load(state)?;
+        self.frame_step = state.read_u8()?;
+        self.cycles = state.read_u32()?;
+        Ok(())
+    }
+}
+
+impl Savable for Pulse {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        self.wave_duty.save(state);
+        state.write_u8(self.duty_position);
+        self.sound_length.save(state);
+        self.envelope.save(state);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.wave_duty.load(state)?;
+        self.duty_position = state.read_u8()? & 0x07;
+        Savable::load(&mut self.sound_length, state)?;
+        self.envelope.load(state)?;
+        self.frequency = state.read_u16()?;
+        self.timer = state.read_u32()?;
+        Ok(())
+    }
+}
+
+impl Savable for Sweep {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u8(self.period);
+        self.direction.save(state);
+        state.write_u8(self.shift);
+        state.write_u8(self.timer);
+        state.write_u16(self.shadow_frequency);
+        state.write_bool(self.negated);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.period = state.read_u8()?;
+        self.direction.load(state)?;
+        self.shift = state.read_u8()?;
+        self.timer = state.read_u8()?;
+        self.shadow_frequency = state.read_u16()?;
+        self.negated = state.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Savable for Wave {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        self.sound_length.save(state);
+        self.output_level.save(state);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer);
+        state.write_u8(self.position);
+        state.write_u8(self.sample_buffer);
+        state.write_bool(self.sample_fetched);
+        state.write_bytes(&self.wave_ram);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        Savable::load(&mut self.sound_length, state)?;
+        self.output_level.load(state)?;
+        self.frequency = state.read_u16()?;
+        self.timer = state.read_u32()?;
+        self.position = state.read_u8()? & 0x1F;
+        self.sample_buffer = state.read_u8()?;
+        self.sample_fetched = state.read_bool()?;
+        state.read_bytes(&mut self.wave_ram)
+    }
+}
+
+impl Savable for Noise {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        self.sound_length.save(state);
+        self.envelope.save(state);
+        state.write_u8(self.clock_shift);
+        self.width.save(state);
+        state.write_u8(self.divisor_code);
+        state.write_u16(self.lfsr);
+        state.write_u32(self.timer);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        Savable::load(&mut self.sound_length, state)?;
+        self.envelope.load(state)?;
+        self.clock_shift = state.read_u8()?;
+        self.width.load(state)?;
+        self.divisor_code = state.read_u8()? & 0x07;
+        self.lfsr = state.read_u16()?;
+        self.timer = state.read_u32()?;
+        Ok(())
+    }
+}
+
+impl Savable for SoundLength {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u16(self.max);
+        state.write_u16(self.counter);
+        state.write_bool(self.enabled);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.max = state.read_u16()?;
+        self.counter = state.read_u16()?;
+        self.enabled = state.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Savable for Envelope {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(self.initial_volume);
+        state.write_u8(self.volume);
+        self.direction.save(state);
+        state.write_u8(self.count);
+        state.write_u8(self.timer);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.initial_volume = state.read_u8()?;
+        self.volume = state.read_u8()?;
+        self.direction.load(state)?;
+        self.count = state.read_u8()?;
+        self.timer = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Savable for Channel {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bool(self.vin);
+        state.write_u8(self.volume);
+        state.write_u8(self.voices.bits());
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.vin = state.read_bool()?;
+        self.volume = state.read_u8()? & 0x07;
+        self.voices = Voices::from_bits_truncate(state.read_u8()?);
+        Ok(())
+    }
+}
+
+impl Savable for WaveDuty {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(self.to_u8());
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        *self = match state.read_variant(4, "wave duty")? {
+            0 => WaveDuty::HalfQuarter,
+            1 => WaveDuty::Quarter,
+            2 => WaveDuty::Half,
+            _ => WaveDuty::ThreeQuarters,
+        };
+        Ok(())
+    }
+}
+
+impl Savable for OutputLevel {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(self.to_u8());
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        *self = OutputLevel::from_u8(state.read_variant(4, "output level")?);
+        Ok(())
+    }
+}
+
+impl Savable for LfsrWidth {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(match *self {
+            LfsrWidth::FifteenBit => 0,
+            LfsrWidth::SevenBit => 1,
+        });
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        *self = match state.read_variant(2, "LFSR width")? {
+            0 => LfsrWidth::FifteenBit,
+            _ => LfsrWidth::SevenBit,
+        };
+        Ok(())
+    }
+}
+
+impl Savable for Direction {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(match *self {
+            Direction::Increasing => 0,
+            Direction::Decreasing => 1,
+        });
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        *self = match state.read_variant(2, "direction")? {
+            0 => Direction::Increasing,
+            _ => Direction::Decreasing,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered_on() -> Apu {
+        let mut apu = Apu::new();
+        apu.write_byte(0xFF26, 0x80);
+        apu
+    }
+
+    #[test]
+    fn registers_read_back_through_masks() {
+        let mut apu = powered_on();
+        for addr in 0xFF10..0xFF26 {
+            apu.write_byte(addr, 0x00);
+        }
+        for addr in 0xFF10..0xFF26 {
+            assert_eq!(apu.read_byte(addr), READ_MASKS[addr as usize - 0xFF10], "{:04X}", addr);
+        }
+        assert_eq!(apu.read_byte(0xFF26), 0xF0);
+        for addr in 0xFF27..0xFF30 {
+            assert_eq!(apu.read_byte(addr), 0xFF, "{:04X}", addr);
+        }
+    }
+
+    #[test]
+    fn writable_bits_read_back() {
+        let mut apu = powered_on();
+        apu.write_byte(0xFF10, 0x7A);
+        apu.write_byte(0xFF11, 0x80);
+        apu.write_byte(0xFF24, 0x5C);
+        apu.write_byte(0xFF25, 0xA5);
+        assert_eq!(apu.read_byte(0xFF10), 0xFA);
+        assert_eq!(apu.read_byte(0xFF11), 0xBF);
+        assert_eq!(apu.read_byte(0xFF24), 0x5C);
+        assert_eq!(apu.read_byte(0xFF25), 0xA5);
+    }
+
+    #[test]
+    fn writes_are_ignored_while_powered_off() {
+        let mut apu = Apu::new();
+        apu.write_byte(0xFF24, 0x77);
+        apu.write_byte(0xFF30, 0x12);
+        assert_eq!(apu.read_byte(0xFF24), 0x00);
+        assert_eq!(apu.read_byte(0xFF30), 0x12);
+        assert_eq!(apu.read_byte(0xFF26), 0x70);
+    }
+
+    // One sample per M-cycle for `count` M-cycles
+    fn run(apu: &mut Apu, count: usize) -> Vec<StereoSample> {
+        apu.take_samples();
+        apu.step(4 * count as u32);
+        apu.take_samples()
+    }
+
+    #[test]
+    fn pulse_duty_sets_the_share_of_high_samples() {
+        for &(duty, high) in [(0x00, 1), (0x40, 2), (0x80, 4), (0xC0, 6)].iter() {
+            let mut apu = powered_on();
+            apu.write_byte(0xFF24, 0x77);
+            apu.write_byte(0xFF25, 0x11);
+            apu.write_byte(0xFF11, duty);
+            apu.write_byte(0xFF12, 0xF0);
+            // Frequency 2047 advances the duty position every M-cycle
+            apu.write_byte(0xFF13, 0xFF);
+            apu.write_byte(0xFF14, 0x87);
+
+            let samples = run(&mut apu, 8);
+            assert_eq!(samples.iter().filter(|sample| sample.left == 0.25).count(), high, "{:02X}", duty);
+            assert_eq!(samples.iter().filter(|sample| sample.left == -0.25).count(), 8 - high, "{:02X}", duty);
+        }
+    }
+
+    #[test]
+    fn sweep_overflow_on_clock_disables_pulse_a() {
+        let mut apu = powered_on();
+        apu.write_byte(0xFF10, 0x11);
+        apu.write_byte(0xFF12, 0xF0);
+        apu.write_byte(0xFF13, 0xE8);
+        apu.write_byte(0xFF14, 0x83);
+
+        // Sweep clocks on step 2: 1000 becomes 1500, and the recheck for
+        // 2250 overflows
+        apu.clock_frame_sequencer();
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.read_byte(0xFF26) & 0x01, 0x01);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.pulse_a.frequency, 1500);
+        assert_eq!(apu.read_byte(0xFF26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn wave_plays_ram_shifted_by_output_level() {
+        for &(level, expected) in [(0x20, 15), (0x40, 7), (0x60, 3), (0x00, 0)].iter() {
+            let mut apu = powered_on();
+            apu.write_byte(0xFF24, 0x77);
+            apu.write_byte(0xFF25, 0x44);
+            apu.write_byte(0xFF30, 0x0F);
+            apu.write_byte(0xFF31, 0xF0);
+            apu.write_byte(0xFF1A, 0x80);
+            apu.write_byte(0xFF1C, level);
+            // Frequency 2046 moves to the next sample every M-cycle
+            apu.write_byte(0xFF1D, 0xFE);
+            apu.write_byte(0xFF1E, 0x87);
+
+            let samples = run(&mut apu, 3);
+            let high = (expected as f32 / 7.5 - 1.0) / 4.0;
+            assert_eq!(samples[0].left, high, "{:02X}", level);
+            assert_eq!(samples[1].left, high, "{:02X}", level);
+            assert_eq!(samples[2].left, -0.25, "{:02X}", level);
+        }
+    }
+
+    // Clocks until the bits in `mask` return to where they started
+    fn lfsr_period(apu: &mut Apu, mask: u16) -> usize {
+        let start = apu.noise.lfsr & mask;
+        (1..).find(|_| {
+            apu.noise.clock_lfsr();
+            apu.noise.lfsr & mask == start
+        }).unwrap()
+    }
+
+    #[test]
+    fn lfsr_width_sets_the_noise_period() {
+        let mut apu = powered_on();
+        apu.write_byte(0xFF22, 0x00);
+        assert_eq!(lfsr_period(&mut apu, 0x7FFF), 32767);
+
+        apu.write_byte(0xFF22, 0x08);
+        apu.noise.lfsr = 0x7FFF;
+        assert_eq!(lfsr_period(&mut apu, 0x7F), 127);
+    }
+
+    #[test]
+    fn nr51_pans_and_nr50_scales() {
+        let mut apu = powered_on();
+        apu.write_byte(0xFF12, 0xF0);
+        apu.write_byte(0xFF14, 0x80);
+
+        apu.write_byte(0xFF24, 0x30);
+        apu.write_byte(0xFF25, 0x11);
+        let sample = run(&mut apu, 1)[0];
+        assert_eq!((sample.left, sample.right), (-0.125, -0.03125));
+
+        apu.write_byte(0xFF25, 0x01);
+        let sample = run(&mut apu, 1)[0];
+        assert_eq!((sample.left, sample.right), (0.0, -0.03125));
+
+        apu.write_byte(0xFF25, 0x10);
+        let sample = run(&mut apu, 1)[0];
+        assert_eq!((sample.left, sample.right), (-0.125, 0.0));
+    }
+
+    // Pulse A on with the DAC powered, `length` clocks from silence
+    fn trigger_pulse_a(apu: &mut Apu, length: u8) {
+        apu.write_byte(0xFF11, 64 - length);
+        apu.write_byte(0xFF12, 0xF0);
+        apu.write_byte(0xFF14, 0xC0);
+    }
+
+    #[test]
+    fn frame_sequencer_clocks_length_on_even_steps() {
+        let mut apu = powered_on();
+        trigger_pulse_a(&mut apu, 2);
+        assert_eq!(apu.read_byte(0xFF26) & 0x01, 0x01);
+
+        apu.clock_frame_sequencer();
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.read_byte(0xFF26) & 0x01, 0x01);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.read_byte(0xFF26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn enabling_length_on_an_odd_step_clocks_it_early() {
+        let mut apu = powered_on();
+        apu.write_byte(0xFF11, 63);
+        apu.write_byte(0xFF12, 0xF0);
+        apu.write_byte(0xFF14, 0x80);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.read_byte(0xFF26) & 0x01, 0x01);
+
+        apu.write_byte(0xFF14, 0x40);
+        assert_eq!(apu.read_byte(0xFF26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn sweep_overflow_on_trigger_disables_pulse_a() {
+        let mut apu = powered_on();
+        apu.write_byte(0xFF10, 0x11);
+        apu.write_byte(0xFF12, 0xF0);
+        apu.write_byte(0xFF13, 0xFF);
+        apu.write_byte(0xFF14, 0x87);
+        assert_eq!(apu.read_byte(0xFF26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn frame_sequencer_stops_while_powered_off() {
+        let mut apu = powered_on();
+        trigger_pulse_a(&mut apu, 1);
+        apu.write_byte(0xFF26, 0x00);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.frame_step, 0);
+    }
+
+    #[test]
+    fn wave_ram_is_only_reachable_as_the_channel_reads_it() {
+        let mut apu = powered_on();
+        for addr in 0xFF30..0xFF40 {
+            apu.write_byte(addr, (addr as u8 & 0xF) * 0x11);
+        }
+        apu.write_byte(0xFF1A, 0x80);
+        apu.write_byte(0xFF1C, 0x20);
+        // Frequency 2044 reads a sample every other M-cycle
+        apu.write_byte(0xFF1D, 0xFC);
+        apu.write_byte(0xFF1E, 0x87);
+
+        apu.step(4);
+        assert_eq!(apu.read_byte(0xFF30), 0xFF);
+        apu.write_byte(0xFF30, 0x12);
+
+        // Position 1 is in byte 0, whichever address is accessed
+        apu.step(4);
+        assert_eq!(apu.read_byte(0xFF3F), 0x00);
+        apu.write_byte(0xFF3F, 0xAB);
+        assert_eq!(apu.read_byte(0xFF30), 0xAB);
+
+        apu.write_byte(0xFF1A, 0x00);
+        assert_eq!(apu.read_byte(0xFF30), 0xAB);
+        assert_eq!(apu.read_byte(0xFF3F), 0xFF);
+        assert_eq!(apu.read_byte(0xFF31), 0x11);
+    }
+
+    #[test]
+    fn powering_off_clears_registers() {
+        let mut apu = powered_on();
+        apu.write_byte(0xFF24, 0x77);
+        apu.write_byte(0xFF26, 0x00);
+        apu.write_byte(0xFF26, 0x80);
+        assert_eq!(apu.read_byte(0xFF24), 0x00);
     }
 }
@@ -0,0 +1,77 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sdl2;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+
+use rustboy::StereoSample;
+use rustboy::resampler;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
+// Queue depth, in stereo frames, the rate control steers towards
+const TARGET_LATENCY_FRAMES: u32 = 2048;
+
+// Largest deviation from the nominal resampling ratio. 0.5% is well under
+// what the ear can pick up as a pitch change.
+const MAX_RATE_DELTA: f64 = 0.005;
+
+const BYTES_PER_FRAME: u32 = 4;
+
+// Longest wait for the queue to drain before giving up on a stalled device
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// Plays APU output through an SDL2 audio queue.
+///
+/// The emulator's frame rate never exactly matches the host's audio clock,
+/// so the resampling ratio is nudged every push according to how full the
+/// queue is: a draining queue gets slightly more samples, an overfull one
+/// slightly fewer. This keeps latency stable without audible pitch changes.
+pub struct AudioOutput {
+    queue: AudioQueue<i16>,
+    resampler: resampler::Resampler,
+}
+
+impl AudioOutput {
+    pub fn new(sdl: &sdl2::Sdl, sample_rate: u32) -> Result<Self, String> {
+        let audio = sdl.audio()?;
+
+        let desired = AudioSpecDesired {
+            freq: Some(sample_rate as i32),
+            channels: Some(2),
+            samples: Some(1024),
+        };
+        let queue = audio.open_queue::<i16>(None, &desired)?;
+        let output_rate = queue.spec().freq as u32;
+        queue.resume();
+
+        Ok(AudioOutput {
+            queue,
+            resampler: resampler::Resampler::new(output_rate),
+        })
+    }
+
+    /// Stereo frames waiting in the SDL queue.
+    pub fn queued_frames(&self) -> u32 {
+        self.queue.size() / BYTES_PER_FRAME
+    }
+
+    /// Blocks until the queue is down to the target latency. Pacing frames
+    /// on this keeps emulation locked to the audio device's clock.
+    pub fn wait_for_space(&self) {
+        let start = Instant::now();
+        while self.queued_frames() > TARGET_LATENCY_FRAMES && start.elapsed() < MAX_WAIT {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    pub fn push(&mut self, samples: &[StereoSample]) {
+        let fill = self.queued_frames() as f64 / (2 * TARGET_LATENCY_FRAMES) as f64;
+        let fill = fill.min(1.0);
+        self.resampler.set_rate_adjust(1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * fill));
+
+        self.resampler.push(samples);
+        let output = self.resampler.read_i16();
+        self.queue.queue(&output);
+    }
+}
@@ -0,0 +1,202 @@
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+const ROM_EXTENSIONS: [&str; 3] = ["gb", "gbc", "sgb"];
+
+const TITLE_START: usize = 0x134;
+const TITLE_END: usize = 0x144;
+const GLOBAL_CHECKSUM: usize = 0x14E;
+
+// The largest ROM any MBC can map, so a compressed file can't expand into
+// more memory than a real cartridge has
+pub const MAX_ROM_SIZE: u64 = 8 * 1024 * 1024;
+
+/// A game ROM and the header fields read from it.
+pub struct Cartridge {
+    rom: Vec<u8>,
+}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>) -> Self {
+        Cartridge {
+            rom,
+        }
+    }
+
+    /// Loads a ROM file, which may also be gzipped or inside a zip archive.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        read_rom(path).map(Cartridge::new)
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    /// The title from the header, up to the first NUL.
+    pub fn title(&self) -> String {
+        let end = TITLE_END.min(self.rom.len());
+        let start = TITLE_START.min(end);
+
+        self.rom[start..end].iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| byte as char)
+            .collect()
+    }
+
+    /// Big-endian sum of every ROM byte except the checksum itself, as
+    /// stored at 0x14E. Zero if the header is missing.
+    pub fn global_checksum(&self) -> u16 {
+        match self.rom.get(GLOBAL_CHECKSUM..GLOBAL_CHECKSUM + 2) {
+            Some(bytes) => (bytes[0] as u16) << 8 | bytes[1] as u16,
+            None => 0,
+        }
+    }
+}
+
+/// Reads a ROM image. A `.gz` file is decompressed and a `.zip` archive
+/// yields the first `.gb`, `.gbc` or `.sgb` file inside it. Anything larger
+/// than `MAX_ROM_SIZE` once decompressed is refused.
+pub fn read_rom<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let path = path.as_ref();
+    let file = fs::File::open(path)?;
+
+    match extension(path).as_deref() {
+        Some("gz") => read_limited(GzDecoder::new(file)),
+        Some("zip") => {
+            let mut archive = ZipArchive::new(file)?;
+            let index = (0..archive.len())
+                .find(|&index| {
+                    archive.by_index(index)
+                        .map(|entry| is_rom_name(Path::new(entry.name())))
+                        .unwrap_or(false)
+                })
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no .gb, .gbc or .sgb file in archive"))?;
+            let rom = read_limited(archive.by_index(index)?)?;
+            Ok(rom)
+        },
+        _ => read_limited(file),
+    }
+}
+
+fn read_limited<R: Read>(reader: R) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.take(MAX_ROM_SIZE + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > MAX_ROM_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("ROM is larger than {} MiB", MAX_ROM_SIZE / (1024 * 1024))));
+    }
+    Ok(data)
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension().and_then(|extension| extension.to_str()).map(|extension| extension.to_lowercase())
+}
+
+fn is_rom_name(path: &Path) -> bool {
+    match extension(path) {
+        Some(extension) => ROM_EXTENSIONS.contains(&extension.as_str()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use zip::ZipWriter;
+    use zip::write::FileOptions;
+
+    // Unique to this process, so concurrent test runs can't collide
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("rustboy-cartridge-{}-{}", process::id(), name))
+    }
+
+    fn header_rom(title: &str) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[TITLE_START..TITLE_START + title.len()].copy_from_slice(title.as_bytes());
+        rom[GLOBAL_CHECKSUM] = 0x12;
+        rom[GLOBAL_CHECKSUM + 1] = 0x34;
+        rom
+    }
+
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut zip = ZipWriter::new(fs::File::create(path).unwrap());
+        for &(name, data) in entries {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn reads_the_header() {
+        let cartridge = Cartridge::new(header_rom("TETRIS"));
+        assert_eq!(cartridge.title(), "TETRIS");
+        assert_eq!(cartridge.global_checksum(), 0x1234);
+
+        let empty = Cartridge::new(Vec::new());
+        assert_eq!(empty.title(), "");
+        assert_eq!(empty.global_checksum(), 0);
+    }
+
+    #[test]
+    fn reads_gzipped_roms() {
+        let rom = header_rom("GZIP");
+        let path = temp_path("game.gb.gz");
+        let mut encoder = GzEncoder::new(fs::File::create(&path).unwrap(), Compression::default());
+        encoder.write_all(&rom).unwrap();
+        encoder.finish().unwrap();
+
+        let result = read_rom(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap(), rom);
+    }
+
+    #[test]
+    fn reads_the_first_rom_in_a_zip() {
+        let rom = header_rom("ZIP");
+        let path = temp_path("game.zip");
+        write_zip(&path, &[("readme.txt", b"not a rom"), ("Game.GBC", &rom), ("other.gb", b"second")]);
+
+        let result = read_rom(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap(), rom);
+    }
+
+    #[test]
+    fn refuses_zips_without_a_rom() {
+        let path = temp_path("empty.zip");
+        write_zip(&path, &[("readme.txt", b"not a rom")]);
+
+        let result = read_rom(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn refuses_roms_that_decompress_too_large() {
+        let path = temp_path("bomb.gb.gz");
+        let mut encoder = GzEncoder::new(fs::File::create(&path).unwrap(), Compression::best());
+        let zeros = vec![0; 1024 * 1024];
+        for _ in 0..=MAX_ROM_SIZE / zeros.len() as u64 {
+            encoder.write_all(&zeros).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        let result = read_rom(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
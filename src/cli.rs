@@ -0,0 +1,494 @@
+use std::path::{Path, PathBuf};
+
+use rustboy::{Model, Voice};
+use rustboy::trace;
+
+use super::pacing::Sync;
+use super::palette::Palette;
+use super::video::{Filter, Shader};
+
+pub const USAGE: &str = "\
+Usage: rustboy [OPTIONS] <ROM>
+       rustboy disasm [--output <FILE>] [--symbols <FILE>] <ROM>
+
+ROM may be a .gb, .gbc or .sgb file, gzipped, or inside a .zip archive.
+
+Options:
+    --boot-rom <FILE>         Run this boot ROM first instead of skipping it
+    --model <MODEL>           dmg, mgb, cgb or sgb [default: dmg]
+    --scale <N>               Window scale factor [default: 3]
+    --palette <PALETTE>       grey, green, pocket or four RRGGBB colors
+                              separated by commas, lightest first
+    --filter <FILTER>         nearest or linear scaling [default: nearest]
+    --shader <SHADER>         none, lcd, ghosting or crt [default: none]
+    --fullscreen              Start fullscreen
+    --no-integer-scale        Fill the window instead of scaling by whole
+                              multiples
+    --sync <SYNC>             Pace frames on the audio device or a timer
+                              [default: audio]
+    --fast-forward <N>        Fast-forward speed from 0.01 to 100, 0 for
+                              uncapped [default: 0]
+    --slow-motion <N>         Slow-motion speed from 0.01 to 100
+                              [default: 0.5]
+    --frame-skip <N>          Only draw one in every N+1 frames
+    --rewind <SECONDS>        How far back rewind can go, 0 to turn it off
+                              [default: 20]
+    --rewind-interval <N>     Frames between rewind snapshots [default: 2]
+    --headless                Run without a window, audio or input
+    --frames <N>              Exit after N frames
+    --debug                   Start in the command-line debugger instead
+                              of a window
+    --gdb <PORT>              Run headless under GDB instead of a window:
+                              wait for it to attach on a local port, halted
+                              at the first instruction
+    --trace <stdout|FILE>     Log every instruction executed in the
+                              Gameboy Doctor format
+    --trace-range <START-END> Only trace PCs in this range, in hex
+    --trace-bank <BANK>       Only trace code running from this ROM bank
+    --trace-symbols           End each trace line with the nearest symbol
+    --symbols <FILE>          RGBDS or WLA-DX symbols for the debugger,
+                              disassembly and traces [default: the ROM's
+                              name with a .sym extension, if it exists]
+    --record-movie <FILE>     Record input from power-on to a movie
+    --play-movie <FILE>       Play back a .rbm, BizHawk .bk2 or VBA .vbm
+                              movie; headless runs stop when it ends
+    --save-dir <DIR>          Where screenshots and saves go
+                              [default: the ROM's directory]
+    --input-config <FILE>     Key and controller bindings
+    --record-audio <FILE>     Record the audio mix to a WAV file
+    --record-voices           Also record each voice to its own file
+    --mute <VOICES>           Leave voices out of the mix: pulse_a, pulse_b,
+                              wave or noise, separated by commas
+    --voice-volume <VOICE=N>  Scale a voice in the mix from 0 to 4, 1 being
+                              its normal level; may be given once per voice
+    --serial <stdout|FILE>    Log bytes sent over the link port
+    --printer <DIR>           Connect a Game Boy Printer saving to DIR
+    --link <ROM>              Run a second Game Boy linked to this one
+    --link-listen <PORT>      Wait for a link partner on a local port
+    --link-connect <PORT>     Connect to a link partner on a local port
+    -h, --help                Print this help
+
+disasm writes the whole ROM as assembly, bank by bank, to FILE or to
+standard output, labelled from the same symbol file the debugger uses.";
+
+pub struct Options {
+    pub rom: PathBuf,
+    pub boot_rom: Option<PathBuf>,
+    pub model: Model,
+    pub scale: u32,
+    pub palette: Palette,
+    pub filter: Filter,
+    pub shader: Shader,
+    pub fullscreen: bool,
+    pub integer_scale: bool,
+    pub sync: Sync,
+    pub fast_forward_speed: f64,
+    pub slow_motion_speed: f64,
+    pub frame_skip: u32,
+    pub rewind_seconds: f64,
+    pub rewind_interval: u32,
+    pub headless: bool,
+    pub frames: Option<u32>,
+    pub debug: bool,
+    pub gdb: Option<u16>,
+    pub trace: Option<String>,
+    pub trace_range: Option<(u16, u16)>,
+    pub trace_bank: Option<u16>,
+    pub trace_symbols: bool,
+    pub symbols: Option<PathBuf>,
+    pub record_movie: Option<PathBuf>,
+    pub play_movie: Option<PathBuf>,
+    pub save_dir: PathBuf,
+    pub input_config: Option<PathBuf>,
+    pub record_audio: Option<PathBuf>,
+    pub record_voices: bool,
+    pub muted_voices: Vec<Voice>,
+    pub voice_volumes: Vec<(Voice, f32)>,
+    pub serial: Option<String>,
+    pub printer: Option<PathBuf>,
+    pub link_rom: Option<PathBuf>,
+    pub link_listen: Option<u16>,
+    pub link_connect: Option<u16>,
+}
+
+pub struct DisasmOptions {
+    pub rom: PathBuf,
+    pub output: Option<PathBuf>,
+    pub symbols: Option<PathBuf>,
+}
+
+/// The result of parsing the command line: options to run with, a ROM to
+/// disassemble, or a request for help.
+pub enum Command {
+    Run(Box<Options>),
+    Disasm(DisasmOptions),
+    Help,
+}
+
+pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut args = args.peekable();
+    if args.peek().is_some_and(|arg| arg == "disasm") {
+        args.next();
+        return parse_disasm(args);
+    }
+
+    let mut rom = None;
+    let mut boot_rom = None;
+    let mut model = Model::Dmg;
+    let mut scale = 3;
+    let mut palette = Palette::grey();
+    let mut filter = Filter::Nearest;
+    let mut shader = Shader::Plain;
+    let mut fullscreen = false;
+    let mut integer_scale = true;
+    let mut sync = Sync::Audio;
+    let mut fast_forward_speed = 0.0;
+    let mut slow_motion_speed = 0.5;
+    let mut frame_skip = 0;
+    let mut rewind_seconds: f64 = 20.0;
+    let mut rewind_interval = 2;
+    let mut headless = false;
+    let mut frames = None;
+    let mut debug = false;
+    let mut gdb = None;
+    let mut trace = None;
+    let mut trace_range = None;
+    let mut trace_bank = None;
+    let mut trace_symbols = false;
+    let mut symbols = None;
+    let mut record_movie = None;
+    let mut play_movie = None;
+    let mut save_dir = None;
+    let mut input_config = None;
+    let mut record_audio = None;
+    let mut record_voices = false;
+    let mut muted_voices = Vec::new();
+    let mut voice_volumes = Vec::new();
+    let mut serial = None;
+    let mut printer = None;
+    let mut link_rom = None;
+    let mut link_listen = None;
+    let mut link_connect = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--boot-rom" => boot_rom = Some(PathBuf::from(value(&arg, &mut args)?)),
+            "--model" => {
+                let name = value(&arg, &mut args)?;
+                model = Model::from_name(&name)
+                    .ok_or_else(|| format!("unknown model '{}', expected dmg, mgb, cgb or sgb", name))?;
+            },
+            "--scale" => {
+                scale = number(&arg, &mut args)?;
+                if scale == 0 {
+                    return Err("--scale must be at least 1".to_string());
+                }
+            },
+            "--palette" => palette = Palette::from_name(&value(&arg, &mut args)?)?,
+            "--filter" => {
+                let name = value(&arg, &mut args)?;
+                filter = Filter::from_name(&name)
+                    .ok_or_else(|| format!("unknown filter '{}', expected nearest or linear", name))?;
+            },
+            "--shader" => {
+                let name = value(&arg, &mut args)?;
+                shader = Shader::from_name(&name)
+                    .ok_or_else(|| format!("unknown shader '{}', expected none, lcd, ghosting or crt", name))?;
+            },
+            "--fullscreen" => fullscreen = true,
+            "--no-integer-scale" => integer_scale = false,
+            "--sync" => {
+                let name = value(&arg, &mut args)?;
+                sync = Sync::from_name(&name)
+                    .ok_or_else(|| format!("unknown sync '{}', expected audio or timer", name))?;
+            },
+            "--fast-forward" => {
+                fast_forward_speed = number(&arg, &mut args)?;
+                if fast_forward_speed != 0.0 && !is_speed(fast_forward_speed) {
+                    return Err(format!("--fast-forward must be 0 or between {} and {}", MIN_SPEED, MAX_SPEED));
+                }
+            },
+            "--slow-motion" => {
+                slow_motion_speed = number(&arg, &mut args)?;
+                if !is_speed(slow_motion_speed) {
+                    return Err(format!("--slow-motion must be between {} and {}", MIN_SPEED, MAX_SPEED));
+                }
+            },
+            "--frame-skip" => frame_skip = number(&arg, &mut args)?,
+            "--rewind" => {
+                rewind_seconds = number(&arg, &mut args)?;
+                if !rewind_seconds.is_finite() || rewind_seconds < 0.0 {
+                    return Err("--rewind must be a number of seconds, 0 or more".to_string());
+                }
+            },
+            "--rewind-interval" => {
+                rewind_interval = number(&arg, &mut args)?;
+                if rewind_interval == 0 {
+                    return Err("--rewind-interval must be at least 1".to_string());
+                }
+            },
+            "--headless" => headless = true,
+            "--frames" => frames = Some(number(&arg, &mut args)?),
+            "--debug" => debug = true,
+            "--gdb" => gdb = Some(number(&arg, &mut args)?),
+            "--trace" => trace = Some(value(&arg, &mut args)?),
+            "--trace-range" => {
+                let text = value(&arg, &mut args)?;
+                trace_range = Some(trace::parse_range(&text)
+                    .ok_or_else(|| format!("invalid --trace-range '{}', expected START-END in hex", text))?);
+            },
+            "--trace-bank" => trace_bank = Some(number(&arg, &mut args)?),
+            "--trace-symbols" => trace_symbols = true,
+            "--symbols" => symbols = Some(PathBuf::from(value(&arg, &mut args)?)),
+            "--record-movie" => record_movie = Some(PathBuf::from(value(&arg, &mut args)?)),
+            "--play-movie" => play_movie = Some(PathBuf::from(value(&arg, &mut args)?)),
+            "--save-dir" => save_dir = Some(PathBuf::from(value(&arg, &mut args)?)),
+            "--input-config" => input_config = Some(PathBuf::from(value(&arg, &mut args)?)),
+            "--record-audio" => record_audio = Some(PathBuf::from(value(&arg, &mut args)?)),
+            "--record-voices" => record_voices = true,
+            "--mute" => {
+                for name in value(&arg, &mut args)?.split(',') {
+                    muted_voices.push(voice(name)?);
+                }
+            },
+            "--voice-volume" => {
+                let text = value(&arg, &mut args)?;
+                let invalid = || format!("invalid --voice-volume '{}', expected VOICE=N", text);
+                let mut parts = text.splitn(2, '=');
+                let name = parts.next().ok_or_else(&invalid)?;
+                let volume: f32 = parts.next().and_then(|volume| volume.parse().ok()).ok_or_else(&invalid)?;
+                if !(0.0..=4.0).contains(&volume) {
+                    return Err("--voice-volume must be between 0 and 4".to_string());
+                }
+                voice_volumes.push((voice(name)?, volume));
+            },
+            "--serial" => serial = Some(value(&arg, &mut args)?),
+            "--printer" => printer = Some(PathBuf::from(value(&arg, &mut args)?)),
+            "--link" => link_rom = Some(PathBuf::from(value(&arg, &mut args)?)),
+            "--link-listen" => link_listen = Some(number(&arg, &mut args)?),
+            "--link-connect" => link_connect = Some(number(&arg, &mut args)?),
+            _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+            _ if rom.is_some() => return Err(format!("unexpected argument '{}', only one ROM can be run", arg)),
+            _ => rom = Some(PathBuf::from(arg)),
+        }
+    }
+
+    let rom: PathBuf = rom.ok_or_else(|| "no ROM given".to_string())?;
+    if record_voices && record_audio.is_none() {
+        return Err("--record-voices needs --record-audio".to_string());
+    }
+    if (trace_range.is_some() || trace_bank.is_some() || trace_symbols) && trace.is_none() {
+        return Err("--trace-range, --trace-bank and --trace-symbols need --trace".to_string());
+    }
+    if debug && gdb.is_some() {
+        return Err("--debug and --gdb can't be used together".to_string());
+    }
+    if record_movie.is_some() && play_movie.is_some() {
+        return Err("--record-movie and --play-movie can't be used together".to_string());
+    }
+    // Each of these takes the link port, so only one can have it
+    let serial_devices = [
+        (serial.is_some(), "--serial"),
+        (printer.is_some(), "--printer"),
+        (link_rom.is_some(), "--link"),
+        (link_listen.is_some(), "--link-listen"),
+        (link_connect.is_some(), "--link-connect"),
+    ];
+    let connected: Vec<&str> = serial_devices.iter()
+        .filter(|&&(given, _)| given)
+        .map(|&(_, option)| option)
+        .collect();
+    if let Some((last, rest)) = connected.split_last() {
+        if !rest.is_empty() {
+            return Err(format!("{} and {} can't be used together, the link port only takes one",
+                               rest.join(", "), last));
+        }
+    }
+    if debug || gdb.is_some() {
+        let mode = if debug { "--debug" } else { "--gdb" };
+        if headless {
+            return Err(format!("--headless can't be used with {}, which never opens a window", mode));
+        }
+        if record_movie.is_some() || play_movie.is_some() {
+            return Err(format!("--record-movie and --play-movie can't be used with {}", mode));
+        }
+    }
+
+    let save_dir = save_dir.unwrap_or_else(|| {
+        rom.parent().unwrap_or(Path::new(".")).to_path_buf()
+    });
+
+    Ok(Command::Run(Box::new(Options {
+        rom,
+        boot_rom,
+        model,
+        scale,
+        palette,
+        filter,
+        shader,
+        fullscreen,
+        integer_scale,
+        sync,
+        fast_forward_speed,
+        slow_motion_speed,
+        frame_skip,
+        rewind_seconds,
+        rewind_interval,
+        headless,
+        frames,
+        debug,
+        gdb,
+        trace,
+        trace_range,
+        trace_bank,
+        trace_symbols,
+        symbols,
+        record_movie,
+        play_movie,
+        save_dir,
+        input_config,
+        record_audio,
+        record_voices,
+        muted_voices,
+        voice_volumes,
+        serial,
+        printer,
+        link_rom,
+        link_listen,
+        link_connect,
+    })))
+}
+
+fn parse_disasm<I: Iterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut rom = None;
+    let mut output = None;
+    let mut symbols = None;
+
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-o" | "--output" => output = Some(PathBuf::from(value(&arg, &mut args)?)),
+            "--symbols" => symbols = Some(PathBuf::from(value(&arg, &mut args)?)),
+            _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+            _ if rom.is_some() => return Err(format!("unexpected argument '{}', only one ROM can be disassembled", arg)),
+            _ => rom = Some(PathBuf::from(arg)),
+        }
+    }
+
+    Ok(Command::Disasm(DisasmOptions {
+        rom: rom.ok_or_else(|| "no ROM given".to_string())?,
+        output,
+        symbols,
+    }))
+}
+
+fn voice(name: &str) -> Result<Voice, String> {
+    Voice::from_name(name)
+        .ok_or_else(|| format!("unknown voice '{}', expected pulse_a, pulse_b, wave or noise", name))
+}
+
+// Speeds the frame pacer can turn into a frame time
+const MIN_SPEED: f64 = 0.01;
+const MAX_SPEED: f64 = 100.0;
+
+fn is_speed(speed: f64) -> bool {
+    (MIN_SPEED..=MAX_SPEED).contains(&speed)
+}
+
+fn value<I: Iterator<Item = String>>(option: &str, args: &mut I) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} needs a value", option))
+}
+
+fn number<T, I>(option: &str, args: &mut I) -> Result<T, String>
+    where T: ::std::str::FromStr, I: Iterator<Item = String>
+{
+    let value = value(option, args)?;
+    value.parse().map_err(|_| format!("{} expects a number, got '{}'", option, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Options, String> {
+        match parse(args.iter().map(|arg| arg.to_string()))? {
+            Command::Run(options) => Ok(*options),
+            _ => panic!("{:?} didn't parse to a run", args),
+        }
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = parse_args(&["--model", "cgb", "--scale", "4", "--frames", "60", "--headless", "game.gb"]).unwrap();
+        assert_eq!(options.rom, PathBuf::from("game.gb"));
+        assert_eq!(options.model, Model::Cgb);
+        assert_eq!(options.scale, 4);
+        assert_eq!(options.frames, Some(60));
+        assert!(options.headless);
+        assert_eq!(options.save_dir, PathBuf::from(""));
+
+        let options = parse_args(&["roms/game.gb", "--mute", "wave,noise", "--voice-volume", "pulse_a=2"]).unwrap();
+        assert_eq!(options.save_dir, PathBuf::from("roms"));
+        assert_eq!(options.muted_voices, vec![Voice::Wave, Voice::Noise]);
+        assert_eq!(options.voice_volumes, vec![(Voice::PulseA, 2.0)]);
+    }
+
+    #[test]
+    fn help_and_disasm_are_commands() {
+        assert!(matches!(parse(["game.gb", "-h"].iter().map(|arg| arg.to_string())), Ok(Command::Help)));
+        match parse(["disasm", "-o", "game.asm", "game.gb"].iter().map(|arg| arg.to_string())) {
+            Ok(Command::Disasm(options)) => {
+                assert_eq!(options.rom, PathBuf::from("game.gb"));
+                assert_eq!(options.output, Some(PathBuf::from("game.asm")));
+            },
+            _ => panic!("disasm didn't parse"),
+        }
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        let cases: [&[&str]; 9] = [
+            &[],
+            &["--scale", "2"],
+            &["--bogus", "game.gb"],
+            &["game.gb", "other.gb"],
+            &["--model", "gba", "game.gb"],
+            &["--scale", "0", "game.gb"],
+            &["--scale", "big", "game.gb"],
+            &["game.gb", "--scale"],
+            &["--mute", "drums", "game.gb"],
+        ];
+        for args in cases.iter() {
+            assert!(parse_args(args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn rejects_conflicting_options() {
+        let cases: [&[&str]; 6] = [
+            &["--debug", "--gdb", "1234", "game.gb"],
+            &["--record-movie", "a.rbm", "--play-movie", "b.rbm", "game.gb"],
+            &["--serial", "stdout", "--link", "other.gb", "game.gb"],
+            &["--headless", "--debug", "game.gb"],
+            &["--record-voices", "game.gb"],
+            &["--trace-bank", "1", "game.gb"],
+        ];
+        for args in cases.iter() {
+            assert!(parse_args(args).is_err(), "{:?}", args);
+        }
+        let err = parse_args(&["--printer", "out", "--link-listen", "1", "--link-connect", "2", "game.gb"]).err();
+        assert_eq!(err.unwrap(), "--printer, --link-listen and --link-connect can't be used together, \
+                                 the link port only takes one");
+    }
+
+    #[test]
+    fn rewind_must_be_finite_and_not_negative() {
+        assert_eq!(parse_args(&["--rewind", "0", "game.gb"]).unwrap().rewind_seconds, 0.0);
+        assert_eq!(parse_args(&["--rewind", "2.5", "game.gb"]).unwrap().rewind_seconds, 2.5);
+        for seconds in ["-1", "NaN", "inf", "-inf"].iter() {
+            assert!(parse_args(&["--rewind", seconds, "game.gb"]).is_err(), "{}", seconds);
+        }
+    }
+}
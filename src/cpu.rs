@@ -1,4 +1,54 @@
+use std::fmt;
+
+use super::disasm;
 use super::mmu;
+use super::model;
+use super::savestate::{Savable, StateError, StateReader, StateWriter};
+
+// IO registers as the boot ROM leaves them, written when it is skipped
+const BOOT_IO_REGISTERS: [(u16, u8); 8] = [
+    (0xFF26, 0xF1),
+    (0xFF10, 0x80),
+    (0xFF11, 0xBF),
+    (0xFF12, 0xF3),
+    (0xFF24, 0x77),
+    (0xFF25, 0xF3),
+    (0xFF40, 0x91),
+    (0xFF47, 0xFC),
+];
+
+/// A snapshot of the registers, for debuggers and tracing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+/// An opcode the CPU can't execute, left unexecuted at `pc`.
+#[derive(Clone, Copy, Debug)]
+pub struct InvalidOpcode {
+    pub pc: u16,
+    pub opcode: u8,
+    pub prefixed: bool,
+}
+
+impl fmt::Display for InvalidOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.prefixed {
+            write!(f, "Unimplemented CB Instruction: {:#04x} at {:#06x}", self.opcode, self.pc)
+        } else {
+            write!(f, "Unimpletemented instruction: {:#04X} at {:#06x}", self.opcode, self.pc)
+        }
+    }
+}
 
 pub struct Cpu {
     reg_a: u8, // Accumlator
@@ -15,7 +65,7 @@ pub struct Cpu {
     reg_sp: u16, // Stack Pointer
     reg_pc: u16, // Program Counter
 
-    pub mmu: mmu::Mmu,
+    pub(crate) mmu: mmu::Mmu,
 }
 
 impl Cpu {
@@ -37,198 +87,260 @@ impl Cpu {
         }
     }
 
-    pub fn run(&mut self) {
-        loop {
-            let opcode = self.mmu.read_byte(self.reg_pc);
-            println!("reg_pc {:#06x}: {:#04x}", self.reg_pc, opcode);
-
-            self.reg_pc += 1;
-
-
-            match opcode {
-                0x04 => { // INC B
-                    self.reg_f.half_carry = (self.reg_b & 0x0F) == 0x0F;
-                    self.reg_b = self.reg_b.wrapping_add(1);
-
-                    self.reg_f.zero = self.reg_b == 0;
-                    self.reg_f.add_sub = true;
-                },
-                0x05 => { // DEC B
-                    self.reg_f.half_carry = (self.reg_b & 0x0F) != 0x00;
-                    self.reg_b = self.reg_b.wrapping_sub(1);
-
-                    self.reg_f.zero = self.reg_b == 0;
-                    self.reg_f.add_sub = true;
-                },
-                0x06 => { // LD B, d8
-                    self.reg_b = self.load_imm_u8();
-                },
-                0x0C => { // INC C
-                    self.reg_f.half_carry = (self.reg_c & 0x0F) == 0x0F;
-                    self.reg_c = self.reg_c.wrapping_add(1);
-
-                    self.reg_f.zero = self.reg_c == 0;
-                    self.reg_f.add_sub = true;
-                },
-                0x0D => { // DEC C
-                    self.reg_f.half_carry = (self.reg_c & 0x0F) != 0x00;
-                    self.reg_c = self.reg_c.wrapping_sub(1);
-
-                    self.reg_f.zero = self.reg_c == 0;
-                    self.reg_f.add_sub = true;
-                }
-                0x0E => { // LD C, d8
-                    self.reg_c = self.load_imm_u8();
-                },
-                0x11 => { // LD DE, d16
-                    let imm = self.load_imm_u16();
-                    self.set_de(imm);
-                },
-                0x13 => { // INC DE
-                    let val = self.de() + 1;
-                    self.set_de(val);
-                },
-                0x17 => { // RLA
-                    let (value, carry) = rotate_left_carry(self.reg_a, self.reg_f.carry);
-                    self.reg_c = value;
-
-                    self.reg_f.zero = value == 0;
-                    self.reg_f.half_carry = false;
-                    self.reg_f.add_sub = false;
-                    self.reg_f.carry = carry;
-                },
-                0x18 => { // JR r8 {
+    /// Puts the machine in the state `model`'s boot ROM hands over to the
+    /// cartridge in, starting at 0x100.
+    pub fn skip_boot_rom(&mut self, model: model::Model) {
+        let [af, bc, de, hl] = model.boot_registers();
+        self.reg_a = (af >> 8) as u8;
+        self.reg_f = RegFlag::from_u8(af as u8);
+        self.set_bc(bc);
+        self.set_de(de);
+        self.set_hl(hl);
+        self.reg_sp = 0xFFFE;
+        self.reg_pc = 0x0100;
+
+        for &(addr, value) in BOOT_IO_REGISTERS.iter() {
+            self.mmu.write_byte(addr, value);
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.reg_pc
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.reg_a,
+            f: self.reg_f.to_u8(),
+            b: self.reg_b,
+            c: self.reg_c,
+            d: self.reg_d,
+            e: self.reg_e,
+            h: self.reg_h,
+            l: self.reg_l,
+            sp: self.reg_sp,
+            pc: self.reg_pc,
+        }
+    }
+
+    /// For debuggers; the low nibble of F always reads back as 0.
+    pub fn set_registers(&mut self, registers: Registers) {
+        self.reg_a = registers.a;
+        self.reg_f = RegFlag::from_u8(registers.f);
+        self.reg_b = registers.b;
+        self.reg_c = registers.c;
+        self.reg_d = registers.d;
+        self.reg_e = registers.e;
+        self.reg_h = registers.h;
+        self.reg_l = registers.l;
+        self.reg_sp = registers.sp;
+        self.reg_pc = registers.pc;
+    }
+
+    /// Executes a single instruction and returns the T-cycles it took. An
+    /// opcode that can't be executed is returned as an error with the
+    /// machine left as it was.
+    pub fn step(&mut self) -> Result<u32, InvalidOpcode> {
+        let pc = self.reg_pc;
+        let opcode = self.mmu.read_byte(self.reg_pc);
+
+        self.reg_pc += 1;
+
+        let mut cycles = disasm::CYCLES[opcode as usize] as u32;
+
+        match opcode {
+            0x04 => { // INC B
+                self.reg_f.half_carry = (self.reg_b & 0x0F) == 0x0F;
+                self.reg_b = self.reg_b.wrapping_add(1);
+
+                self.reg_f.zero = self.reg_b == 0;
+                self.reg_f.add_sub = true;
+            },
+            0x05 => { // DEC B
+                self.reg_f.half_carry = (self.reg_b & 0x0F) != 0x00;
+                self.reg_b = self.reg_b.wrapping_sub(1);
+
+                self.reg_f.zero = self.reg_b == 0;
+                self.reg_f.add_sub = true;
+            },
+            0x06 => { // LD B, d8
+                self.reg_b = self.load_imm_u8();
+            },
+            0x0C => { // INC C
+                self.reg_f.half_carry = (self.reg_c & 0x0F) == 0x0F;
+                self.reg_c = self.reg_c.wrapping_add(1);
+
+                self.reg_f.zero = self.reg_c == 0;
+                self.reg_f.add_sub = true;
+            },
+            0x0D => { // DEC C
+                self.reg_f.half_carry = (self.reg_c & 0x0F) != 0x00;
+                self.reg_c = self.reg_c.wrapping_sub(1);
+
+                self.reg_f.zero = self.reg_c == 0;
+                self.reg_f.add_sub = true;
+            }
+            0x0E => { // LD C, d8
+                self.reg_c = self.load_imm_u8();
+            },
+            0x11 => { // LD DE, d16
+                let imm = self.load_imm_u16();
+                self.set_de(imm);
+            },
+            0x13 => { // INC DE
+                let val = self.de() + 1;
+                self.set_de(val);
+            },
+            0x17 => { // RLA
+                let (value, carry) = rotate_left_carry(self.reg_a, self.reg_f.carry);
+                self.reg_c = value;
+
+                self.reg_f.zero = value == 0;
+                self.reg_f.half_carry = false;
+                self.reg_f.add_sub = false;
+                self.reg_f.carry = carry;
+            },
+            0x18 => { // JR r8 {
+                self.jump_return();
+            },
+            0x1A => { // LD A, (DE)
+                self.reg_a = self.mmu.read_byte(self.de());
+            },
+            0x1E => { // LD E, d8
+                self.reg_e = self.load_imm_u8();
+            },
+            0x20 => { // JR NZ, r8
+                if !self.reg_f.zero {
                     self.jump_return();
-                },
-                0x1A => { // LD A, (DE)
-                    self.reg_a = self.mmu.read_byte(self.de());
-                },
-                0x1E => { // LD E, d8
-                    self.reg_e = self.load_imm_u8();
-                },
-                0x20 => { // JR NZ, r8
-                    if !self.reg_f.zero {
-                        self.jump_return();
-                    } else {
-                        self.reg_pc += 1;
-                    }
-                },
-                0x21 => { // LD HL, d16
-                    let imm = self.load_imm_u16();
-                    self.set_hl(imm);
-                },
-                0x22 => { // (HL+), A
-                    let idx = self.hl();
-                    self.mmu.write_byte(idx, self.reg_a);
-                    self.set_hl(idx + 1);
-                },
-                0x23 => { // INC HL
-                    let val = self.hl() + 1;
-                    self.set_hl(val);
-                },
-                0x28 => { // JR Z, r8
-                    if self.reg_f.zero {
-                        self.jump_return()
-                    } else {
-                        self.reg_pc += 1;
-                    }
-                },
-                0x2E => { // LD L d8
-                    self.reg_l = self.load_imm_u8();
-                },
-                0x31 => { // LD SP, d16
-                    self.reg_sp = self.load_imm_u16();
-                },
-                0x32 => { // LD (HL-), A
-                    let idx = self.hl();
-                    self.mmu.write_byte(idx, self.reg_a);
-                    self.set_hl(idx - 1);
-                },
-                0x3D => { // DEC A
-                    self.reg_f.half_carry = (self.reg_a & 0x0F) != 0x00;
-                    self.reg_a = self.reg_a.wrapping_sub(1);
-
-                    self.reg_f.zero = self.reg_a == 0;
-                    self.reg_f.add_sub = true;
-                },
-                0x3E => { // LD A, d8
-                    self.reg_a = self.load_imm_u8();
-                },
-                0x4F => { // C, A
-                    self.reg_c = self.reg_a;
-                }
-                0x57 => { // LD D, A
-                    self.reg_d = self.reg_a;
-                },
-                0x67 => { // LD H, A
-                    self.reg_h = self.reg_a;
-                },
-                0x77 => { // LD (HL), A
-                    let addr = self.hl();
-                    self.mmu.write_byte(addr, self.reg_a);
-                },
-                0x7B => { // A, E
-                    self.reg_a = self.reg_e;
-                },
-                0xAF => { // XOR A
-                    self.reg_a ^= self.reg_a;
-
-                    self.reg_f.zero = self.reg_a == 0;
-                    self.reg_f.carry = false;
-                    self.reg_f.add_sub = false;
-                    self.reg_f.half_carry = false;
-                },
-                0xC1 => { // POP BC
-                    let val = self.pop_u16();
-                    self.set_bc(val);
-                },
-                0xC5 => { // PUSH BC
-                    let value = self.bc();
-                    self.push_u16(value);
-                },
-                0xC9 => { // RET
-                    self.reg_pc = self.pop_u16();
-                },
-                0xCB => { // CB Insruction
-                    self.execute_cb();
-                },
-                0xCD => { // CALL a16
-                    let call = self.load_imm_u16();
-                    let ret = self.reg_pc;
-                    self.push_u16(ret);
-                    self.reg_pc = call;
-                },
-                0xE0 => { // LDH (a8), A
-                    let imm = self.load_imm_u8();
-                    self.mmu.write_byte(0xFF00 + imm as u16, self.reg_a);
-                },
-                0xE2 => { // Ld (C), A
-                    self.mmu.write_byte(0xFF00 + self.reg_c as u16, self.reg_a);
+                    cycles += 4;
+                } else {
+                    self.reg_pc += 1;
                 }
-                0xEA => { // LD (a16), A
-                    let imm = self.load_imm_u16();
-                    self.mmu.write_byte(imm, self.reg_a);
-                },
-                0xF0 => { // LDH A, (a8)
-                    let imm = self.load_imm_u8();
-                    self.reg_a = self.mmu.read_byte(0xFF00 + imm as u16);
-                },
-                0xFE => { // CP d8
-                    let val = self.reg_a.wrapping_sub(self.load_imm_u8());
-
-                    self.reg_f.zero = val == 0;
-                    self.reg_f.add_sub = true;
-                    self.reg_f.half_carry = (val & 0x0F) == 0x00;
-                    self.reg_f.carry = self.reg_a < val
+            },
+            0x21 => { // LD HL, d16
+                let imm = self.load_imm_u16();
+                self.set_hl(imm);
+            },
+            0x22 => { // (HL+), A
+                let idx = self.hl();
+                self.mmu.write_byte(idx, self.reg_a);
+                self.set_hl(idx + 1);
+            },
+            0x23 => { // INC HL
+                let val = self.hl() + 1;
+                self.set_hl(val);
+            },
+            0x28 => { // JR Z, r8
+                if self.reg_f.zero {
+                    self.jump_return();
+                    cycles += 4;
+                } else {
+                    self.reg_pc += 1;
                 }
-                _ => panic!("Unimpletemented instruction: {:#04X}", opcode)
+            },
+            0x2E => { // LD L d8
+                self.reg_l = self.load_imm_u8();
+            },
+            0x31 => { // LD SP, d16
+                self.reg_sp = self.load_imm_u16();
+            },
+            0x32 => { // LD (HL-), A
+                let idx = self.hl();
+                self.mmu.write_byte(idx, self.reg_a);
+                self.set_hl(idx - 1);
+            },
+            0x3D => { // DEC A
+                self.reg_f.half_carry = (self.reg_a & 0x0F) != 0x00;
+                self.reg_a = self.reg_a.wrapping_sub(1);
+
+                self.reg_f.zero = self.reg_a == 0;
+                self.reg_f.add_sub = true;
+            },
+            0x3E => { // LD A, d8
+                self.reg_a = self.load_imm_u8();
+            },
+            0x4F => { // C, A
+                self.reg_c = self.reg_a;
+            }
+            0x57 => { // LD D, A
+                self.reg_d = self.reg_a;
+            },
+            0x67 => { // LD H, A
+                self.reg_h = self.reg_a;
+            },
+            0x77 => { // LD (HL), A
+                let addr = self.hl();
+                self.mmu.write_byte(addr, self.reg_a);
+            },
+            0x7B => { // A, E
+                self.reg_a = self.reg_e;
+            },
+            0xAF => { // XOR A
+                self.reg_a ^= self.reg_a;
+
+                self.reg_f.zero = self.reg_a == 0;
+                self.reg_f.carry = false;
+                self.reg_f.add_sub = false;
+                self.reg_f.half_carry = false;
+            },
+            0xC1 => { // POP BC
+                let val = self.pop_u16();
+                self.set_bc(val);
+            },
+            0xC5 => { // PUSH BC
+                let value = self.bc();
+                self.push_u16(value);
+            },
+            0xC9 => { // RET
+                self.reg_pc = self.pop_u16();
+            },
+            0xCB => { // CB Insruction
+                cycles = self.execute_cb().map_err(|opcode| {
+                    self.reg_pc = pc;
+                    InvalidOpcode { pc, opcode, prefixed: true }
+                })?;
+            },
+            0xCD => { // CALL a16
+                let call = self.load_imm_u16();
+                let ret = self.reg_pc;
+                self.push_u16(ret);
+                self.reg_pc = call;
+            },
+            0xE0 => { // LDH (a8), A
+                let imm = self.load_imm_u8();
+                self.mmu.write_byte(0xFF00 + imm as u16, self.reg_a);
+            },
+            0xE2 => { // Ld (C), A
+                self.mmu.write_byte(0xFF00 + self.reg_c as u16, self.reg_a);
+            }
+            0xEA => { // LD (a16), A
+                let imm = self.load_imm_u16();
+                self.mmu.write_byte(imm, self.reg_a);
+            },
+            0xF0 => { // LDH A, (a8)
+                let imm = self.load_imm_u8();
+                self.reg_a = self.mmu.read_byte(0xFF00 + imm as u16);
+            },
+            0xFE => { // CP d8
+                let val = self.reg_a.wrapping_sub(self.load_imm_u8());
+
+                self.reg_f.zero = val == 0;
+                self.reg_f.add_sub = true;
+                self.reg_f.half_carry = (val & 0x0F) == 0x00;
+                self.reg_f.carry = self.reg_a < val
+            }
+            _ => {
+                self.reg_pc = pc;
+                return Err(InvalidOpcode { pc, opcode, prefixed: false });
             }
         }
+
+        Ok(cycles)
     }
 
-    fn execute_cb(&mut self) {
+    // Returns the opcode as the error if it isn't implemented
+    fn execute_cb(&mut self) -> Result<u32, u8> {
         let opcode = self.mmu.read_byte(self.reg_pc);
-        println!("CB Instruction -> {:#04x}", opcode);
 
         self.reg_pc += 1;
 
@@ -248,8 +360,10 @@ impl Cpu {
                 self.reg_f.add_sub = false;
                 self.reg_f.half_carry = true;
             }
-            _ => panic!("Unimplemented CB Instruction: {:#04x}", opcode)
+            _ => return Err(opcode)
         }
+
+        Ok(disasm::cb_cycles(opcode))
     }
 
     fn load_imm_u8(&mut self) -> u8 {
@@ -340,3 +454,51 @@ struct RegFlag {
     add_sub: bool,
     zero: bool,
 }
+
+impl RegFlag {
+    fn from_u8(value: u8) -> Self {
+        RegFlag {
+            carry: value & 0x10 != 0,
+            half_carry: value & 0x20 != 0,
+            add_sub: value & 0x40 != 0,
+            zero: value & 0x80 != 0,
+        }
+    }
+
+    fn to_u8(&self) -> u8 {
+        (self.zero as u8) << 7 | (self.add_sub as u8) << 6 | (self.half_carry as u8) << 5 | (self.carry as u8) << 4
+    }
+}
+
+impl Savable for Cpu {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bytes(&[
+            self.reg_a, self.reg_f.to_u8(),
+            self.reg_b, self.reg_c,
+            self.reg_d, self.reg_e,
+            self.reg_h, self.reg_l,
+        ]);
+        state.write_u16(self.reg_sp);
+        state.write_u16(self.reg_pc);
+
+        self.mmu.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut registers = [0; 8];
+        state.read_bytes(&mut registers)?;
+        let [a, f, b, c, d, e, h, l] = registers;
+        self.reg_a = a;
+        self.reg_f = RegFlag::from_u8(f);
+        self.reg_b = b;
+        self.reg_c = c;
+        self.reg_d = d;
+        self.reg_e = e;
+        self.reg_h = h;
+        self.reg_l = l;
+        self.reg_sp = state.read_u16()?;
+        self.reg_pc = state.read_u16()?;
+
+        self.mmu.load(state)
+    }
+}
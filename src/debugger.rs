@@ -0,0 +1,358 @@
+//! Breakpoints, watchpoints and stepping, for anything that wants to stop
+//! the machine part way through a frame and look around.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::cpu;
+use super::disasm;
+use super::gameboy::GameBoy;
+use super::mmu::{Access, Interrupts, Watchpoint};
+
+// Instructions remembered for showing the code leading up to PC
+const HISTORY_LENGTH: usize = 8;
+
+/// The ROM bank mapped at `addr`. No MBC is emulated, so the switchable area
+/// always holds bank 1.
+pub fn rom_bank(addr: u16) -> u16 {
+    if (0x4000..0x8000).contains(&addr) { 1 } else { 0 }
+}
+
+/// Stops when PC reaches `addr`, and if a bank is given, only while that
+/// bank is mapped there.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub bank: Option<u16>,
+    pub addr: u16,
+}
+
+impl Breakpoint {
+    /// Parses `addr` or `bank:addr`, in hex.
+    pub fn parse(text: &str) -> Option<Breakpoint> {
+        let mut parts = text.splitn(2, ':');
+        let first = parse_hex(parts.next().unwrap_or(""))?;
+        match parts.next() {
+            Some(addr) => Some(Breakpoint { bank: Some(first), addr: parse_hex(addr)? }),
+            None => Some(Breakpoint { bank: None, addr: first }),
+        }
+    }
+
+    fn hits(&self, pc: u16) -> bool {
+        pc == self.addr && self.bank.is_none_or(|bank| bank == rom_bank(pc))
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.addr),
+            None => write!(f, "{:04X}", self.addr),
+        }
+    }
+}
+
+/// Parses a hex number, with or without a `$` or `0x` prefix.
+pub fn parse_hex(text: &str) -> Option<u16> {
+    let digits = text.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).ok()
+}
+
+/// Why the machine stopped.
+#[derive(Debug)]
+pub enum Stop {
+    // Finished the step asked for
+    Done,
+    Breakpoint(Breakpoint),
+    Watchpoint(Access),
+    Interrupt(Interrupts),
+    InvalidOpcode(cpu::InvalidOpcode),
+    Interrupted,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stop::Done => write!(f, "Stopped"),
+            Stop::Breakpoint(breakpoint) => write!(f, "Breakpoint at {}", breakpoint),
+            Stop::Watchpoint(access) if access.write => {
+                write!(f, "Watchpoint: wrote {:#04x} to {:#06x}", access.value, access.addr)
+            },
+            Stop::Watchpoint(access) => {
+                write!(f, "Watchpoint: read {:#04x} from {:#06x}", access.value, access.addr)
+            },
+            Stop::Interrupt(interrupts) => write!(f, "Interrupt requested: {:?}", interrupts),
+            Stop::InvalidOpcode(err) => write!(f, "{}", err),
+            Stop::Interrupted => write!(f, "Interrupted"),
+        }
+    }
+}
+
+/// A call made while the debugger was running the machine.
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    pub call_site: u16,
+    pub target: u16,
+    // Where the return address is; the frame is gone once SP is above it
+    sp: u16,
+}
+
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    // Interrupt requests that stop the machine as they're raised
+    pub break_interrupts: Interrupts,
+
+    history: VecDeque<u16>,
+    calls: Vec<Frame>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            break_interrupts: Interrupts::empty(),
+
+            history: VecDeque::new(),
+            calls: Vec::new(),
+        }
+    }
+
+    /// The addresses of the last few instructions executed, oldest first.
+    pub fn history(&self) -> &VecDeque<u16> {
+        &self.history
+    }
+
+    /// Calls that haven't returned yet, outermost first.
+    pub fn call_stack(&self) -> &[Frame] {
+        &self.calls
+    }
+
+    /// Forgets the history and call stack, which mean nothing once the
+    /// machine is reset or a state is loaded.
+    pub fn forget(&mut self) {
+        self.history.clear();
+        self.calls.clear();
+    }
+
+    /// Executes one instruction, following calls.
+    pub fn step(&mut self, game_boy: &mut GameBoy) -> Stop {
+        self.arm(game_boy);
+        self.execute(game_boy).unwrap_or(Stop::Done)
+    }
+
+    /// Executes one instruction, running calls through to their return.
+    pub fn step_over(&mut self, game_boy: &mut GameBoy, interrupted: &AtomicBool) -> Stop {
+        let registers = game_boy.cpu().registers();
+        let opcode = game_boy.cpu().mmu.peek(registers.pc);
+        if !disasm::is_call(opcode) {
+            return self.step(game_boy);
+        }
+
+        let length = disasm::decode(registers.pc, |addr| game_boy.cpu().mmu.peek(addr)).length();
+        let return_addr = registers.pc.wrapping_add(length);
+        self.run_until(game_boy, interrupted, |cpu| {
+            cpu.pc() == return_addr && cpu.registers().sp >= registers.sp
+        })
+    }
+
+    /// Runs until the current function returns to its caller.
+    pub fn step_out(&mut self, game_boy: &mut GameBoy, interrupted: &AtomicBool) -> Stop {
+        let sp = game_boy.cpu().registers().sp;
+        self.run_until(game_boy, interrupted, |cpu| cpu.registers().sp > sp)
+    }
+
+    /// Runs until something stops the machine.
+    pub fn resume(&mut self, game_boy: &mut GameBoy, interrupted: &AtomicBool) -> Stop {
+        self.run_until(game_boy, interrupted, |_| false)
+    }
+
+    fn run_until<F>(&mut self, game_boy: &mut GameBoy, interrupted: &AtomicBool, mut done: F) -> Stop
+        where F: FnMut(&cpu::Cpu) -> bool
+    {
+        self.arm(game_boy);
+        loop {
+            if let Some(stop) = self.execute(game_boy) {
+                return stop;
+            }
+            if done(game_boy.cpu()) {
+                return Stop::Done;
+            }
+
+            let pc = game_boy.cpu().pc();
+            if let Some(&breakpoint) = self.breakpoints.iter().find(|breakpoint| breakpoint.hits(pc)) {
+                return Stop::Breakpoint(breakpoint);
+            }
+            if interrupted.load(Ordering::Relaxed) {
+                interrupted.store(false, Ordering::Relaxed);
+                return Stop::Interrupted;
+            }
+        }
+    }
+
+    // A taken call pushes the return address; anything that pops it, RET or
+    // not, ends the frame
+    fn track_calls(&mut self, cpu: &cpu::Cpu, before: cpu::Registers, opcode: u8) {
+        let sp = cpu.registers().sp;
+        while self.calls.last().is_some_and(|frame| frame.sp < sp) {
+            self.calls.pop();
+        }
+        if disasm::is_call(opcode) && sp == before.sp.wrapping_sub(2) {
+            self.calls.push(Frame {
+                call_site: before.pc,
+                target: cpu.pc(),
+                sp,
+            });
+        }
+    }
+
+    // Watchpoints live in the MMU, which resets and state loads replace
+    fn arm(&self, game_boy: &mut GameBoy) {
+        let mmu = &mut game_boy.cpu_mut().mmu;
+        mmu.set_watchpoints(self.watchpoints.clone());
+        mmu.take_watch_hit();
+    }
+
+    fn execute(&mut self, game_boy: &mut GameBoy) -> Option<Stop> {
+        let registers = game_boy.cpu().registers();
+        let opcode = game_boy.cpu().mmu.peek(registers.pc);
+        let requested = game_boy.cpu().mmu.interrupt_flag;
+
+        if let Err(err) = game_boy.step_instruction() {
+            return Some(Stop::InvalidOpcode(err));
+        }
+        // Nothing plays audio while the debugger runs the machine
+        game_boy.discard_samples();
+
+        self.history.push_back(registers.pc);
+        if self.history.len() > HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+        self.track_calls(game_boy.cpu(), registers, opcode);
+
+        if let Some(access) = game_boy.cpu_mut().mmu.take_watch_hit() {
+            return Some(Stop::Watchpoint(access));
+        }
+
+        let raised = game_boy.cpu().mmu.interrupt_flag - requested;
+        if raised.intersects(self.break_interrupts) {
+            return Some(Stop::Interrupt(raised & self.break_interrupts));
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gameboy::tests::game_boy_running;
+
+    // 0100 LD SP, $FFFE
+    // 0103 CALL $0110
+    // 0106 INC B
+    // 0107 JR $0106
+    // 0110 INC C
+    // 0111 LDH ($80), A
+    // 0113 RET
+    fn calling_game_boy() -> GameBoy {
+        let mut code = vec![0; 0x14];
+        code[0x00..0x09].copy_from_slice(&[0x31, 0xFE, 0xFF, 0xCD, 0x10, 0x01, 0x04, 0x18, 0xFD]);
+        code[0x10..0x14].copy_from_slice(&[0x0C, 0xE0, 0x80, 0xC9]);
+        game_boy_running("DEBUGGER", &code)
+    }
+
+    #[test]
+    fn parses_breakpoints() {
+        assert_eq!(Breakpoint::parse("$150"), Some(Breakpoint { bank: None, addr: 0x0150 }));
+        assert_eq!(Breakpoint::parse("1:4000"), Some(Breakpoint { bank: Some(1), addr: 0x4000 }));
+        assert_eq!(Breakpoint::parse("1:"), None);
+        assert!(Breakpoint { bank: Some(1), addr: 0x4000 }.hits(0x4000));
+        assert!(!Breakpoint { bank: Some(2), addr: 0x4000 }.hits(0x4000));
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let mut game_boy = calling_game_boy();
+        let mut debugger = Debugger::new();
+        debugger.breakpoints.push(Breakpoint { bank: None, addr: 0x0106 });
+
+        match debugger.resume(&mut game_boy, &AtomicBool::new(false)) {
+            Stop::Breakpoint(breakpoint) => assert_eq!(breakpoint.addr, 0x0106),
+            stop => panic!("{}", stop),
+        }
+        assert_eq!(game_boy.pc(), 0x0106);
+        assert_eq!(debugger.history().back(), Some(&0x0113));
+    }
+
+    #[test]
+    fn stops_after_watched_writes() {
+        let mut game_boy = calling_game_boy();
+        let mut debugger = Debugger::new();
+        debugger.watchpoints.push(Watchpoint { start: 0xFF80, end: 0xFF80, read: false, write: true });
+
+        match debugger.resume(&mut game_boy, &AtomicBool::new(false)) {
+            Stop::Watchpoint(access) => assert_eq!((access.addr, access.write), (0xFF80, true)),
+            stop => panic!("{}", stop),
+        }
+        assert_eq!(game_boy.pc(), 0x0113);
+    }
+
+    #[test]
+    fn next_runs_calls_through() {
+        let mut game_boy = calling_game_boy();
+        let mut debugger = Debugger::new();
+        debugger.step(&mut game_boy);
+        let c = game_boy.registers().c;
+
+        assert!(matches!(debugger.step_over(&mut game_boy, &AtomicBool::new(false)), Stop::Done));
+        assert_eq!(game_boy.pc(), 0x0106);
+        assert_eq!(game_boy.registers().c, c + 1);
+        assert!(debugger.call_stack().is_empty());
+    }
+
+    #[test]
+    fn finish_returns_to_the_caller() {
+        let mut game_boy = calling_game_boy();
+        let mut debugger = Debugger::new();
+        debugger.step(&mut game_boy);
+        debugger.step(&mut game_boy);
+        assert_eq!(game_boy.pc(), 0x0110);
+        assert_eq!(debugger.call_stack().len(), 1);
+        assert_eq!(debugger.call_stack()[0].call_site, 0x0103);
+
+        assert!(matches!(debugger.step_out(&mut game_boy, &AtomicBool::new(false)), Stop::Done));
+        assert_eq!(game_boy.pc(), 0x0106);
+        assert!(debugger.call_stack().is_empty());
+    }
+
+    #[test]
+    fn stops_on_invalid_opcodes() {
+        let mut game_boy = game_boy_running("DEBUGGER", &[0x04, 0xD3]);
+        let mut debugger = Debugger::new();
+
+        match debugger.resume(&mut game_boy, &AtomicBool::new(false)) {
+            Stop::InvalidOpcode(err) => assert_eq!((err.pc, err.opcode), (0x0101, 0xD3)),
+            stop => panic!("{}", stop),
+        }
+        assert_eq!(game_boy.pc(), 0x0101);
+        assert_eq!(game_boy.registers().b, 1);
+    }
+
+    #[test]
+    fn interrupted_runs_stop() {
+        let mut game_boy = calling_game_boy();
+        let mut debugger = Debugger::new();
+        let interrupted = AtomicBool::new(true);
+
+        assert!(matches!(debugger.resume(&mut game_boy, &interrupted), Stop::Interrupted));
+        assert!(!interrupted.load(Ordering::Relaxed));
+    }
+}
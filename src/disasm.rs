@@ -0,0 +1,382 @@
+//! SM83 opcode tables and instruction decoding. The CPU takes its timings
+//! from here too, so what's shown and what runs come from the same place.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+// Bytes in a switchable ROM bank
+const BANK_SIZE: usize = 0x4000;
+// The cartridge header, which is data rather than code
+const HEADER_START: usize = 0x0104;
+const HEADER_END: usize = 0x0150;
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const PAIRS: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const STACK_PAIRS: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CONDITIONS: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = ["ADD A,", "ADC A,", "SUB", "SBC A,", "AND", "XOR", "OR", "CP"];
+const ROTATES: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+const ROTATES_A: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+
+/// T-cycles per opcode, conditional branches counted as not taken.
+pub const CYCLES: [u8; 256] = [
+//  x0  x1  x2  x3  x4  x5  x6  x7  x8  x9  xA  xB  xC  xD  xE  xF
+     4, 12,  8,  8,  4,  4,  8,  4, 20,  8,  8,  8,  4,  4,  8,  4, // 0x
+     4, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8,  8,  4,  4,  8,  4, // 1x
+     8, 12,  8,  8,  4,  4,  8,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 2x
+     8, 12,  8,  8, 12, 12, 12,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 3x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 4x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 5x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 6x
+     8,  8,  8,  8,  8,  8,  4,  8,  4,  4,  4,  4,  4,  4,  8,  4, // 7x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 8x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 9x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // Ax
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // Bx
+     8, 12, 12, 16, 12, 16,  8, 16,  8, 16, 12,  4, 12, 24,  8, 16, // Cx
+     8, 12, 12,  0, 12, 16,  8, 16,  8, 16, 12,  0, 12,  0,  8, 16, // Dx
+    12, 12,  8,  0,  0, 16,  8, 16, 16,  4, 16,  0,  0,  0,  8, 16, // Ex
+    12, 12,  8,  4,  0, 16,  8, 16, 12,  8, 16,  4,  0,  0,  8, 16, // Fx
+];
+
+/// T-cycles for a CB-prefixed opcode, including the prefix itself.
+pub fn cb_cycles(opcode: u8) -> u32 {
+    match (opcode & 0x7, opcode >> 6) {
+        (0x6, 0x1) => 12, // BIT b, (HL)
+        (0x6, _) => 16,
+        _ => 8,
+    }
+}
+
+/// A decoded instruction.
+pub struct Instruction {
+    pub text: String,
+    pub bytes: Vec<u8>,
+    /// Where a jump, call or RST goes, when it's known without running it.
+    pub target: Option<u16>,
+    /// T-cycles taken, with conditional branches counted as not taken.
+    pub cycles: u32,
+}
+
+impl Instruction {
+    pub fn length(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    /// The address, bytes and mnemonic on one line, with the target shown
+    /// as `label` if one is given.
+    pub fn line(&self, addr: u16, label: Option<&str>) -> String {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let text = match (self.target, label) {
+            (Some(target), Some(label)) => self.text.replace(&format!("${:04X}", target), label),
+            _ => self.text.clone(),
+        };
+        format!("{:04X}: {:<9} {}", addr, bytes.join(" "), text)
+    }
+}
+
+/// Opcodes the SM83 doesn't have; executing one locks up the CPU.
+pub fn is_illegal(opcode: u8) -> bool {
+    matches!(opcode, 0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD)
+}
+
+/// Decodes the instruction at `addr`, fetching its bytes with `read`.
+pub fn decode<F: Fn(u16) -> u8>(addr: u16, read: F) -> Instruction {
+    let opcode = read(addr);
+    let d8 = read(addr.wrapping_add(1));
+    let d16 = d8 as u16 | (read(addr.wrapping_add(2)) as u16) << 8;
+    let relative = addr.wrapping_add(2).wrapping_add(d8 as i8 as u16);
+
+    let (x, y, z) = (opcode >> 6, (opcode >> 3 & 0x7) as usize, (opcode & 0x7) as usize);
+    let (p, q) = (y >> 1, y & 1);
+
+    let mut target = None;
+    let (text, length) = match (x, z) {
+        (0, 0) => match y {
+            0 => ("NOP".to_string(), 1),
+            1 => (format!("LD (${:04X}), SP", d16), 3),
+            2 => ("STOP".to_string(), 2),
+            3 => {
+                target = Some(relative);
+                (format!("JR ${:04X}", relative), 2)
+            },
+            _ => {
+                target = Some(relative);
+                (format!("JR {}, ${:04X}", CONDITIONS[y - 4], relative), 2)
+            },
+        },
+        (0, 1) if q == 0 => (format!("LD {}, ${:04X}", PAIRS[p], d16), 3),
+        (0, 1) => (format!("ADD HL, {}", PAIRS[p]), 1),
+        (0, 2) => {
+            let pointer = ["(BC)", "(DE)", "(HL+)", "(HL-)"][p];
+            if q == 0 {
+                (format!("LD {}, A", pointer), 1)
+            } else {
+                (format!("LD A, {}", pointer), 1)
+            }
+        },
+        (0, 3) => (format!("{} {}", if q == 0 { "INC" } else { "DEC" }, PAIRS[p]), 1),
+        (0, 4) => (format!("INC {}", REGISTERS[y]), 1),
+        (0, 5) => (format!("DEC {}", REGISTERS[y]), 1),
+        (0, 6) => (format!("LD {}, ${:02X}", REGISTERS[y], d8), 2),
+        (0, _) => (ROTATES_A[y].to_string(), 1),
+        (1, 6) if y == 6 => ("HALT".to_string(), 1),
+        (1, _) => (format!("LD {}, {}", REGISTERS[y], REGISTERS[z]), 1),
+        (2, _) => (format!("{} {}", ALU[y], REGISTERS[z]), 1),
+        _ if is_illegal(opcode) => (format!("DB ${:02X}", opcode), 1),
+        (_, 0) => match y {
+            0..=3 => (format!("RET {}", CONDITIONS[y]), 1),
+            4 => (format!("LDH (${:02X}), A", d8), 2),
+            5 => (format!("ADD SP, {}", d8 as i8), 2),
+            6 => (format!("LDH A, (${:02X})", d8), 2),
+            _ => (format!("LD HL, SP{:+}", d8 as i8), 2),
+        },
+        (_, 1) if q == 0 => (format!("POP {}", STACK_PAIRS[p]), 1),
+        (_, 1) => (["RET", "RETI", "JP HL", "LD SP, HL"][p].to_string(), 1),
+        (_, 2) => match y {
+            0..=3 => {
+                target = Some(d16);
+                (format!("JP {}, ${:04X}", CONDITIONS[y], d16), 3)
+            },
+            4 => ("LD (C), A".to_string(), 1),
+            5 => (format!("LD (${:04X}), A", d16), 3),
+            6 => ("LD A, (C)".to_string(), 1),
+            _ => (format!("LD A, (${:04X})", d16), 3),
+        },
+        (_, 3) => match y {
+            0 => {
+                target = Some(d16);
+                (format!("JP ${:04X}", d16), 3)
+            },
+            1 => (decode_cb(d8), 2),
+            6 => ("DI".to_string(), 1),
+            _ => ("EI".to_string(), 1),
+        },
+        (_, 4) => {
+            target = Some(d16);
+            (format!("CALL {}, ${:04X}", CONDITIONS[y], d16), 3)
+        },
+        (_, 5) if q == 0 => (format!("PUSH {}", STACK_PAIRS[p]), 1),
+        (_, 5) => {
+            target = Some(d16);
+            (format!("CALL ${:04X}", d16), 3)
+        },
+        (_, 6) => (format!("{} ${:02X}", ALU[y], d8), 2),
+        _ => {
+            target = Some(y as u16 * 8);
+            (format!("RST ${:02X}", y * 8), 1)
+        },
+    };
+
+    let cycles = if opcode == 0xCB { cb_cycles(d8) } else { CYCLES[opcode as usize] as u32 };
+    Instruction {
+        text,
+        bytes: (0..length).map(|offset| read(addr.wrapping_add(offset))).collect(),
+        target,
+        cycles,
+    }
+}
+
+fn decode_cb(opcode: u8) -> String {
+    let (y, z) = ((opcode >> 3 & 0x7) as usize, (opcode & 0x7) as usize);
+    match opcode >> 6 {
+        0 => format!("{} {}", ROTATES[y], REGISTERS[z]),
+        1 => format!("BIT {}, {}", y, REGISTERS[z]),
+        2 => format!("RES {}, {}", y, REGISTERS[z]),
+        _ => format!("SET {}, {}", y, REGISTERS[z]),
+    }
+}
+
+/// Whether `opcode` is a CALL or RST, which stepping over runs until it
+/// returns.
+pub fn is_call(opcode: u8) -> bool {
+    match opcode {
+        0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => true,
+        _ => opcode & 0xC7 == 0xC7,
+    }
+}
+
+/// Names for addresses, keyed by ROM bank and address. Outside the
+/// switchable ROM area the bank is 0.
+pub type Labels = BTreeMap<(u16, u16), String>;
+
+/// Writes the whole ROM out bank by bank. Jump and call targets get a label
+/// from `labels`, or a made up one if it has none.
+pub fn disassemble_rom<W: Write>(rom: &[u8], labels: &Labels, out: &mut W) -> io::Result<()> {
+    let mut labels = labels.clone();
+    for (bank, code) in rom.chunks(BANK_SIZE).enumerate() {
+        let bank = bank as u16;
+        sweep(bank, code, |_, instruction| {
+            let target = instruction.target.and_then(|target| label_key(bank, target));
+            if let Some((target_bank, target)) = target.filter(|&(_, target)| (target as usize) < 2 * BANK_SIZE) {
+                let prefix = if is_call(instruction.bytes[0]) { "Call" } else { "Jump" };
+                labels.entry((target_bank, target))
+                    .or_insert_with(|| format!("{}_{:02X}_{:04X}", prefix, target_bank, target));
+            }
+        });
+    }
+
+    for (bank, code) in rom.chunks(BANK_SIZE).enumerate() {
+        let bank = bank as u16;
+        writeln!(out, "; ROM bank ${:02X}", bank)?;
+        let mut result = Ok(());
+        sweep(bank, code, |addr, instruction| {
+            if result.is_err() {
+                return;
+            }
+            let label = instruction.target.and_then(|target| label_key(bank, target))
+                .and_then(|key| labels.get(&key));
+            result = write_line(out, labels.get(&(bank, addr)), &instruction.line(addr, label.map(|label| label.as_str())));
+        });
+        result?;
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+fn write_line<W: Write>(out: &mut W, label: Option<&String>, line: &str) -> io::Result<()> {
+    if let Some(label) = label {
+        writeln!(out, "{}:", label)?;
+    }
+    writeln!(out, "    {}", line)
+}
+
+// Where a bank's code is mapped
+fn base(bank: u16) -> usize {
+    if bank == 0 { 0 } else { BANK_SIZE }
+}
+
+// The label for `target` as seen from code in `bank`. Nothing says which
+// bank bank 0 code finds switched in, so those targets go unlabelled.
+fn label_key(bank: u16, target: u16) -> Option<(u16, u16)> {
+    match target as usize {
+        addr if !(BANK_SIZE..2 * BANK_SIZE).contains(&addr) => Some((0, target)),
+        _ if bank != 0 => Some((bank, target)),
+        _ => None,
+    }
+}
+
+// Decodes a bank from start to end, with the cartridge header and anything
+// cut off by the end of the bank shown as data
+fn sweep<F: FnMut(u16, &Instruction)>(bank: u16, code: &[u8], mut visit: F) {
+    let base = base(bank);
+    let read = |addr: u16| code.get(addr as usize - base).cloned().unwrap_or(0);
+    let mut offset = 0;
+    while offset < code.len() {
+        let addr = (base + offset) as u16;
+        let instruction = if bank == 0 && (HEADER_START..HEADER_END).contains(&offset) {
+            data(&code[offset..HEADER_END.min(offset + 8).min(code.len())])
+        } else {
+            let instruction = decode(addr, read);
+            if offset + instruction.bytes.len() > code.len() {
+                data(&code[offset..])
+            } else {
+                instruction
+            }
+        };
+        visit(addr, &instruction);
+        offset += instruction.bytes.len();
+    }
+}
+
+fn data(bytes: &[u8]) -> Instruction {
+    let values: Vec<String> = bytes.iter().map(|byte| format!("${:02X}", byte)).collect();
+    Instruction {
+        text: format!("DB {}", values.join(", ")),
+        bytes: bytes.to_vec(),
+        target: None,
+        cycles: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_bytes(bytes: &[u8], addr: u16) -> Instruction {
+        decode(addr, |at| bytes.get(at.wrapping_sub(addr) as usize).cloned().unwrap_or(0))
+    }
+
+    #[test]
+    fn decodes_operands() {
+        let cases: [(&[u8], &str); 12] = [
+            (&[0x00], "NOP"),
+            (&[0x01, 0x34, 0x12], "LD BC, $1234"),
+            (&[0x22], "LD (HL+), A"),
+            (&[0x36, 0x7F], "LD (HL), $7F"),
+            (&[0x76], "HALT"),
+            (&[0x78], "LD A, B"),
+            (&[0xAF], "XOR A"),
+            (&[0xE0, 0x40], "LDH ($40), A"),
+            (&[0xE8, 0xFE], "ADD SP, -2"),
+            (&[0xCB, 0x7C], "BIT 7, H"),
+            (&[0xCB, 0x37], "SWAP A"),
+            (&[0xD3], "DB $D3"),
+        ];
+        for &(bytes, text) in cases.iter() {
+            let instruction = decode_bytes(bytes, 0x0150);
+            assert_eq!(instruction.text, text);
+            assert_eq!(instruction.bytes, bytes);
+        }
+    }
+
+    #[test]
+    fn decodes_branch_targets() {
+        assert_eq!(decode_bytes(&[0x18, 0xFE], 0x0100).target, Some(0x0100));
+        assert_eq!(decode_bytes(&[0x20, 0x05], 0x0100).text, "JR NZ, $0107");
+        assert_eq!(decode_bytes(&[0xC3, 0x50, 0x01], 0x0100).target, Some(0x0150));
+        assert_eq!(decode_bytes(&[0xCD, 0x00, 0x40], 0x0100).text, "CALL $4000");
+        assert_eq!(decode_bytes(&[0xEF], 0x0100).target, Some(0x0028));
+        assert_eq!(decode_bytes(&[0xC9], 0x0100).target, None);
+    }
+
+    #[test]
+    fn cycles_come_from_the_tables() {
+        assert_eq!(decode_bytes(&[0x00], 0).cycles, 4);
+        assert_eq!(decode_bytes(&[0x08, 0x00, 0xC0], 0).cycles, 20);
+        assert_eq!(decode_bytes(&[0xCD, 0x00, 0x40], 0).cycles, 24);
+        assert_eq!(decode_bytes(&[0xCB, 0x46], 0).cycles, 12);
+        assert_eq!(decode_bytes(&[0xCB, 0x86], 0).cycles, 16);
+        assert_eq!(decode_bytes(&[0xCB, 0x11], 0).cycles, 8);
+    }
+
+    #[test]
+    fn only_illegal_opcodes_take_no_cycles() {
+        for opcode in 0..=0xFFu8 {
+            assert_eq!(CYCLES[opcode as usize] == 0, is_illegal(opcode), "{:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn lengths_follow_the_operands() {
+        assert_eq!(decode_bytes(&[0x00], 0).length(), 1);
+        assert_eq!(decode_bytes(&[0x10, 0x00], 0).length(), 2);
+        assert_eq!(decode_bytes(&[0x18, 0x00], 0).length(), 2);
+        assert_eq!(decode_bytes(&[0xCB, 0x00], 0).length(), 2);
+        assert_eq!(decode_bytes(&[0xF8, 0x01], 0).length(), 2);
+        assert_eq!(decode_bytes(&[0xEA, 0x00, 0xC0], 0).length(), 3);
+        assert_eq!(decode_bytes(&[0xD3], 0).length(), 1);
+    }
+
+    #[test]
+    fn is_call_covers_calls_and_rsts() {
+        let calls: Vec<u8> = (0..=0xFFu8).filter(|&opcode| is_call(opcode)).collect();
+        assert_eq!(calls, vec![0xC4, 0xC7, 0xCC, 0xCD, 0xCF, 0xD4, 0xD7, 0xDC, 0xDF,
+                               0xE7, 0xEF, 0xF7, 0xFF]);
+    }
+
+    #[test]
+    fn labels_rom_jumps() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        let mut labels = Labels::new();
+        labels.insert((0, 0x0100), "Start".to_string());
+
+        let mut out = Vec::new();
+        disassemble_rom(&rom, &labels, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("Start:\n    0100: 00        NOP\n    0101: C3 50 01  JP Jump_00_0150\n"));
+        assert!(text.contains("Jump_00_0150:\n    0150: 00        NOP\n"));
+        assert!(text.contains("; ROM bank $01\n"));
+    }
+}
@@ -0,0 +1,532 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustboy::{GameBoy, InvalidOpcode, SCREEN_HEIGHT, SCREEN_WIDTH};
+use rustboy::movie::Movie;
+use rustboy::rewind::Rewind;
+use rustboy::savestate::{Header, StateReader};
+
+use png;
+
+use super::audio;
+use super::cli;
+use super::input;
+use super::pacing;
+use super::palette;
+use super::video;
+
+use glium::backend::glutin::SimpleWindowBuilder;
+use glium::winit::application::ApplicationHandler;
+use glium::winit::event::{ElementState, WindowEvent};
+use glium::winit::event_loop::{ActiveEventLoop, EventLoop};
+use glium::winit::keyboard::PhysicalKey;
+use glium::winit::platform::pump_events::{EventLoopExtPumpEvents, PumpStatus};
+use glium::winit::window::{Fullscreen, Window, WindowId};
+use sdl2;
+
+const STATE_SLOTS: u32 = 10;
+
+/// A movie being recorded and where it will be saved.
+struct Recording {
+    movie: Movie,
+    path: PathBuf,
+}
+
+/// Window, audio and input around a `GameBoy`.
+pub struct Frontend {
+    game_boy: GameBoy,
+    event_loop: EventLoop<()>,
+    window: Window,
+    video: video::Video,
+    input: input::Input,
+    audio: Option<audio::AudioOutput>,
+
+    palette: palette::Palette,
+    save_dir: PathBuf,
+    rom_name: String,
+    state_slot: u32,
+
+    // Which linked machine receives input
+    player: usize,
+
+    // Frame count at the last upload, so each emulated frame is drawn once
+    drawn_frame: u64,
+    redraw: bool,
+    frame_limit: Option<u32>,
+    frames: u32,
+    frame_skip: u32,
+    last_present: Instant,
+
+    // Off when the history length is 0
+    rewind: Option<Rewind>,
+
+    recording: Option<Recording>,
+    playback: Option<Movie>,
+    playback_frame: usize,
+
+    pacer: pacing::FramePacer,
+    sync: pacing::Sync,
+    // Multiples of normal speed; a fast-forward speed of 0 is uncapped
+    fast_forward_speed: f64,
+    slow_motion_speed: f64,
+
+    running: bool,
+    paused: bool,
+    advance_frame: bool,
+    fast_forward: bool,
+    slow_motion: bool,
+    rewinding: bool,
+}
+
+impl Frontend {
+    pub fn new(game_boy: GameBoy, bindings: input::Bindings, options: &cli::Options) -> Result<Self, String> {
+        let screens = if game_boy.is_linked() { 2 } else { 1 };
+        let width = SCREEN_WIDTH as u32 * screens * options.scale;
+        let height = SCREEN_HEIGHT as u32 * options.scale;
+
+        let event_loop = EventLoop::new().unwrap();
+        let (window, display) = SimpleWindowBuilder::new()
+            .with_inner_size(width, height)
+            .with_title("Rust Boy")
+            .build(&event_loop);
+
+        if options.fullscreen {
+            window.set_fullscreen(Some(Fullscreen::Borderless(None)));
+        }
+
+        let video = video::Video::new(display,
+                                      screens as usize,
+                                      options.palette,
+                                      options.filter,
+                                      options.shader,
+                                      options.integer_scale)?;
+
+        let sdl = match sdl2::init() {
+            Ok(sdl) => Some(sdl),
+            Err(err) => {
+                println!("SDL unavailable: {}", err);
+                None
+            }
+        };
+
+        let audio = match sdl.as_ref().map(|sdl| audio::AudioOutput::new(sdl, audio::DEFAULT_SAMPLE_RATE)) {
+            Some(Ok(audio)) => Some(audio),
+            Some(Err(err)) => {
+                println!("Audio disabled: {}", err);
+                None
+            },
+            None => None,
+        };
+
+        let rewind_snapshots = (options.rewind_seconds * pacing::FRAME_RATE / options.rewind_interval as f64).ceil() as usize;
+        let rewind = if rewind_snapshots > 0 {
+            Some(Rewind::new(rewind_snapshots, options.rewind_interval))
+        } else {
+            None
+        };
+
+        let frontend = Frontend {
+            game_boy,
+            event_loop,
+            window,
+            video,
+            input: input::Input::new(bindings, sdl.as_ref()),
+            audio,
+
+            palette: options.palette,
+            save_dir: options.save_dir.clone(),
+            rom_name: options.rom.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "screenshot".to_string()),
+            state_slot: 0,
+
+            player: 0,
+
+            drawn_frame: 0,
+            redraw: true,
+            frame_limit: options.frames,
+            frames: 0,
+            frame_skip: options.frame_skip,
+            last_present: Instant::now(),
+
+            rewind,
+
+            recording: None,
+            playback: None,
+            playback_frame: 0,
+
+            pacer: pacing::FramePacer::new(),
+            sync: options.sync,
+            fast_forward_speed: options.fast_forward_speed,
+            slow_motion_speed: options.slow_motion_speed,
+
+            running: false,
+            paused: false,
+            advance_frame: false,
+            fast_forward: false,
+            slow_motion: false,
+            rewinding: false,
+        };
+        frontend.update_title();
+        Ok(frontend)
+    }
+
+    /// Plays `movie`, which must already have been started, in place of
+    /// live input.
+    pub fn play_movie(&mut self, movie: Movie) {
+        self.playback = Some(movie);
+        self.playback_frame = 0;
+    }
+
+    /// Records input into `movie`, saving it to `path` when recording stops.
+    pub fn record_movie(&mut self, movie: Movie, path: PathBuf) {
+        self.recording = Some(Recording {
+            movie,
+            path,
+        });
+    }
+
+    fn update_title(&self) {
+        if self.game_boy.is_linked() {
+            self.window.set_title(&format!("Rust Boy - Player {}", self.player + 1));
+        }
+    }
+
+    /// Runs until the window closes or the frame limit is reached. An
+    /// opcode the CPU can't execute ends the run and is returned.
+    pub fn run(&mut self) -> Result<(), InvalidOpcode> {
+        self.running = true;
+
+        while self.running {
+            self.handle_events();
+
+            // Plays the history backwards at normal speed, showing each
+            // snapshot for as many frames as it stands for
+            if self.rewinding {
+                let mut interval = 1;
+                if let Some(ref mut rewind) = self.rewind {
+                    if let Err(err) = rewind.step_back(&mut self.game_boy) {
+                        println!("Rewind failed: {}", err);
+                    }
+                    interval = rewind.interval();
+                }
+                self.draw(false);
+                self.pacer.wait(1.0 / interval as f64);
+                continue;
+            }
+
+            if self.paused && !self.advance_frame {
+                self.draw(false);
+                thread::sleep(Duration::from_millis(16));
+                self.pacer.reset();
+                continue;
+            }
+            self.advance_frame = false;
+
+            self.movie_frame();
+            if let Err(err) = self.game_boy.run_frame() {
+                self.stop_recording();
+                return Err(err);
+            }
+            self.frames += 1;
+            if let Some(ref mut rewind) = self.rewind {
+                rewind.record(&self.game_boy);
+            }
+            if self.frame_limit.is_some_and(|limit| self.frames >= limit) {
+                self.running = false;
+            }
+
+            // Audio only plays at normal speed
+            let speed = self.speed();
+            let samples = self.game_boy.take_samples();
+            if speed == Some(1.0) {
+                if let Some(ref mut audio) = self.audio {
+                    audio.push(&samples);
+                }
+            }
+
+            let skip = !self.frames.is_multiple_of(self.frame_skip + 1) ||
+                (speed.is_none_or(|speed| speed > 1.0) && self.last_present.elapsed().as_secs_f64() < 1.0 / pacing::FRAME_RATE);
+            self.draw(skip);
+
+            match speed {
+                Some(1.0) if self.sync == pacing::Sync::Audio && self.audio.is_some() => {
+                    if let Some(ref audio) = self.audio {
+                        audio.wait_for_space();
+                    }
+                },
+                Some(speed) => self.pacer.wait(speed),
+                None => {},
+            }
+        }
+
+        self.stop_recording();
+        Ok(())
+    }
+
+    // Records or plays back the input for the frame about to run
+    fn movie_frame(&mut self) {
+        if let Some(ref mut recording) = self.recording {
+            recording.movie.record_frame(&self.game_boy);
+        }
+
+        let finished = match self.playback {
+            Some(ref movie) => !movie.play_frame(self.playback_frame, &mut self.game_boy),
+            None => false,
+        };
+        if self.playback.is_some() {
+            self.playback_frame += 1;
+        }
+        if finished {
+            println!("Movie finished after {} frames", self.playback_frame - 1);
+            self.playback = None;
+            for player in 0..2 {
+                self.game_boy.set_buttons(player, 0);
+            }
+        }
+    }
+
+    fn toggle_recording(&mut self) {
+        if self.recording.is_some() {
+            self.stop_recording();
+        } else if self.playback.is_some() {
+            println!("Can't record while a movie is playing");
+        } else {
+            let path = self.numbered_path("rbm");
+            println!("Recording movie to {}", path.display());
+            let movie = Movie::record_from_state(&self.game_boy);
+            self.record_movie(movie, path);
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        if let Some(recording) = self.recording.take() {
+            let result = fs::create_dir_all(&self.save_dir).and_then(|_| recording.movie.save(&recording.path));
+            match result {
+                Ok(()) => println!("Saved {} frame movie to {}", recording.movie.frames(), recording.path.display()),
+                Err(err) => println!("Failed to save movie to {}: {}", recording.path.display(), err),
+            }
+        }
+    }
+
+    /// Emulation speed relative to real hardware, or `None` for as fast as
+    /// possible.
+    fn speed(&self) -> Option<f64> {
+        if self.fast_forward {
+            if self.fast_forward_speed > 0.0 { Some(self.fast_forward_speed) } else { None }
+        } else if self.slow_motion {
+            Some(self.slow_motion_speed)
+        } else {
+            Some(1.0)
+        }
+    }
+
+    /// Draws once per emulated VBlank, or again if the window needs it. A
+    /// skipped frame is not shown at all.
+    fn draw(&mut self, skip: bool) {
+        let frame_count = self.game_boy.frame_count();
+        if frame_count != self.drawn_frame && !skip {
+            self.drawn_frame = frame_count;
+
+            let mut frames = vec![self.game_boy.frame()];
+            frames.extend(self.game_boy.peer_frame());
+            self.video.upload(&frames);
+            self.redraw = true;
+        }
+
+        if self.redraw {
+            self.video.draw();
+            self.redraw = false;
+            self.last_present = Instant::now();
+        }
+    }
+
+    fn toggle_fullscreen(&self) {
+        if self.window.fullscreen().is_some() {
+            self.window.set_fullscreen(None);
+        } else {
+            self.window.set_fullscreen(Some(Fullscreen::Borderless(None)));
+        }
+    }
+
+    fn handle_events(&mut self) {
+        let mut window_events = WindowEvents { events: Vec::new() };
+        let status = self.event_loop.pump_app_events(Some(Duration::ZERO), &mut window_events);
+        if let PumpStatus::Exit(_) = status {
+            self.running = false;
+        }
+
+        let mut events = self.input.poll_controllers();
+        for event in window_events.events {
+            match event {
+                WindowEvent::CloseRequested => self.running = false,
+                WindowEvent::Resized(size) => {
+                    self.video.resize(size.width, size.height);
+                    self.redraw = true;
+                },
+                WindowEvent::RedrawRequested => self.redraw = true,
+                WindowEvent::KeyboardInput { event, .. } => {
+                    if event.repeat {
+                        continue;
+                    }
+                    if let PhysicalKey::Code(key) = event.physical_key {
+                        let pressed = event.state == ElementState::Pressed;
+                        events.extend(self.input.key_event(key, pressed));
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        for event in events {
+            match event.action {
+                // Movies replace live input until they end
+                input::Action::Button(_) if self.playback.is_some() => {},
+                input::Action::Button(button) => {
+                    if event.pressed {
+                        self.game_boy.press(self.player, button);
+                    } else {
+                        self.game_boy.release(self.player, button);
+                    }
+                },
+                input::Action::Hotkey(hotkey) => self.handle_hotkey(hotkey, event.pressed),
+            }
+        }
+    }
+
+    fn handle_hotkey(&mut self, hotkey: input::Hotkey, pressed: bool) {
+        let movie_active = self.recording.is_some() || self.playback.is_some();
+        match hotkey {
+            input::Hotkey::Rewind |
+            input::Hotkey::Reset |
+            input::Hotkey::LoadState if movie_active => {
+                if pressed {
+                    println!("{:?} is unavailable while a movie is recording or playing", hotkey);
+                }
+            },
+            input::Hotkey::FastForward => self.fast_forward = pressed,
+            input::Hotkey::Rewind => self.rewinding = pressed,
+            _ if !pressed => {},
+            input::Hotkey::Pause => self.paused = !self.paused,
+            input::Hotkey::FrameAdvance => {
+                self.paused = true;
+                self.advance_frame = true;
+            },
+            input::Hotkey::SlowMotion => self.slow_motion = !self.slow_motion,
+            input::Hotkey::Reset => self.game_boy.reset(),
+            input::Hotkey::Quit => self.running = false,
+            input::Hotkey::SwitchPlayer => {
+                if self.game_boy.is_linked() {
+                    self.player = 1 - self.player;
+                    self.update_title();
+                }
+            },
+            input::Hotkey::Fullscreen => self.toggle_fullscreen(),
+            input::Hotkey::Screenshot => {
+                match self.save_screenshot() {
+                    Ok(path) => println!("Saved screenshot to {}", path.display()),
+                    Err(err) => println!("Failed to save screenshot: {}", err),
+                }
+            },
+            input::Hotkey::SaveState => {
+                match self.save_state() {
+                    Ok(path) => println!("Saved state to {}", path.display()),
+                    Err(err) => println!("Failed to save state: {}", err),
+                }
+            },
+            input::Hotkey::LoadState => {
+                match self.load_state() {
+                    Ok(()) => println!("Loaded state from slot {}", self.state_slot),
+                    Err(err) => println!("Failed to load state from slot {}: {}", self.state_slot, err),
+                }
+            },
+            input::Hotkey::PreviousSlot => self.select_slot(self.state_slot + STATE_SLOTS - 1),
+            input::Hotkey::NextSlot => self.select_slot(self.state_slot + 1),
+            input::Hotkey::RecordMovie => self.toggle_recording(),
+        }
+    }
+
+    fn state_path(&self, slot: u32) -> PathBuf {
+        self.save_dir.join(format!("{}.ss{}", self.rom_name, slot))
+    }
+
+    fn select_slot(&mut self, slot: u32) {
+        self.state_slot = slot % STATE_SLOTS;
+
+        let path = self.state_path(self.state_slot);
+        let description = match fs::read(&path) {
+            Ok(data) => match Header::read(&mut StateReader::new(&data)) {
+                Ok(header) => format!("{}, saved by Rust Boy {}", header.rom_title, header.emulator_version),
+                Err(err) => err.to_string(),
+            },
+            Err(_) => "empty".to_string(),
+        };
+        println!("State slot {}: {}", self.state_slot, description);
+    }
+
+    fn save_state(&self) -> io::Result<PathBuf> {
+        let path = self.state_path(self.state_slot);
+        fs::create_dir_all(&self.save_dir)?;
+        fs::write(&path, self.game_boy.save_state())?;
+        Ok(path)
+    }
+
+    fn load_state(&mut self) -> Result<(), String> {
+        let path = self.state_path(self.state_slot);
+        let data = fs::read(&path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => "slot is empty".to_string(),
+            _ => format!("{}: {}", path.display(), err),
+        })?;
+        self.game_boy.load_state(&data).map_err(|err| err.to_string())?;
+
+        // The restored frame may share a count with the one on screen
+        self.drawn_frame = self.game_boy.frame_count().wrapping_sub(1);
+        Ok(())
+    }
+
+    /// Writes the focused player's last frame as a PNG in the save
+    /// directory.
+    fn save_screenshot(&self) -> io::Result<PathBuf> {
+        let frame = match self.game_boy.peer_frame() {
+            Some(frame) if self.player == 1 => frame,
+            _ => self.game_boy.frame(),
+        };
+
+        fs::create_dir_all(&self.save_dir)?;
+        let path = self.numbered_path("png");
+
+        let pixels = self.palette.frame_to_rgb(frame);
+
+        let file = fs::File::create(&path)?;
+        let mut encoder = png::Encoder::new(io::BufWriter::new(file), SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&pixels)?;
+
+        Ok(path)
+    }
+
+    // The first unused `<rom>_NNN.<extension>` in the save directory
+    fn numbered_path(&self, extension: &str) -> PathBuf {
+        (1..)
+            .map(|index| self.save_dir.join(format!("{}_{:03}.{}", self.rom_name, index, extension)))
+            .find(|path| !path.exists())
+            .unwrap()
+    }
+}
+
+/// Collects the window's events while the event loop is pumped.
+struct WindowEvents {
+    events: Vec<WindowEvent>,
+}
+
+impl ApplicationHandler for WindowEvents {
+    fn resumed(&mut self, _event_loop: &ActiveEventLoop) {}
+
+    fn window_event(&mut self, _event_loop: &ActiveEventLoop, _window_id: WindowId, event: WindowEvent) {
+        self.events.push(event);
+    }
+}
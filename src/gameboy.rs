@@ -1,43 +1,526 @@
+use super::apu;
+use super::cartridge;
 use super::cpu;
+use super::joypad;
+use super::link;
 use super::mmu;
+use super::model;
+use super::savestate::{Header, Savable, StateError, StateReader, StateWriter};
+use super::serial;
+use super::trace::Tracer;
+use super::wav;
 
-use glium;
-use glium::DisplayBuild;
+pub const CYCLES_PER_FRAME: u32 = 70224;
 
+/// The emulated machine, with no window, audio device or input attached.
+/// A frontend drives it a frame at a time and reads the frame and audio
+/// back out.
 pub struct GameBoy {
     cpu: cpu::Cpu,
-    display: glium::Display,
+    recorder: Option<wav::AudioRecorder>,
+    samples: Vec<apu::StereoSample>,
+    frame_cycles: u32,
+    // Mix settings, which outlive the APU across resets and state loads
+    voice_volumes: [f32; 4],
+    voice_muted: [bool; 4],
+
+    model: model::Model,
+    boot_rom: Option<Vec<u8>>,
+    cartridge: Option<cartridge::Cartridge>,
+    tracer: Option<Tracer>,
+
+    // Second machine on the other end of a link cable, run in lockstep
+    peer: Option<cpu::Cpu>,
+    peer_cartridge: Option<cartridge::Cartridge>,
+    peer_lag: i32,
+}
+
+impl Default for GameBoy {
+    fn default() -> Self {
+        GameBoy::new()
+    }
 }
 
 impl GameBoy {
     pub fn new() -> Self {
-        let display = glium::glutin::WindowBuilder::new()
-            .with_dimensions(160, 144)
-            .with_title("Rust Boy")
-            .build_glium()
-            .unwrap();
-
         GameBoy {
             cpu: cpu::Cpu::new(),
-            display: display,
+            recorder: None,
+            samples: Vec::new(),
+            frame_cycles: 0,
+            voice_volumes: [1.0; 4],
+            voice_muted: [false; 4],
+
+            model: model::Model::Dmg,
+            boot_rom: None,
+            cartridge: None,
+            tracer: None,
+
+            peer: None,
+            peer_cartridge: None,
+            peer_lag: 0,
         }
     }
 
-    pub fn power_on(&mut self, boot_rom: Vec<u8>, game_rom: Vec<u8>) {
-        self.load_rom(game_rom);
-        self.load_rom(boot_rom);
+    pub fn record_audio(&mut self, recorder: wav::AudioRecorder) {
+        self.cpu.mmu.apu.set_capture_voices(recorder.records_voices());
+        self.recorder = Some(recorder);
+    }
+
+    /// Scales one voice in the mix, 1.0 being its normal level.
+    pub fn set_voice_volume(&mut self, voice: apu::Voice, volume: f32) {
+        self.voice_volumes[voice as usize] = volume;
+        self.cpu.mmu.apu.set_voice_volume(voice, volume);
+    }
+
+    pub fn set_voice_muted(&mut self, voice: apu::Voice, muted: bool) {
+        self.voice_muted[voice as usize] = muted;
+        self.cpu.mmu.apu.set_voice_muted(voice, muted);
+    }
+
+    pub fn connect_serial(&mut self, device: Box<dyn serial::SerialDevice>) {
+        self.cpu.mmu.serial.connect(device);
+    }
+
+    /// Runs a second Game Boy with `cartridge` alongside this one, connected
+    /// by a link cable.
+    pub fn link(&mut self, cartridge: cartridge::Cartridge) {
+        let (port, peer_port) = link::cable();
+        self.cpu.mmu.serial.connect(Box::new(port));
+
+        let mut peer = cpu::Cpu::new();
+        peer.mmu.serial.connect(Box::new(peer_port));
+        self.peer = Some(peer);
+        self.peer_cartridge = Some(cartridge);
+    }
+
+    pub fn is_linked(&self) -> bool {
+        self.peer.is_some()
+    }
+
+    /// Presses a button for `player`, 0 being this machine and 1 a linked
+    /// peer.
+    pub fn press(&mut self, player: usize, button: joypad::Button) {
+        self.player_cpu(player).mmu.press_button(button);
+    }
+
+    pub fn release(&mut self, player: usize, button: joypad::Button) {
+        self.player_cpu(player).mmu.release_button(button);
+    }
 
-        self.run()
+    /// The buttons `player` is holding, one bit each in `joypad::BUTTONS`
+    /// order.
+    pub fn buttons(&self, player: usize) -> u8 {
+        match self.peer {
+            Some(ref peer) if player == 1 => peer.mmu.pressed_buttons(),
+            _ => self.cpu.mmu.pressed_buttons(),
+        }
     }
 
-    fn run(&mut self) {
-        self.cpu.run();
+    /// Presses and releases buttons so `player` holds exactly `buttons`.
+    pub fn set_buttons(&mut self, player: usize, buttons: u8) {
+        for (bit, &button) in joypad::BUTTONS.iter().enumerate() {
+            if buttons & 1 << bit != 0 {
+                self.press(player, button);
+            } else {
+                self.release(player, button);
+            }
+        }
     }
 
-    fn load_rom(&mut self, rom_data: Vec<u8>) {
-        for (idx, op) in rom_data.iter().enumerate().take(0x2000) {
-            self.cpu.mmu.write_byte(idx as u16, *op)
+    fn player_cpu(&mut self, player: usize) -> &mut cpu::Cpu {
+        match self.peer {
+            Some(ref mut peer) if player == 1 => peer,
+            _ => &mut self.cpu,
         }
     }
+
+    /// Takes effect from the next reset.
+    pub fn set_model(&mut self, model: model::Model) {
+        self.model = model;
+    }
+
+    pub fn model(&self) -> model::Model {
+        self.model
+    }
+
+    /// Logs every instruction this machine executes, but not a linked
+    /// peer's.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Without a boot ROM the machine starts at 0x100 in the state the
+    /// model's boot ROM would have left it in.
+    pub fn power_on(&mut self, boot_rom: Option<Vec<u8>>, cartridge: cartridge::Cartridge) {
+        self.boot_rom = boot_rom;
+        self.cartridge = Some(cartridge);
+        self.reset();
+    }
+
+    pub fn cartridge(&self) -> Option<&cartridge::Cartridge> {
+        self.cartridge.as_ref()
+    }
+
+    /// The machine's CPU, and through it memory, for inspection.
+    pub(crate) fn cpu(&self) -> &cpu::Cpu {
+        &self.cpu
+    }
+
+    /// For debuggers, which edit memory and set watchpoints.
+    pub(crate) fn cpu_mut(&mut self) -> &mut cpu::Cpu {
+        &mut self.cpu
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.cpu.pc()
+    }
+
+    pub fn registers(&self) -> cpu::Registers {
+        self.cpu.registers()
+    }
+
+    pub fn set_registers(&mut self, registers: cpu::Registers) {
+        self.cpu.set_registers(registers);
+    }
+
+    /// Reads memory without the side effects a read by the CPU would have.
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.cpu.mmu.peek(addr)
+    }
+
+    /// Writes memory for a debugger. Returns false for addresses with
+    /// nothing mapped that can be written.
+    pub fn poke(&mut self, addr: u16, value: u8) -> bool {
+        self.cpu.mmu.poke(addr, value)
+    }
+
+    /// The IF register: interrupts waiting to be serviced.
+    pub fn interrupt_flag(&self) -> mmu::Interrupts {
+        self.cpu.mmu.interrupt_flag
+    }
+
+    /// The IE register.
+    pub fn interrupt_enable(&self) -> u8 {
+        self.cpu.mmu.interrupt_enable
+    }
+
+    pub fn reset(&mut self) {
+        let serial_device = self.cpu.mmu.serial.disconnect();
+        self.cpu = cpu::Cpu::new();
+        self.cpu.mmu.serial.connect(serial_device);
+        self.frame_cycles = 0;
+
+        if let Some(ref cartridge) = self.cartridge {
+            load_rom(&mut self.cpu, cartridge.rom());
+        }
+        boot(&mut self.cpu, self.model, &self.boot_rom);
+
+        if let Some(ref recorder) = self.recorder {
+            self.cpu.mmu.apu.set_capture_voices(recorder.records_voices());
+        }
+        set_mix(&mut self.cpu.mmu.apu, &self.voice_volumes, &self.voice_muted);
+
+        if let Some(mut peer) = self.peer.take() {
+            let serial_device = peer.mmu.serial.disconnect();
+            peer = cpu::Cpu::new();
+            peer.mmu.serial.connect(serial_device);
+
+            if let Some(ref cartridge) = self.peer_cartridge {
+                load_rom(&mut peer, cartridge.rom());
+            }
+            boot(&mut peer, self.model, &self.boot_rom);
+
+            self.peer = Some(peer);
+            self.peer_lag = 0;
+        }
+    }
+
+    /// Snapshots the whole machine, and a linked peer, in the save state
+    /// format.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut machine = StateWriter::new();
+        self.cpu.save(&mut machine);
+        machine.write_u32(self.frame_cycles);
+
+        machine.write_bool(self.peer.is_some());
+        if let Some(ref peer) = self.peer {
+            peer.save(&mut machine);
+            machine.write_i32(self.peer_lag);
+        }
+
+        let mut state = StateWriter::new();
+        match self.cartridge {
+            Some(ref cartridge) => Header::new(&cartridge.title(), cartridge.rom(), self.frame()),
+            None => Header::new("", &[], self.frame()),
+        }.write(&mut state);
+        state.write_checksummed(&machine.into_bytes());
+        state.into_bytes()
+    }
+
+    /// Restores a snapshot taken by `save_state`. Nothing changes unless the
+    /// whole state loads, and states saved with another ROM are refused.
+    /// Connected serial devices and audio recording carry on as they were.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data);
+        let header = Header::read(&mut state)?;
+        if let Some(ref cartridge) = self.cartridge {
+            header.check_rom(&cartridge.title(), cartridge.rom())?;
+        }
+        state.verify_checksum()?;
+
+        let mut cpu = cpu::Cpu::new();
+        cpu.load(&mut state)?;
+        let frame_cycles = state.read_u32()?;
+
+        let linked = state.read_bool()?;
+        if linked != self.peer.is_some() {
+            return Err(StateError::LinkMismatch { linked });
+        }
+        let peer = if linked {
+            let mut peer = cpu::Cpu::new();
+            peer.load(&mut state)?;
+            Some((peer, state.read_i32()?))
+        } else {
+            None
+        };
+        state.finish()?;
+
+        cpu.mmu.serial.connect(self.cpu.mmu.serial.disconnect());
+        if let Some(ref recorder) = self.recorder {
+            cpu.mmu.apu.set_capture_voices(recorder.records_voices());
+        }
+        set_mix(&mut cpu.mmu.apu, &self.voice_volumes, &self.voice_muted);
+        self.cpu = cpu;
+        self.frame_cycles = frame_cycles;
+
+        if let (Some((mut peer, peer_lag)), Some(old_peer)) = (peer, self.peer.as_mut()) {
+            peer.mmu.serial.connect(old_peer.mmu.serial.disconnect());
+            *old_peer = peer;
+            self.peer_lag = peer_lag;
+        }
+
+        Ok(())
+    }
+
+    /// The last frame drawn, one shade per pixel from 0 (white) to 3 (black).
+    pub fn frame(&self) -> &[u8] {
+        self.cpu.mmu.frame()
+    }
+
+    /// Increases each time a new frame is ready, at VBlank.
+    pub fn frame_count(&self) -> u64 {
+        self.cpu.mmu.frame_count()
+    }
+
+    pub fn peer_frame(&self) -> Option<&[u8]> {
+        self.peer.as_ref().map(|peer| peer.mmu.frame())
+    }
+
+    /// Takes the audio produced since the last call, at `apu::SAMPLE_RATE`.
+    /// Only this machine is heard when linked.
+    pub fn take_samples(&mut self) -> Vec<apu::StereoSample> {
+        ::std::mem::take(&mut self.samples)
+    }
+
+    /// Drops the audio produced so far, for callers with nowhere to play it
+    /// that would otherwise let it pile up.
+    pub fn discard_samples(&mut self) {
+        self.samples.clear();
+    }
+
+    pub fn run_frames(&mut self, frames: u32) -> Result<(), cpu::InvalidOpcode> {
+        for _ in 0..frames {
+            self.run_frame()?;
+        }
+        Ok(())
+    }
+
+    /// Runs until `condition` holds after an instruction, giving up after
+    /// `max_frames`. Returns whether the condition was met.
+    pub fn run_until<F>(&mut self, max_frames: u32, mut condition: F) -> Result<bool, cpu::InvalidOpcode>
+        where F: FnMut(&GameBoy) -> bool
+    {
+        for _ in 0..max_frames {
+            while self.frame_cycles < CYCLES_PER_FRAME {
+                self.step()?;
+                if condition(self) {
+                    self.collect_samples();
+                    return Ok(true);
+                }
+            }
+            self.end_frame();
+        }
+
+        Ok(false)
+    }
+
+    /// Runs the machine for one frame's worth of T-cycles. An opcode either
+    /// machine can't execute stops the frame where it is and is returned.
+    pub fn run_frame(&mut self) -> Result<(), cpu::InvalidOpcode> {
+        while self.frame_cycles < CYCLES_PER_FRAME {
+            self.step()?;
+        }
+        self.end_frame();
+        Ok(())
+    }
+
+    /// Executes a single instruction, finishing the frame if it ends there.
+    /// An opcode the CPU can't execute is left at PC and returned.
+    pub fn step_instruction(&mut self) -> Result<(), cpu::InvalidOpcode> {
+        self.trace();
+        let cycles = self.cpu.step()?;
+        self.advance(cycles)?;
+        if self.frame_cycles >= CYCLES_PER_FRAME {
+            self.end_frame();
+        }
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<(), cpu::InvalidOpcode> {
+        self.trace();
+        let cycles = self.cpu.step()?;
+        self.advance(cycles)
+    }
+
+    fn trace(&mut self) {
+        let failed = match self.tracer {
+            Some(ref mut tracer) => tracer.log(&self.cpu).err(),
+            None => return,
+        };
+        if let Some(err) = failed {
+            eprintln!("Trace stopped: {}", err);
+            self.tracer = None;
+        }
+    }
+
+    /// Runs the rest of the machine for an instruction's `cycles`. A linked
+    /// peer is kept within one instruction of this machine so serial
+    /// transfers happen at the same point every run.
+    fn advance(&mut self, cycles: u32) -> Result<(), cpu::InvalidOpcode> {
+        self.cpu.mmu.step(cycles);
+        self.frame_cycles += cycles;
+
+        if let Some(ref mut peer) = self.peer {
+            self.peer_lag += cycles as i32;
+            while self.peer_lag > 0 {
+                let cycles = peer.step()?;
+                peer.mmu.step(cycles);
+                self.peer_lag -= cycles as i32;
+            }
+        }
+        Ok(())
+    }
+
+    fn end_frame(&mut self) {
+        self.frame_cycles -= CYCLES_PER_FRAME;
+        self.collect_samples();
+    }
+
+    fn collect_samples(&mut self) {
+        let samples = self.cpu.mmu.apu.take_samples();
+        let voice_samples = self.cpu.mmu.apu.take_voice_samples();
+
+        if let Some(ref mut peer) = self.peer {
+            peer.mmu.apu.take_samples();
+        }
+
+        let recording_failed = match self.recorder {
+            Some(ref mut recorder) => recorder.push(&samples, &voice_samples).err(),
+            None => None,
+        };
+        if let Some(err) = recording_failed {
+            eprintln!("Audio recording stopped: {}", err);
+            self.recorder = None;
+            self.cpu.mmu.apu.set_capture_voices(false);
+        }
+
+        self.samples.extend(samples);
+    }
 }
 
+fn boot(cpu: &mut cpu::Cpu, model: model::Model, boot_rom: &Option<Vec<u8>>) {
+    match *boot_rom {
+        Some(ref boot_rom) => load_rom(cpu, boot_rom),
+        None => cpu.skip_boot_rom(model),
+    }
+}
+
+fn load_rom(cpu: &mut cpu::Cpu, rom_data: &[u8]) {
+    for (idx, op) in rom_data.iter().enumerate().take(0x2000) {
+        cpu.mmu.write_byte(idx as u16, *op)
+    }
+}
+
+fn set_mix(apu: &mut apu::Apu, volumes: &[f32; 4], muted: &[bool; 4]) {
+    for &voice in apu::VOICES.iter() {
+        apu.set_voice_volume(voice, volumes[voice as usize]);
+        apu.set_voice_muted(voice, muted[voice as usize]);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A machine running a ROM called `title` that counts up in B and C
+    /// forever.
+    pub fn counting_game_boy(title: &str) -> GameBoy {
+        game_boy_running(title, &[0x04, 0x0C, 0x18, 0xFC])
+    }
+
+    /// A machine running a ROM called `title` with `code` at the entry
+    /// point, 0x100.
+    pub fn game_boy_running(title: &str, code: &[u8]) -> GameBoy {
+        let mut game_boy = GameBoy::new();
+        game_boy.power_on(None, rom_running(title, code));
+        game_boy
+    }
+
+    /// A ROM called `title` with `code` at the entry point.
+    pub fn rom_running(title: &str, code: &[u8]) -> cartridge::Cartridge {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+        rom[0x134..0x134 + title.len()].copy_from_slice(title.as_bytes());
+        cartridge::Cartridge::new(rom)
+    }
+
+    #[test]
+    fn run_frame_returns_an_invalid_opcode() {
+        let mut game_boy = game_boy_running("BAD", &[0x04, 0xD3]);
+
+        let err = game_boy.run_frame().unwrap_err();
+        assert_eq!((err.pc, err.opcode, err.prefixed), (0x101, 0xD3, false));
+        assert_eq!(game_boy.pc(), 0x101);
+
+        // The machine stays where it stopped rather than running on
+        assert!(game_boy.run_frames(2).is_err());
+        assert_eq!(game_boy.pc(), 0x101);
+    }
+
+    #[test]
+    fn run_until_returns_an_invalid_opcode() {
+        let mut game_boy = game_boy_running("BAD", &[0x04, 0xD3]);
+        assert!(game_boy.run_until(1, |game_boy| game_boy.pc() == 0x101).unwrap());
+
+        let err = game_boy.run_until(1, |_| false).unwrap_err();
+        assert_eq!(err.pc, 0x101);
+    }
+
+    #[test]
+    fn run_frame_returns_an_invalid_opcode_in_the_peer() {
+        let mut game_boy = GameBoy::new();
+        game_boy.link(rom_running("PEER", &[0xD3]));
+        game_boy.power_on(None, rom_running("HOST", &[0x04, 0x0C, 0x18, 0xFC]));
+
+        let err = game_boy.run_frame().unwrap_err();
+        assert_eq!((err.pc, err.opcode), (0x100, 0xD3));
+    }
+
+    #[test]
+    fn runs_frames_of_valid_code() {
+        let mut game_boy = counting_game_boy("COUNT");
+        game_boy.run_frames(2).unwrap();
+        assert!(!game_boy.run_until(1, |_| false).unwrap());
+    }
+}
@@ -0,0 +1,457 @@
+//! A GDB remote serial protocol server, so GDB and editors that speak the
+//! protocol can attach over a local TCP port. The registers are the pairs
+//! AF, BC, DE, HL, SP and PC, described to GDB as the matching part of a
+//! Z80. Breakpoints and watchpoints go through `Debugger`, so the ROM is
+//! never patched.
+//!
+//! The server drives the machine itself: nothing else runs it while GDB is
+//! attached, and it sits halted until GDB says to continue or step.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use super::cpu::Registers;
+use super::debugger::{Breakpoint, Debugger, Stop};
+use super::gameboy::GameBoy;
+use super::mmu::Watchpoint;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>z80</architecture>
+  <feature name="org.gnu.gdb.z80.cpu">
+    <reg name="af" bitsize="16" type="int"/>
+    <reg name="bc" bitsize="16" type="data_ptr"/>
+    <reg name="de" bitsize="16" type="data_ptr"/>
+    <reg name="hl" bitsize="16" type="data_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// The signals stop replies report
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// Waits for GDB to connect to `listener`, then debugs `game_boy` until it
+/// detaches, kills the machine or disconnects.
+pub fn serve(game_boy: &mut GameBoy, listener: &TcpListener) -> io::Result<()> {
+    let (stream, _) = listener.accept()?;
+    let mut session = Session::new(stream)?;
+    let result = session.run(game_boy);
+    let _ = session.stream.shutdown(Shutdown::Both);
+    result
+}
+
+struct Session {
+    stream: TcpStream,
+    incoming: Receiver<u8>,
+    // Set by GDB's break-in byte, or the connection closing
+    interrupted: Arc<AtomicBool>,
+    debugger: Debugger,
+}
+
+impl Session {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+
+        // The break-in byte arrives while the machine runs, so a thread
+        // watches for it and hands everything else over
+        let mut reader = stream.try_clone()?;
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let reader_interrupted = interrupted.clone();
+        let (sender, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            let mut byte = [0];
+            while let Ok(1) = reader.read(&mut byte) {
+                if byte[0] == 0x03 {
+                    reader_interrupted.store(true, Ordering::Relaxed);
+                } else if sender.send(byte[0]).is_err() {
+                    return;
+                }
+            }
+            reader_interrupted.store(true, Ordering::Relaxed);
+        });
+
+        Ok(Session {
+            stream,
+            incoming,
+            interrupted,
+            debugger: Debugger::new(),
+        })
+    }
+
+    fn run(&mut self, game_boy: &mut GameBoy) -> io::Result<()> {
+        while let Some(packet) = self.receive()? {
+            match packet.as_str() {
+                "k" => return Ok(()),
+                "D" => return self.send("OK"),
+                _ => {
+                    let reply = self.handle(game_boy, &packet);
+                    self.send(&reply)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // Returns None once the connection closes
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Acknowledgements and anything else between packets is skipped
+            loop {
+                match self.incoming.recv() {
+                    Ok(b'$') => break,
+                    Ok(_) => {},
+                    Err(_) => return Ok(None),
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.incoming.recv() {
+                    Ok(b'#') => break,
+                    Ok(byte) => data.push(byte),
+                    Err(_) => return Ok(None),
+                }
+            }
+            let checksum = match (self.incoming.recv(), self.incoming.recv()) {
+                (Ok(high), Ok(low)) => parse_hex(&[high, low]),
+                _ => return Ok(None),
+            };
+
+            if checksum == Some(checksum_of(&data) as u32) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        let mut data = Vec::new();
+        for &byte in reply.as_bytes() {
+            match byte {
+                b'$' | b'#' | b'}' | b'*' => data.extend(&[b'}', byte ^ 0x20]),
+                _ => data.push(byte),
+            }
+        }
+
+        let mut packet = vec![b'$'];
+        packet.extend(&data);
+        packet.extend(format!("#{:02x}", checksum_of(&data)).as_bytes());
+        self.stream.write_all(&packet)
+    }
+
+    // Empty replies tell GDB a packet isn't supported
+    fn handle(&mut self, game_boy: &mut GameBoy, packet: &str) -> String {
+        if packet.is_empty() || !packet.is_char_boundary(1) {
+            return String::new();
+        }
+        let (command, args) = packet.split_at(1);
+        let reply = match command {
+            "?" => Some(format!("S{:02x}", SIGTRAP)),
+            "g" => Some(read_registers(game_boy)),
+            "G" => write_registers(game_boy, args),
+            "p" => read_register(game_boy, args),
+            "P" => write_register(game_boy, args),
+            "m" => read_memory(game_boy, args),
+            "M" => write_memory(game_boy, args),
+            "c" => self.resume(game_boy, args, false),
+            "s" => self.resume(game_boy, args, true),
+            "Z" => self.set_breakpoint(args, true),
+            "z" => self.set_breakpoint(args, false),
+            "H" => Some("OK".to_string()),
+            "q" => Some(query(args)),
+            _ => Some(String::new()),
+        };
+        reply.unwrap_or_else(|| "E01".to_string())
+    }
+
+    fn resume(&mut self, game_boy: &mut GameBoy, args: &str, step: bool) -> Option<String> {
+        if !args.is_empty() {
+            let mut registers = game_boy.cpu().registers();
+            registers.pc = parse_addr(args)?;
+            game_boy.cpu_mut().set_registers(registers);
+        }
+
+        self.interrupted.store(false, Ordering::Relaxed);
+        let stop = if step {
+            self.debugger.step(game_boy)
+        } else {
+            self.debugger.resume(game_boy, &self.interrupted)
+        };
+
+        Some(match stop {
+            Stop::Watchpoint(access) => {
+                let kind = match self.debugger.watchpoints.iter()
+                    .find(|watchpoint| watchpoint.start <= access.addr && access.addr <= watchpoint.end) {
+                    Some(watchpoint) if watchpoint.read && watchpoint.write => "awatch",
+                    Some(watchpoint) if watchpoint.read => "rwatch",
+                    _ => "watch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, kind, access.addr)
+            },
+            Stop::InvalidOpcode(_) => format!("S{:02x}", SIGILL),
+            Stop::Interrupted => format!("S{:02x}", SIGINT),
+            _ => format!("S{:02x}", SIGTRAP),
+        })
+    }
+
+    // `TYPE,ADDR,KIND`: types 0 and 1 are breakpoints, 2 to 4 are write,
+    // read and access watchpoints with KIND as their length
+    fn set_breakpoint(&mut self, args: &str, insert: bool) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let addr = parse_addr(fields.next()?)?;
+        let length = parse_hex(fields.next()?.as_bytes())?.max(1);
+
+        match kind {
+            "0" | "1" => {
+                let breakpoint = Breakpoint { bank: None, addr };
+                let breakpoints = &mut self.debugger.breakpoints;
+                breakpoints.retain(|&existing| existing != breakpoint);
+                if insert {
+                    breakpoints.push(breakpoint);
+                }
+            },
+            "2" | "3" | "4" => {
+                let watchpoint = Watchpoint {
+                    start: addr,
+                    end: addr.saturating_add((length - 1).min(0xFFFF) as u16),
+                    read: kind != "2",
+                    write: kind != "3",
+                };
+                let watchpoints = &mut self.debugger.watchpoints;
+                watchpoints.retain(|&existing| existing != watchpoint);
+                if insert {
+                    watchpoints.push(watchpoint);
+                }
+            },
+            _ => return Some(String::new()),
+        }
+        Some("OK".to_string())
+    }
+}
+
+fn query(args: &str) -> String {
+    if args.starts_with("Supported") {
+        return "PacketSize=4000;qXfer:features:read+".to_string();
+    }
+    if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+        let mut bounds = range.split(',');
+        let offset = bounds.next().and_then(|offset| parse_hex(offset.as_bytes()));
+        let length = bounds.next().and_then(|length| parse_hex(length.as_bytes()));
+        return match (offset, length) {
+            (Some(offset), Some(length)) => {
+                let start = (offset as usize).min(TARGET_XML.len());
+                let end = (start + length as usize).min(TARGET_XML.len());
+                let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                format!("{}{}", more, &TARGET_XML[start..end])
+            },
+            _ => "E01".to_string(),
+        };
+    }
+    match args {
+        "Attached" => "1".to_string(),
+        "fThreadInfo" => "m1".to_string(),
+        "sThreadInfo" => "l".to_string(),
+        _ => String::new(),
+    }
+}
+
+// AF, BC, DE, HL, SP and PC, each little-endian
+fn register_values(registers: &Registers) -> [u16; 6] {
+    let pair = |high: u8, low: u8| (high as u16) << 8 | low as u16;
+    [pair(registers.a, registers.f), pair(registers.b, registers.c), pair(registers.d, registers.e),
+     pair(registers.h, registers.l), registers.sp, registers.pc]
+}
+
+fn set_register_value(registers: &mut Registers, index: usize, value: u16) -> bool {
+    let (high, low) = ((value >> 8) as u8, value as u8);
+    match index {
+        0 => { registers.a = high; registers.f = low; },
+        1 => { registers.b = high; registers.c = low; },
+        2 => { registers.d = high; registers.e = low; },
+        3 => { registers.h = high; registers.l = low; },
+        4 => registers.sp = value,
+        5 => registers.pc = value,
+        _ => return false,
+    }
+    true
+}
+
+fn read_registers(game_boy: &GameBoy) -> String {
+    register_values(&game_boy.cpu().registers()).iter().map(|&value| encode_u16(value)).collect()
+}
+
+fn write_registers(game_boy: &mut GameBoy, args: &str) -> Option<String> {
+    let bytes = decode_hex(args)?;
+    if bytes.len() < 12 {
+        return None;
+    }
+    let mut registers = game_boy.cpu().registers();
+    for (index, value) in bytes.chunks(2).take(6).enumerate() {
+        set_register_value(&mut registers, index, value[0] as u16 | (value[1] as u16) << 8);
+    }
+    game_boy.cpu_mut().set_registers(registers);
+    Some("OK".to_string())
+}
+
+fn read_register(game_boy: &GameBoy, args: &str) -> Option<String> {
+    let index = parse_hex(args.as_bytes())? as usize;
+    register_values(&game_boy.cpu().registers()).get(index).map(|&value| encode_u16(value))
+}
+
+fn write_register(game_boy: &mut GameBoy, args: &str) -> Option<String> {
+    let mut parts = args.splitn(2, '=');
+    let index = parse_hex(parts.next()?.as_bytes())? as usize;
+    let bytes = decode_hex(parts.next()?)?;
+    if bytes.len() != 2 {
+        return None;
+    }
+
+    let mut registers = game_boy.cpu().registers();
+    if !set_register_value(&mut registers, index, bytes[0] as u16 | (bytes[1] as u16) << 8) {
+        return None;
+    }
+    game_boy.cpu_mut().set_registers(registers);
+    Some("OK".to_string())
+}
+
+// `ADDR,LENGTH`, cut short at the top of the address space
+fn read_memory(game_boy: &GameBoy, args: &str) -> Option<String> {
+    let mut fields = args.split(',');
+    let addr = parse_addr(fields.next()?)?;
+    let length = parse_hex(fields.next()?.as_bytes())?.min(0x10000 - addr as u32);
+    let mmu = &game_boy.cpu().mmu;
+    Some((0..length).map(|offset| format!("{:02x}", mmu.peek(addr + offset as u16))).collect())
+}
+
+// `ADDR,LENGTH:DATA`
+fn write_memory(game_boy: &mut GameBoy, args: &str) -> Option<String> {
+    let mut parts = args.splitn(2, ':');
+    let mut fields = parts.next()?.split(',');
+    let addr = parse_addr(fields.next()?)?;
+    let length = parse_hex(fields.next()?.as_bytes())? as usize;
+    let bytes = decode_hex(parts.next()?)?;
+    if bytes.len() != length || addr as usize + length > 0x10000 {
+        return None;
+    }
+
+    let mmu = &mut game_boy.cpu_mut().mmu;
+    let written = bytes.iter().enumerate().all(|(offset, &byte)| mmu.poke(addr + offset as u16, byte));
+    if written { Some("OK".to_string()) } else { None }
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u32> {
+    ::std::str::from_utf8(digits).ok().and_then(|digits| u32::from_str_radix(digits, 16).ok())
+}
+
+fn parse_addr(text: &str) -> Option<u16> {
+    parse_hex(text.as_bytes()).filter(|&addr| addr <= 0xFFFF).map(|addr| addr as u16)
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    text.as_bytes().chunks(2).map(|pair| parse_hex(pair).map(|byte| byte as u8)).collect()
+}
+
+fn encode_u16(value: u16) -> String {
+    format!("{:02x}{:02x}", value as u8, value >> 8)
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gameboy::tests::counting_game_boy;
+
+    #[test]
+    fn checksums_wrap() {
+        assert_eq!(checksum_of(b""), 0);
+        assert_eq!(checksum_of(b"OK"), 0x9A);
+        assert_eq!(checksum_of(&[0xFF, 0x02]), 0x01);
+    }
+
+    #[test]
+    fn parses_hex() {
+        assert_eq!(parse_hex(b"1f"), Some(0x1F));
+        assert_eq!(parse_hex(b"C000"), Some(0xC000));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"xy"), None);
+        assert_eq!(parse_addr("ffff"), Some(0xFFFF));
+        assert_eq!(parse_addr("10000"), None);
+    }
+
+    #[test]
+    fn decodes_and_encodes_hex() {
+        assert_eq!(decode_hex("00ff7a"), Some(vec![0x00, 0xFF, 0x7A]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(encode_u16(0x1234), "3412");
+    }
+
+    #[test]
+    fn serves_the_target_description() {
+        assert!(query("Supported:multiprocess+").contains("qXfer:features:read+"));
+        let first = query("Xfer:features:read:target.xml:0,10");
+        assert_eq!(first, format!("m{}", &TARGET_XML[..0x10]));
+        let rest = query(&format!("Xfer:features:read:target.xml:10,{:x}", TARGET_XML.len()));
+        assert_eq!(rest, format!("l{}", &TARGET_XML[0x10..]));
+        assert_eq!(query("Xfer:features:read:target.xml:zz"), "E01");
+        assert_eq!(query("Unknown"), "");
+    }
+
+    #[test]
+    fn reads_and_writes_registers() {
+        let mut game_boy = counting_game_boy("GDB");
+        let mut registers = game_boy.registers();
+        registers.a = 0x12;
+        registers.f = 0x80;
+        registers.pc = 0x0150;
+        game_boy.set_registers(registers);
+
+        let all = read_registers(&game_boy);
+        assert_eq!(all.len(), 24);
+        assert_eq!(&all[..4], "8012");
+        assert_eq!(read_register(&game_boy, "5"), Some("5001".to_string()));
+        assert_eq!(read_register(&game_boy, "6"), None);
+
+        assert_eq!(write_register(&mut game_boy, "1=3412"), Some("OK".to_string()));
+        assert_eq!((game_boy.registers().b, game_boy.registers().c), (0x12, 0x34));
+        assert_eq!(write_register(&mut game_boy, "6=0000"), None);
+        assert_eq!(write_register(&mut game_boy, "1=34"), None);
+
+        let mut values = all.clone();
+        values.replace_range(20..24, "0002");
+        assert_eq!(write_registers(&mut game_boy, &values), Some("OK".to_string()));
+        assert_eq!(game_boy.pc(), 0x0200);
+        assert_eq!(write_registers(&mut game_boy, "0000"), None);
+    }
+
+    #[test]
+    fn reads_and_writes_memory() {
+        let mut game_boy = counting_game_boy("GDB");
+        assert_eq!(read_memory(&game_boy, "100,4"), Some("040c18fc".to_string()));
+        assert_eq!(read_memory(&game_boy, "ffff,4").map(|bytes| bytes.len()), Some(2));
+
+        assert_eq!(write_memory(&mut game_boy, "8000,2:abcd"), Some("OK".to_string()));
+        assert_eq!((game_boy.peek(0x8000), game_boy.peek(0x8001)), (0xAB, 0xCD));
+        assert_eq!(write_memory(&mut game_boy, "8000,2:ab"), None);
+        assert_eq!(write_memory(&mut game_boy, "c000,1:ab"), None);
+        assert_eq!(write_memory(&mut game_boy, "ffff,2:abcd"), None);
+    }
+}
@@ -1,14 +1,26 @@
+use super::mmu;
+use super::savestate::{Savable, StateError, StateReader, StateWriter};
+
 const TILE_MAP_SIZE: usize = 0x180;
 const BG_MAP_SIZE: usize = 0x400;
-const SCREEN_HEIGHT: usize = 144;
-const SCREEN_WIDTH: usize = 160;
-const SCREEN_PIXELS: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+pub const SCREEN_HEIGHT: usize = 144;
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_PIXELS: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
 const TILE_WIDTH:  usize = 8;
 const TILE_HEIGHT: usize = 8;
-const TILE_PIXELS: usize = TILE_WIDTH * TILE_HEIGHT;
-const SCREEN_BUFFER_HEIGHT: usize = 32;
-const SCREEN_BUFFER_WIDTH: usize = 32;
-const SCREEN_BUFFER_TILES: usize = SCREEN_BUFFER_WIDTH * SCREEN_BUFFER_HEIGHT;
+const BG_MAP_WIDTH: usize = 32;
+
+// Dots spent in each mode of a visible line; a whole line is 456 dots
+const OAM_CYCLES: u32 = 80;
+const VRAM_CYCLES: u32 = 172;
+const HBLANK_CYCLES: u32 = 204;
+const LINE_CYCLES: u32 = 456;
+const LINES_PER_FRAME: u8 = 154;
+
+const STAT_HBLANK_INTERRUPT: u8 = 1 << 3;
+const STAT_VBLANK_INTERRUPT: u8 = 1 << 4;
+const STAT_OAM_INTERRUPT: u8 = 1 << 5;
+const STAT_COINCIDENCE_INTERRUPT: u8 = 1 << 6;
 
 #[derive(Clone, Copy)]
 struct Tile {
@@ -25,10 +37,19 @@ pub struct Gpu {
 
     scroll_x: u8,
     scroll_y: u8,
+    window_x: u8,
+    window_y: u8,
+    window_line: u8,
 
     mode: Mode,
+    mode_cycles: u32,
+    stat_enable: u8,
 
-    screen_buffer: [Tile; SCREEN_BUFFER_TILES],
+    // Line being drawn and the last complete frame, as shades 0 (white) to
+    // 3 (black)
+    screen_buffer: [u8; SCREEN_PIXELS],
+    frame: [u8; SCREEN_PIXELS],
+    frame_count: u64,
 }
 
 impl Gpu {
@@ -43,18 +64,180 @@ impl Gpu {
 
             scroll_x: 0,
             scroll_y: 0,
+            window_x: 0,
+            window_y: 0,
+            window_line: 0,
 
             mode: Mode::AccessOam,
+            mode_cycles: 0,
+            stat_enable: 0,
 
-            screen_buffer: [Tile { data: [0; 16] }; SCREEN_BUFFER_TILES]
+            screen_buffer: [0; SCREEN_PIXELS],
+            frame: [0; SCREEN_PIXELS],
+            frame_count: 0,
         }
     }
 
+    /// The last complete frame, one shade per pixel from 0 (white) to
+    /// 3 (black), row by row.
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    /// Counts the frames completed, including the blank one shown when the
+    /// LCD is switched off.
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Advances the LCD by `cycles` dots and returns the interrupts raised.
+    pub fn step(&mut self, cycles: u32) -> mmu::Interrupts {
+        let mut interrupts = mmu::Interrupts::empty();
+        if !self.lcd.controls.contains(Controls::LCD_ENABLE) {
+            return interrupts;
+        }
+
+        self.mode_cycles += cycles;
+        loop {
+            let mode_length = match self.mode {
+                Mode::AccessOam => OAM_CYCLES,
+                Mode::AccessVram => VRAM_CYCLES,
+                Mode::HBlank => HBLANK_CYCLES,
+                Mode::VBlank => LINE_CYCLES,
+            };
+            if self.mode_cycles < mode_length {
+                break;
+            }
+            self.mode_cycles -= mode_length;
+
+            match self.mode {
+                Mode::AccessOam => self.mode = Mode::AccessVram,
+                Mode::AccessVram => {
+                    self.render_line();
+                    self.enter_mode(Mode::HBlank, &mut interrupts);
+                },
+                Mode::HBlank => {
+                    self.next_line(&mut interrupts);
+                    if self.lcd.current_line as usize == SCREEN_HEIGHT {
+                        self.frame = self.screen_buffer;
+                        self.frame_count += 1;
+                        interrupts.insert(mmu::Interrupts::VBLANK);
+                        self.enter_mode(Mode::VBlank, &mut interrupts);
+                    } else {
+                        self.enter_mode(Mode::AccessOam, &mut interrupts);
+                    }
+                },
+                Mode::VBlank => {
+                    if self.lcd.current_line == LINES_PER_FRAME - 1 {
+                        self.lcd.current_line = 0;
+                        self.window_line = 0;
+                        self.check_coincidence(&mut interrupts);
+                        self.enter_mode(Mode::AccessOam, &mut interrupts);
+                    } else {
+                        self.next_line(&mut interrupts);
+                    }
+                },
+            }
+        }
+
+        interrupts
+    }
+
+    fn enter_mode(&mut self, mode: Mode, interrupts: &mut mmu::Interrupts) {
+        let enable = match mode {
+            Mode::HBlank => STAT_HBLANK_INTERRUPT,
+            Mode::VBlank => STAT_VBLANK_INTERRUPT,
+            Mode::AccessOam => STAT_OAM_INTERRUPT,
+            Mode::AccessVram => 0,
+        };
+        if self.stat_enable & enable != 0 {
+            interrupts.insert(mmu::Interrupts::LCD_STAT);
+        }
+        self.mode = mode;
+    }
+
+    fn next_line(&mut self, interrupts: &mut mmu::Interrupts) {
+        self.lcd.current_line += 1;
+        self.check_coincidence(interrupts);
+    }
+
+    fn check_coincidence(&mut self, interrupts: &mut mmu::Interrupts) {
+        if self.lcd.current_line == self.lcd.compare_line &&
+            self.stat_enable & STAT_COINCIDENCE_INTERRUPT != 0 {
+            interrupts.insert(mmu::Interrupts::LCD_STAT);
+        }
+    }
+
+    fn render_line(&mut self) {
+        let line = self.lcd.current_line as usize;
+        let controls = self.lcd.controls;
+        let window_visible = controls.contains(Controls::WINDOW_ENABLE) &&
+            self.lcd.current_line >= self.window_y && self.window_x <= 166;
+
+        for x in 0..SCREEN_WIDTH {
+            let in_window = window_visible && x + 7 >= self.window_x as usize;
+
+            let color = if in_window {
+                let map_select = controls.contains(Controls::WINDOW_MAP_SELECT);
+                let window_x = x + 7 - self.window_x as usize;
+                self.background_color(map_select, window_x, self.window_line as usize)
+            } else if controls.contains(Controls::BG_ENABLE) {
+                let map_select = controls.contains(Controls::BG_MAP_SELECT);
+                let bg_x = (x + self.scroll_x as usize) & 0xFF;
+                let bg_y = (line + self.scroll_y as usize) & 0xFF;
+                self.background_color(map_select, bg_x, bg_y)
+            } else {
+                0
+            };
+
+            self.screen_buffer[line * SCREEN_WIDTH + x] = self.bg_palette.shade(color);
+        }
+
+        if window_visible {
+            self.window_line += 1;
+        }
+    }
+
+    // Color number 0-3 at a pixel of the 256x256 background or window map
+    fn background_color(&self, map_select: bool, x: usize, y: usize) -> u8 {
+        let map = if map_select { &self.bg_map_1 } else { &self.bg_map_0 };
+        let tile_number = map[(y / TILE_HEIGHT) * BG_MAP_WIDTH + x / TILE_WIDTH];
+
+        // 0x8000 addressing is unsigned; 0x8800 addressing is signed from 0x9000
+        let tile_index = if self.lcd.controls.contains(Controls::BG_WINDOW_SELECT) {
+            tile_number as usize
+        } else {
+            (0x100 + tile_number as i8 as isize) as usize
+        };
+
+        let tile = &self.tile_map[tile_index];
+        let row = (y % TILE_HEIGHT) * 2;
+        let bit = 7 - x % TILE_WIDTH;
+        (tile.data[row + 1] >> bit & 1) << 1 | tile.data[row] >> bit & 1
+    }
+
     pub fn read_byte(&self, addr: u16) -> u8 {
         match addr >> 8 {
+            0x80..=0x97 => {
+                let rel_addr = addr - 0x8000;
+                self.tile_map[rel_addr as usize / 16].data[rel_addr as usize % 16]
+            },
+            0x98..=0x9B => self.bg_map_0[(addr - 0x9800) as usize],
+            0x9C..=0x9F => self.bg_map_1[(addr - 0x9C00) as usize],
             0xFF => {
                 match addr & 0xFF {
+                    0x40 => self.lcd.controls.bits(),
+                    0x41 => {
+                        let coincidence = if self.lcd.current_line == self.lcd.compare_line { 1 << 2 } else { 0 };
+                        0x80 | self.stat_enable | coincidence | self.mode.to_u8()
+                    },
+                    0x42 => self.scroll_y,
+                    0x43 => self.scroll_x,
                     0x44 => self.lcd.current_line,
+                    0x45 => self.lcd.compare_line,
+                    0x47 => self.bg_palette.to_u8(),
+                    0x4A => self.window_y,
+                    0x4B => self.window_x,
                     _ => panic!("Unimplemented GPU Register read at {:04X}", addr),
                 }
             },
@@ -64,40 +247,45 @@ impl Gpu {
 
     pub fn write_byte(&mut self, addr: u16, value: u8) {
         match addr >> 8 {
-            0x80...0x97 => {
+            0x80..=0x97 => {
                 let rel_addr = addr - 0x8000;
                 let tile = &mut self.tile_map[(rel_addr) as usize / 16];
                 tile.data[rel_addr as usize % 16] = value;
             },
-            0x98...0x9B => self.bg_map_0[(addr - 0x9800) as usize] = value,
-            0x9C...0x9F => self.bg_map_0[(addr - 0x9C00) as usize] = value,
+            0x98..=0x9B => self.bg_map_0[(addr - 0x9800) as usize] = value,
+            0x9C..=0x9F => self.bg_map_1[(addr - 0x9C00) as usize] = value,
             0xFF => {
                 match addr & 0xFF {
                     0x40 => { // LCD Control
                         let new_controls = Controls::from_bits_truncate(value);
 
-                        if !new_controls.contains(LCD_ENABLE) &&
-                            self.lcd.controls.contains(LCD_ENABLE) {
+                        if !new_controls.contains(Controls::LCD_ENABLE) &&
+                            self.lcd.controls.contains(Controls::LCD_ENABLE) {
                             if self.mode != Mode::VBlank {
                                 panic!("Cannot turn off LCD outside of VBLANK")
                             } else {
                                 self.lcd.current_line = 0;
+                                self.window_line = 0;
+                                self.frame = [0; SCREEN_PIXELS];
+                                self.frame_count += 1;
                             }
                         }
 
-                        if new_controls.contains(LCD_ENABLE) &&
-                            !self.lcd.controls.contains(LCD_ENABLE) {
+                        if new_controls.contains(Controls::LCD_ENABLE) &&
+                            !self.lcd.controls.contains(Controls::LCD_ENABLE) {
+                            self.mode = Mode::AccessOam;
+                            self.mode_cycles = 0;
                         }
 
                         self.lcd.controls = new_controls;
                     },
+                    0x41 => self.stat_enable = value & 0x78,
                     0x42 => self.scroll_y = value,
-                    0x47 => {
-                        self.bg_palette.color_0 = Color::from_u8((value >> 0) & 0x3);
-                        self.bg_palette.color_1 = Color::from_u8((value >> 2) & 0x3);
-                        self.bg_palette.color_2 = Color::from_u8((value >> 4) & 0x3);
-                        self.bg_palette.color_3 = Color::from_u8((value >> 6) & 0x3);
-                    },
+                    0x43 => self.scroll_x = value,
+                    0x45 => self.lcd.compare_line = value,
+                    0x47 => self.bg_palette.set(value),
+                    0x4A => self.window_y = value,
+                    0x4B => self.window_x = value,
                     _ => panic!("Unimpletmented GPU Register write at {:#04X}: {:#04X}", addr, value)
                 }
             }
@@ -123,6 +311,26 @@ impl Palette {
             color_3: Color::White,
         }
     }
+
+    fn shade(&self, color: u8) -> u8 {
+        match color {
+            0 => self.color_0 as u8,
+            1 => self.color_1 as u8,
+            2 => self.color_2 as u8,
+            _ => self.color_3 as u8,
+        }
+    }
+
+    fn set(&mut self, value: u8) {
+        self.color_0 = Color::from_u8(value & 0x3);
+        self.color_1 = Color::from_u8((value >> 2) & 0x3);
+        self.color_2 = Color::from_u8((value >> 4) & 0x3);
+        self.color_3 = Color::from_u8((value >> 6) & 0x3);
+    }
+
+    fn to_u8(&self) -> u8 {
+        (self.color_3 as u8) << 6 | (self.color_2 as u8) << 4 | (self.color_1 as u8) << 2 | self.color_0 as u8
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -148,6 +356,7 @@ impl Color {
 struct Lcd {
      controls: Controls,
      current_line: u8,
+     compare_line: u8,
 }
 
 impl Lcd {
@@ -155,20 +364,21 @@ impl Lcd {
         Lcd {
             controls: Controls::empty(),
             current_line: 0,
+            compare_line: 0,
         }
     }
 }
 
 bitflags! {
-    flags Controls: u8 {
-        const LCD_ENABLE        = 1 << 7,
-        const WINDOW_MAP_SELECT = 1 << 6,
-        const WINDOW_ENABLE     = 1 << 5,
-        const BG_WINDOW_SELECT  = 1 << 4,
-        const BG_MAP_SELECT     = 1 << 3,
-        const SPRITE_SIZE       = 1 << 2,
-        const SPRINT_ENABLE     = 1 << 1,
-        const BG_ENABLE         = 1 << 0,
+    struct Controls: u8 {
+        const LCD_ENABLE        = 1 << 7;
+        const WINDOW_MAP_SELECT = 1 << 6;
+        const WINDOW_ENABLE     = 1 << 5;
+        const BG_WINDOW_SELECT  = 1 << 4;
+        const BG_MAP_SELECT     = 1 << 3;
+        const SPRITE_SIZE       = 1 << 2;
+        const SPRINT_ENABLE     = 1 << 1;
+        const BG_ENABLE         = 1 << 0;
     }
 }
 
@@ -179,3 +389,241 @@ enum Mode {
     HBlank,
     VBlank,
 }
+
+impl Mode {
+    fn to_u8(&self) -> u8 {
+        match *self {
+            Mode::HBlank => 0,
+            Mode::VBlank => 1,
+            Mode::AccessOam => 2,
+            Mode::AccessVram => 3,
+        }
+    }
+
+    fn from_u8(value: u8) -> Mode {
+        match value {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::AccessOam,
+            3 => Mode::AccessVram,
+            _ => panic!("Unrecognized mode: {:#X}", value)
+        }
+    }
+}
+
+impl Savable for Gpu {
+    fn save(&self, state: &mut StateWriter) {
+        for tile in self.tile_map.iter() {
+            state.write_bytes(&tile.data);
+        }
+        state.write_bytes(&self.bg_map_0);
+        state.write_bytes(&self.bg_map_1);
+        state.write_u8(self.bg_palette.to_u8());
+
+        state.write_u8(self.lcd.controls.bits());
+        state.write_u8(self.lcd.current_line);
+        state.write_u8(self.lcd.compare_line);
+
+        state.write_u8(self.scroll_x);
+        state.write_u8(self.scroll_y);
+        state.write_u8(self.window_x);
+        state.write_u8(self.window_y);
+        state.write_u8(self.window_line);
+
+        state.write_u8(self.mode.to_u8());
+        state.write_u32(self.mode_cycles);
+        state.write_u8(self.stat_enable);
+
+        state.write_bytes(&self.screen_buffer);
+        state.write_bytes(&self.frame);
+        state.write_u64(self.frame_count);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for tile in self.tile_map.iter_mut() {
+            state.read_bytes(&mut tile.data)?;
+        }
+        state.read_bytes(&mut self.bg_map_0)?;
+        state.read_bytes(&mut self.bg_map_1)?;
+        self.bg_palette.set(state.read_u8()?);
+
+        self.lcd.controls = Controls::from_bits_truncate(state.read_u8()?);
+        self.lcd.current_line = state.read_u8()?;
+        self.lcd.compare_line = state.read_u8()?;
+        if self.lcd.current_line >= LINES_PER_FRAME {
+            return Err(StateError::Corrupt("LCD line"));
+        }
+
+        self.scroll_x = state.read_u8()?;
+        self.scroll_y = state.read_u8()?;
+        self.window_x = state.read_u8()?;
+        self.window_y = state.read_u8()?;
+        self.window_line = state.read_u8()?;
+
+        self.mode = Mode::from_u8(state.read_variant(4, "LCD mode")?);
+        self.mode_cycles = state.read_u32()?;
+        self.stat_enable = state.read_u8()? & 0x78;
+        // Lines past the screen are only ever in VBlank, and rendering one
+        // would run off the end of the buffer
+        let in_vblank = self.lcd.current_line as usize >= SCREEN_HEIGHT;
+        if in_vblank != (self.mode == Mode::VBlank) {
+            return Err(StateError::Corrupt("LCD mode"));
+        }
+        if self.mode_cycles >= LINE_CYCLES {
+            return Err(StateError::Corrupt("LCD mode cycles"));
+        }
+
+        state.read_bytes(&mut self.screen_buffer)?;
+        state.read_bytes(&mut self.frame)?;
+        self.frame_count = state.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Offset of LY in a saved GPU, after the tiles, both maps, BGP and LCDC
+    const LINE_OFFSET: usize = TILE_MAP_SIZE * 16 + BG_MAP_SIZE * 2 + 2;
+    const MODE_OFFSET: usize = LINE_OFFSET + 7;
+
+    fn load_patched(line: u8, mode: Mode) -> Result<(), StateError> {
+        let mut state = StateWriter::new();
+        Gpu::new().save(&mut state);
+        let mut data = state.into_bytes();
+        data[LINE_OFFSET] = line;
+        data[MODE_OFFSET] = mode.to_u8();
+        Gpu::new().load(&mut StateReader::new(&data))
+    }
+
+    // Fills the tile at `addr` with `color`
+    fn fill_tile(gpu: &mut Gpu, addr: u16, color: u8) {
+        for row in 0..8 {
+            gpu.write_byte(addr + row * 2, if color & 1 != 0 { 0xFF } else { 0 });
+            gpu.write_byte(addr + row * 2 + 1, if color & 2 != 0 { 0xFF } else { 0 });
+        }
+    }
+
+    // Draws a whole frame with `controls` and the identity palette
+    fn render(gpu: &mut Gpu, controls: u8) -> Vec<u8> {
+        if gpu.read_byte(0xFF47) == 0 {
+            gpu.write_byte(0xFF47, 0xE4);
+        }
+        gpu.write_byte(0xFF40, controls);
+        gpu.step(LINE_CYCLES * SCREEN_HEIGHT as u32);
+        gpu.frame().to_vec()
+    }
+
+    fn pixel(frame: &[u8], x: usize, y: usize) -> u8 {
+        frame[y * SCREEN_WIDTH + x]
+    }
+
+    #[test]
+    fn background_scrolls_and_wraps() {
+        let mut gpu = Gpu::new();
+        fill_tile(&mut gpu, 0x8010, 3);
+        gpu.write_byte(0x9800, 1);
+
+        let frame = render(&mut gpu, 0x91);
+        assert_eq!(pixel(&frame, 7, 7), 3);
+        assert_eq!(pixel(&frame, 8, 0), 0);
+        assert_eq!(pixel(&frame, 0, 8), 0);
+
+        let mut gpu = Gpu::new();
+        fill_tile(&mut gpu, 0x8010, 3);
+        gpu.write_byte(0x9800, 1);
+        gpu.write_byte(0xFF43, 252);
+        gpu.write_byte(0xFF42, 2);
+        let frame = render(&mut gpu, 0x91);
+        assert_eq!(pixel(&frame, 3, 0), 0);
+        assert_eq!(pixel(&frame, 4, 0), 3);
+        assert_eq!(pixel(&frame, 11, 5), 3);
+        assert_eq!(pixel(&frame, 12, 5), 0);
+        assert_eq!(pixel(&frame, 4, 6), 0);
+    }
+
+    #[test]
+    fn tile_data_select_picks_signed_or_unsigned_tiles() {
+        let mut gpu = Gpu::new();
+        fill_tile(&mut gpu, 0x8000, 1);
+        fill_tile(&mut gpu, 0x9000, 2);
+        fill_tile(&mut gpu, 0x8800, 3);
+        gpu.write_byte(0x9801, 0x80);
+
+        // Tile 0 is at 0x8000 unsigned but 0x9000 signed; 0x80 is at 0x8800
+        // either way
+        let frame = render(&mut gpu, 0x91);
+        assert_eq!(pixel(&frame, 0, 0), 1);
+        assert_eq!(pixel(&frame, 8, 0), 3);
+
+        let mut gpu = Gpu::new();
+        fill_tile(&mut gpu, 0x8000, 1);
+        fill_tile(&mut gpu, 0x9000, 2);
+        fill_tile(&mut gpu, 0x8800, 3);
+        gpu.write_byte(0x9801, 0x80);
+        let frame = render(&mut gpu, 0x81);
+        assert_eq!(pixel(&frame, 0, 0), 2);
+        assert_eq!(pixel(&frame, 8, 0), 3);
+    }
+
+    #[test]
+    fn window_covers_the_background_from_its_own_first_line() {
+        let mut gpu = Gpu::new();
+        fill_tile(&mut gpu, 0x8010, 3);
+        fill_tile(&mut gpu, 0x8020, 2);
+        for column in 0..32 {
+            gpu.write_byte(0x9C00 + column, 1);
+            gpu.write_byte(0x9800 + column, 2);
+        }
+        gpu.write_byte(0xFF4A, 16);
+        gpu.write_byte(0xFF4B, 7 + 40);
+
+        // Window from map 1, background from map 0
+        let frame = render(&mut gpu, 0xF1);
+        assert_eq!(pixel(&frame, 40, 15), 0);
+        assert_eq!(pixel(&frame, 39, 16), 0);
+        assert_eq!(pixel(&frame, 40, 16), 3);
+        assert_eq!(pixel(&frame, 159, 23), 3);
+        assert_eq!(pixel(&frame, 40, 24), 0);
+        assert_eq!(pixel(&frame, 0, 0), 2);
+
+        // With the window off the background shows through
+        let mut gpu = Gpu::new();
+        fill_tile(&mut gpu, 0x8010, 3);
+        gpu.write_byte(0x9C00, 1);
+        gpu.write_byte(0xFF4B, 7);
+        let frame = render(&mut gpu, 0xD1);
+        assert_eq!(pixel(&frame, 0, 0), 0);
+    }
+
+    #[test]
+    fn palette_shades_and_disabled_background() {
+        let mut gpu = Gpu::new();
+        fill_tile(&mut gpu, 0x8000, 1);
+        gpu.write_byte(0xFF47, 0x1B);
+        let frame = render(&mut gpu, 0x91);
+        assert_eq!(pixel(&frame, 0, 0), 2);
+
+        let mut gpu = Gpu::new();
+        fill_tile(&mut gpu, 0x8000, 1);
+        gpu.write_byte(0xFF47, 0x1B);
+        let frame = render(&mut gpu, 0x90);
+        assert_eq!(pixel(&frame, 0, 0), 3);
+    }
+
+    #[test]
+    fn load_accepts_consistent_modes() {
+        assert!(load_patched(0, Mode::AccessOam).is_ok());
+        assert!(load_patched(143, Mode::HBlank).is_ok());
+        assert!(load_patched(150, Mode::VBlank).is_ok());
+    }
+
+    #[test]
+    fn load_rejects_drawing_past_the_screen() {
+        assert!(load_patched(144, Mode::AccessVram).is_err());
+        assert!(load_patched(153, Mode::HBlank).is_err());
+        assert!(load_patched(10, Mode::VBlank).is_err());
+        assert!(load_patched(154, Mode::VBlank).is_err());
+    }
+}
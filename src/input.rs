@@ -0,0 +1,485 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::Hash;
+use std::io::Read;
+use std::path::Path;
+
+use glium::winit::keyboard::KeyCode;
+use sdl2;
+use sdl2::controller::{Axis, Button as ControllerButton, GameController};
+use sdl2::event::Event;
+
+use rustboy::Button;
+
+// Stick deflection past which it counts as a d-pad press
+const STICK_THRESHOLD: i16 = 16384;
+
+/// Bindings used for anything a config file doesn't override. Config files
+/// use the same format.
+const DEFAULT_BINDINGS: &str = "
+[keyboard]
+up = Up
+down = Down
+left = Left
+right = Right
+a = X
+b = Z
+select = Backspace
+start = Enter
+pause = P
+frame_advance = Space
+slow_motion = F6
+reset = F2
+fast_forward = Tab
+rewind = R
+save_state = F5
+load_state = F7
+previous_slot = F4
+next_slot = F8
+record_movie = F9
+screenshot = F12
+fullscreen = F11
+switch_player = F3
+quit = Escape
+
+[controller]
+up = dpup
+down = dpdown
+left = dpleft
+right = dpright
+a = b
+b = a
+select = back
+start = start
+fast_forward = rightshoulder
+rewind = leftshoulder
+";
+
+/// Emulator functions bound to keys alongside the joypad.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Hotkey {
+    Pause,
+    FrameAdvance,
+    Reset,
+    FastForward,
+    Rewind,
+    SlowMotion,
+    SaveState,
+    LoadState,
+    PreviousSlot,
+    NextSlot,
+    RecordMovie,
+    Screenshot,
+    Fullscreen,
+    SwitchPlayer,
+    Quit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Button(Button),
+    Hotkey(Hotkey),
+}
+
+impl Action {
+    fn from_name(name: &str) -> Option<Action> {
+        let action = match name {
+            "up" => Action::Button(Button::Up),
+            "down" => Action::Button(Button::Down),
+            "left" => Action::Button(Button::Left),
+            "right" => Action::Button(Button::Right),
+            "a" => Action::Button(Button::A),
+            "b" => Action::Button(Button::B),
+            "select" => Action::Button(Button::Select),
+            "start" => Action::Button(Button::Start),
+            "pause" => Action::Hotkey(Hotkey::Pause),
+            "frame_advance" => Action::Hotkey(Hotkey::FrameAdvance),
+            "slow_motion" => Action::Hotkey(Hotkey::SlowMotion),
+            "reset" => Action::Hotkey(Hotkey::Reset),
+            "fast_forward" => Action::Hotkey(Hotkey::FastForward),
+            "rewind" => Action::Hotkey(Hotkey::Rewind),
+            "save_state" => Action::Hotkey(Hotkey::SaveState),
+            "load_state" => Action::Hotkey(Hotkey::LoadState),
+            "previous_slot" => Action::Hotkey(Hotkey::PreviousSlot),
+            "next_slot" => Action::Hotkey(Hotkey::NextSlot),
+            "record_movie" => Action::Hotkey(Hotkey::RecordMovie),
+            "screenshot" => Action::Hotkey(Hotkey::Screenshot),
+            "fullscreen" => Action::Hotkey(Hotkey::Fullscreen),
+            "switch_player" => Action::Hotkey(Hotkey::SwitchPlayer),
+            "quit" => Action::Hotkey(Hotkey::Quit),
+            _ => return None,
+        };
+        Some(action)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct InputEvent {
+    pub action: Action,
+    pub pressed: bool,
+}
+
+/// Maps keyboard keys and controller buttons to actions.
+///
+/// A config file holds `action = key` lines under `[keyboard]` and
+/// `[controller]` sections; `#` starts a comment. Controller buttons use
+/// SDL's names (`a`, `dpup`, `leftshoulder`, ...). Listing an action
+/// replaces all of its default bindings in that section, and an action may
+/// be listed several times to bind several keys, but a key can't be bound
+/// to two actions in one file.
+pub struct Bindings {
+    keys: HashMap<KeyCode, Action>,
+    controller_buttons: HashMap<ControllerButton, Action>,
+}
+
+impl Bindings {
+    pub fn new() -> Self {
+        let mut bindings = Bindings {
+            keys: HashMap::new(),
+            controller_buttons: HashMap::new(),
+        };
+        bindings.parse(DEFAULT_BINDINGS).unwrap();
+        bindings
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let mut config = String::new();
+        fs::File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut config))
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+
+        let mut bindings = Bindings::new();
+        bindings.parse(&config).map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(bindings)
+    }
+
+    fn parse(&mut self, config: &str) -> Result<(), String> {
+        let mut section = None;
+        let mut replaced_keys = HashSet::new();
+        let mut replaced_buttons = HashSet::new();
+        // The action each key was given in this config, by name
+        let mut bound_keys = HashMap::new();
+        let mut bound_buttons = HashMap::new();
+
+        for (number, line) in config.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                section = Some(line[1..line.len() - 1].trim().to_string());
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let name = parts.next().unwrap().trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => return Err(format!("line {}: expected `action = key`", number + 1)),
+            };
+            let action = match Action::from_name(name) {
+                Some(action) => action,
+                None => return Err(format!("line {}: unknown action `{}`", number + 1, name)),
+            };
+
+            match section.as_deref() {
+                Some("keyboard") => {
+                    let key = match key_from_name(value) {
+                        Some(key) => key,
+                        None => return Err(format!("line {}: unknown key `{}`", number + 1, value)),
+                    };
+                    if let Some(other) = bound_keys.insert(key, name).filter(|&other| other != name) {
+                        return Err(format!("line {}: `{}` is already bound to `{}`", number + 1, value, other));
+                    }
+                    bind(&mut self.keys, &mut replaced_keys, key, action);
+                },
+                Some("controller") => {
+                    let button = match ControllerButton::from_string(value) {
+                        Some(button) => button,
+                        None => return Err(format!("line {}: unknown controller button `{}`", number + 1, value)),
+                    };
+                    if let Some(other) = bound_buttons.insert(button, name).filter(|&other| other != name) {
+                        return Err(format!("line {}: `{}` is already bound to `{}`", number + 1, value, other));
+                    }
+                    bind(&mut self.controller_buttons, &mut replaced_buttons, button, action);
+                },
+                _ => return Err(format!("line {}: binding outside [keyboard] or [controller]", number + 1)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn bind<K: Eq + Hash>(map: &mut HashMap<K, Action>,
+                      replaced: &mut HashSet<Action>,
+                      key: K,
+                      action: Action) {
+    if replaced.insert(action) {
+        map.retain(|_, bound| *bound != action);
+    }
+    map.insert(key, action);
+}
+
+/// Turns keyboard events from the window and game controller events from
+/// SDL into `InputEvent`s. Controllers may be plugged in or out at any time.
+pub struct Input {
+    bindings: Bindings,
+    controller_subsystem: Option<sdl2::GameControllerSubsystem>,
+    event_pump: Option<sdl2::EventPump>,
+    controllers: Vec<GameController>,
+    stick_x: i8,
+    stick_y: i8,
+}
+
+impl Input {
+    pub fn new(bindings: Bindings, sdl: Option<&sdl2::Sdl>) -> Self {
+        let mut input = Input {
+            bindings,
+            controller_subsystem: None,
+            event_pump: None,
+            controllers: Vec::new(),
+            stick_x: 0,
+            stick_y: 0,
+        };
+
+        if let Some(sdl) = sdl {
+            match sdl.game_controller().and_then(|subsystem| {
+                sdl.event_pump().map(|event_pump| (subsystem, event_pump))
+            }) {
+                Ok((subsystem, event_pump)) => {
+                    input.controller_subsystem = Some(subsystem);
+                    input.event_pump = Some(event_pump);
+                },
+                Err(err) => println!("Game controllers disabled: {}", err),
+            }
+        }
+
+        input
+    }
+
+    pub fn key_event(&self, key: KeyCode, pressed: bool) -> Option<InputEvent> {
+        self.bindings.keys.get(&key).map(|action| {
+            InputEvent { action: *action, pressed }
+        })
+    }
+
+    /// Drains SDL's event queue, opening and closing controllers as they
+    /// come and go.
+    pub fn poll_controllers(&mut self) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+
+        let sdl_events: Vec<Event> = match self.event_pump {
+            Some(ref mut event_pump) => event_pump.poll_iter().collect(),
+            None => return events,
+        };
+
+        for event in sdl_events {
+            match event {
+                Event::ControllerDeviceAdded { which, .. } => self.open_controller(which as u32),
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.controllers.retain(|controller| controller.instance_id() != which);
+                },
+                Event::ControllerButtonDown { button, .. } => {
+                    if let Some(action) = self.bindings.controller_buttons.get(&button) {
+                        events.push(InputEvent { action: *action, pressed: true });
+                    }
+                },
+                Event::ControllerButtonUp { button, .. } => {
+                    if let Some(action) = self.bindings.controller_buttons.get(&button) {
+                        events.push(InputEvent { action: *action, pressed: false });
+                    }
+                },
+                Event::ControllerAxisMotion { axis: Axis::LeftX, value, .. } => {
+                    let direction = stick_direction(value);
+                    stick_events(&mut events, self.stick_x, direction, Button::Left, Button::Right);
+                    self.stick_x = direction;
+                },
+                Event::ControllerAxisMotion { axis: Axis::LeftY, value, .. } => {
+                    let direction = stick_direction(value);
+                    stick_events(&mut events, self.stick_y, direction, Button::Up, Button::Down);
+                    self.stick_y = direction;
+                },
+                _ => {}
+            }
+        }
+
+        events
+    }
+
+    fn open_controller(&mut self, index: u32) {
+        let subsystem = match self.controller_subsystem {
+            Some(ref subsystem) => subsystem,
+            None => return,
+        };
+
+        match subsystem.open(index) {
+            Ok(controller) => {
+                println!("Controller connected: {}", controller.name());
+                self.controllers.push(controller);
+            },
+            Err(err) => println!("Failed to open controller {}: {:?}", index, err),
+        }
+    }
+}
+
+fn stick_direction(value: i16) -> i8 {
+    if value <= -STICK_THRESHOLD {
+        -1
+    } else if value >= STICK_THRESHOLD {
+        1
+    } else {
+        0
+    }
+}
+
+fn stick_events(events: &mut Vec<InputEvent>,
+                old: i8,
+                new: i8,
+                negative: Button,
+                positive: Button) {
+    if old == new {
+        return;
+    }
+
+    let button = |direction| if direction < 0 { negative } else { positive };
+    if old != 0 {
+        events.push(InputEvent { action: Action::Button(button(old)), pressed: false });
+    }
+    if new != 0 {
+        events.push(InputEvent { action: Action::Button(button(new)), pressed: true });
+    }
+}
+
+fn key_from_name(name: &str) -> Option<KeyCode> {
+    let key = match name.to_uppercase().as_str() {
+        "A" => KeyCode::KeyA,
+        "B" => KeyCode::KeyB,
+        "C" => KeyCode::KeyC,
+        "D" => KeyCode::KeyD,
+        "E" => KeyCode::KeyE,
+        "F" => KeyCode::KeyF,
+        "G" => KeyCode::KeyG,
+        "H" => KeyCode::KeyH,
+        "I" => KeyCode::KeyI,
+        "J" => KeyCode::KeyJ,
+        "K" => KeyCode::KeyK,
+        "L" => KeyCode::KeyL,
+        "M" => KeyCode::KeyM,
+        "N" => KeyCode::KeyN,
+        "O" => KeyCode::KeyO,
+        "P" => KeyCode::KeyP,
+        "Q" => KeyCode::KeyQ,
+        "R" => KeyCode::KeyR,
+        "S" => KeyCode::KeyS,
+        "T" => KeyCode::KeyT,
+        "U" => KeyCode::KeyU,
+        "V" => KeyCode::KeyV,
+        "W" => KeyCode::KeyW,
+        "X" => KeyCode::KeyX,
+        "Y" => KeyCode::KeyY,
+        "Z" => KeyCode::KeyZ,
+        "0" => KeyCode::Digit0,
+        "1" => KeyCode::Digit1,
+        "2" => KeyCode::Digit2,
+        "3" => KeyCode::Digit3,
+        "4" => KeyCode::Digit4,
+        "5" => KeyCode::Digit5,
+        "6" => KeyCode::Digit6,
+        "7" => KeyCode::Digit7,
+        "8" => KeyCode::Digit8,
+        "9" => KeyCode::Digit9,
+        "F1" => KeyCode::F1,
+        "F2" => KeyCode::F2,
+        "F3" => KeyCode::F3,
+        "F4" => KeyCode::F4,
+        "F5" => KeyCode::F5,
+        "F6" => KeyCode::F6,
+        "F7" => KeyCode::F7,
+        "F8" => KeyCode::F8,
+        "F9" => KeyCode::F9,
+        "F10" => KeyCode::F10,
+        "F11" => KeyCode::F11,
+        "F12" => KeyCode::F12,
+        "UP" => KeyCode::ArrowUp,
+        "DOWN" => KeyCode::ArrowDown,
+        "LEFT" => KeyCode::ArrowLeft,
+        "RIGHT" => KeyCode::ArrowRight,
+        "ENTER" => KeyCode::Enter,
+        "SPACE" => KeyCode::Space,
+        "BACKSPACE" => KeyCode::Backspace,
+        "TAB" => KeyCode::Tab,
+        "ESCAPE" => KeyCode::Escape,
+        "LSHIFT" => KeyCode::ShiftLeft,
+        "RSHIFT" => KeyCode::ShiftRight,
+        "LCTRL" => KeyCode::ControlLeft,
+        "RCTRL" => KeyCode::ControlRight,
+        "LALT" => KeyCode::AltLeft,
+        "RALT" => KeyCode::AltRight,
+        _ => return None,
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(config: &str) -> Result<Bindings, String> {
+        let mut bindings = Bindings::new();
+        bindings.parse(config).map(|_| bindings)
+    }
+
+    fn key(bindings: &Bindings, key: KeyCode) -> Option<Action> {
+        bindings.keys.get(&key).cloned()
+    }
+
+    #[test]
+    fn defaults_parse() {
+        let bindings = Bindings::new();
+        assert_eq!(key(&bindings, KeyCode::KeyX), Some(Action::Button(Button::A)));
+        assert_eq!(key(&bindings, KeyCode::Escape), Some(Action::Hotkey(Hotkey::Quit)));
+        assert_eq!(bindings.controller_buttons.get(&ControllerButton::DPadUp), Some(&Action::Button(Button::Up)));
+    }
+
+    #[test]
+    fn listed_actions_replace_their_defaults() {
+        let bindings = parse("[keyboard]\n# WASD\nup = w\nup = I  # either\na = K\n\n[controller]\na = a\n").unwrap();
+        assert_eq!(key(&bindings, KeyCode::KeyW), Some(Action::Button(Button::Up)));
+        assert_eq!(key(&bindings, KeyCode::KeyI), Some(Action::Button(Button::Up)));
+        assert_eq!(key(&bindings, KeyCode::ArrowUp), None);
+        assert_eq!(key(&bindings, KeyCode::KeyK), Some(Action::Button(Button::A)));
+        assert_eq!(key(&bindings, KeyCode::KeyX), None);
+        // Unlisted actions keep theirs
+        assert_eq!(key(&bindings, KeyCode::KeyZ), Some(Action::Button(Button::B)));
+
+        assert_eq!(bindings.controller_buttons.get(&ControllerButton::A), Some(&Action::Button(Button::A)));
+        assert_eq!(bindings.controller_buttons.get(&ControllerButton::B), None);
+    }
+
+    #[test]
+    fn keys_can_be_taken_from_the_defaults() {
+        let bindings = parse("[keyboard]\npause = X\n").unwrap();
+        assert_eq!(key(&bindings, KeyCode::KeyX), Some(Action::Hotkey(Hotkey::Pause)));
+        assert_eq!(key(&bindings, KeyCode::KeyP), None);
+    }
+
+    #[test]
+    fn rejects_unknown_names() {
+        assert_eq!(parse("[keyboard]\nup = Hyper\n").err().unwrap(), "line 2: unknown key `Hyper`");
+        assert_eq!(parse("[keyboard]\njump = Space\n").err().unwrap(), "line 2: unknown action `jump`");
+        assert_eq!(parse("[controller]\na = paddle1\n").err().unwrap(), "line 2: unknown controller button `paddle1`");
+        assert_eq!(parse("up = Up\n").err().unwrap(), "line 1: binding outside [keyboard] or [controller]");
+        assert_eq!(parse("[keyboard]\nup\n").err().unwrap(), "line 2: expected `action = key`");
+    }
+
+    #[test]
+    fn rejects_keys_bound_twice() {
+        assert_eq!(parse("[keyboard]\na = X\nb = x\n").err().unwrap(), "line 3: `x` is already bound to `a`");
+        assert_eq!(parse("[controller]\na = start\nstart = start\n").err().unwrap(),
+                   "line 3: `start` is already bound to `a`");
+        assert!(parse("[keyboard]\na = X\na = X\n").is_ok());
+    }
+}
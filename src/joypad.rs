@@ -0,0 +1,187 @@
+use super::savestate::{Savable, StateError, StateReader, StateWriter};
+
+/// The eight Game Boy buttons, independent of whatever drives them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+/// Every button, in the bit order `Joypad::pressed` uses.
+pub const BUTTONS: [Button; 8] = [
+    Button::Right,
+    Button::Left,
+    Button::Up,
+    Button::Down,
+    Button::A,
+    Button::B,
+    Button::Select,
+    Button::Start,
+];
+
+impl Button {
+    fn group(&self) -> Select {
+        match *self {
+            Button::Right | Button::Left | Button::Up | Button::Down => Select::SELECT_DIRECTIONS,
+            Button::A | Button::B | Button::Select | Button::Start => Select::SELECT_BUTTONS,
+        }
+    }
+
+    // P10-P13 input line the button pulls low when its group is selected
+    fn line(&self) -> u8 {
+        match *self {
+            Button::Right | Button::A => 1 << 0,
+            Button::Left | Button::B => 1 << 1,
+            Button::Up | Button::Select => 1 << 2,
+            Button::Down | Button::Start => 1 << 3,
+        }
+    }
+}
+
+/// The P1 register at 0xFF00. Writing bit 4 (P14) or bit 5 (P15) low selects
+/// the direction keys or the action buttons; the low nibble then reads back
+/// the selected keys, active low.
+pub struct Joypad {
+    select: Select,
+    directions: u8,
+    buttons: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            select: Select::empty(),
+            directions: 0,
+            buttons: 0,
+        }
+    }
+
+    pub fn read_byte(&self) -> u8 {
+        0xC0 | (!self.select.bits() & 0x30) | self.lines()
+    }
+
+    /// Returns true if the write requests the joypad interrupt.
+    pub fn write_byte(&mut self, value: u8) -> bool {
+        let old_lines = self.lines();
+        self.select = Select::from_bits_truncate(!value);
+        falling_edge(old_lines, self.lines())
+    }
+
+    /// Returns true if the press requests the joypad interrupt.
+    pub fn press(&mut self, button: Button) -> bool {
+        let old_lines = self.lines();
+        *self.group_mut(button) |= button.line();
+        falling_edge(old_lines, self.lines())
+    }
+
+    pub fn release(&mut self, button: Button) {
+        *self.group_mut(button) &= !button.line();
+    }
+
+    /// Buttons held down, one bit each in `BUTTONS` order.
+    pub fn pressed(&self) -> u8 {
+        self.directions | self.buttons << 4
+    }
+
+    fn group_mut(&mut self, button: Button) -> &mut u8 {
+        if button.group() == Select::SELECT_DIRECTIONS {
+            &mut self.directions
+        } else {
+            &mut self.buttons
+        }
+    }
+
+    // Current state of P10-P13, 0 meaning pulled low
+    fn lines(&self) -> u8 {
+        let mut pressed = 0;
+        if self.select.contains(Select::SELECT_DIRECTIONS) {
+            pressed |= self.directions;
+        }
+        if self.select.contains(Select::SELECT_BUTTONS) {
+            pressed |= self.buttons;
+        }
+        !pressed & 0x0F
+    }
+}
+
+impl Savable for Joypad {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(self.select.bits());
+        state.write_u8(self.directions);
+        state.write_u8(self.buttons);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.select = Select::from_bits_truncate(state.read_u8()?);
+        self.directions = state.read_u8()? & 0x0F;
+        self.buttons = state.read_u8()? & 0x0F;
+        Ok(())
+    }
+}
+
+fn falling_edge(old_lines: u8, new_lines: u8) -> bool {
+    old_lines & !new_lines != 0
+}
+
+bitflags! {
+    struct Select: u8 {
+        const SELECT_DIRECTIONS = 1 << 4;
+        const SELECT_BUTTONS    = 1 << 5;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_only_the_selected_group() {
+        let mut joypad = Joypad::new();
+        joypad.press(Button::Up);
+        joypad.press(Button::A);
+
+        joypad.write_byte(0x20);
+        assert_eq!(joypad.read_byte(), 0xEB);
+        joypad.write_byte(0x10);
+        assert_eq!(joypad.read_byte(), 0xDE);
+        joypad.write_byte(0x00);
+        assert_eq!(joypad.read_byte(), 0xCA);
+        joypad.write_byte(0x30);
+        assert_eq!(joypad.read_byte(), 0xFF);
+    }
+
+    #[test]
+    fn pressing_a_selected_button_interrupts() {
+        let mut joypad = Joypad::new();
+        joypad.write_byte(0x20);
+        assert!(joypad.press(Button::Down));
+        assert!(!joypad.press(Button::Start));
+        assert!(!joypad.press(Button::Down));
+    }
+
+    #[test]
+    fn selecting_a_held_group_interrupts() {
+        let mut joypad = Joypad::new();
+        joypad.write_byte(0x30);
+        joypad.press(Button::B);
+        assert!(!joypad.write_byte(0x20));
+        assert!(joypad.write_byte(0x10));
+    }
+
+    #[test]
+    fn pressed_follows_button_order() {
+        let mut joypad = Joypad::new();
+        for (bit, &button) in BUTTONS.iter().enumerate() {
+            joypad.press(button);
+            assert_eq!(joypad.pressed(), 1 << bit);
+            joypad.release(button);
+        }
+        assert_eq!(joypad.pressed(), 0);
+    }
+}
@@ -0,0 +1,43 @@
+//! The Rust Boy emulation core. `GameBoy` runs a frame at a time with no
+//! window or audio device attached; the `rustboy` binary is one frontend
+//! built on it.
+
+#[macro_use]
+extern crate bitflags;
+extern crate crc32fast;
+extern crate flate2;
+extern crate png;
+extern crate zip;
+
+pub mod cartridge;
+pub mod debugger;
+pub mod disasm;
+pub mod gameboy;
+pub mod gdb;
+pub mod link;
+pub mod model;
+pub mod movie;
+pub mod printer;
+pub mod resampler;
+pub mod rewind;
+pub mod savestate;
+pub mod serial;
+pub mod symbols;
+pub mod trace;
+pub mod wav;
+mod apu;
+mod cpu;
+mod gpu;
+mod joypad;
+mod mmu;
+mod timer;
+
+pub use apu::{StereoSample, Voice};
+pub use cpu::{InvalidOpcode, Registers};
+pub use mmu::{Access, Interrupts, Watchpoint};
+pub use cartridge::Cartridge;
+pub use gameboy::GameBoy;
+pub use gpu::{SCREEN_HEIGHT, SCREEN_PIXELS, SCREEN_WIDTH};
+pub use joypad::Button;
+pub use model::Model;
+pub use serial::SerialDevice;
@@ -0,0 +1,423 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use super::serial;
+
+/// A virtual link cable between two Game Boys in the same process. Each end
+/// is a `SerialDevice`; whichever side uses its internal clock drives the
+/// transfer and the other side must already be waiting on an external clock,
+/// exactly as with real hardware.
+///
+/// Both machines have to be stepped in lockstep for transfers to line up.
+pub fn cable() -> (LinkPort, LinkPort) {
+    let state = Rc::new(RefCell::new(LinkState {
+        waiting: [None, None],
+        incoming: [None, None],
+    }));
+
+    (LinkPort { side: 0, state: state.clone() }, LinkPort { side: 1, state })
+}
+
+struct LinkState {
+    // Byte each side has in SB while waiting for the other to clock it
+    waiting: [Option<u8>; 2],
+    // Byte clocked into each side by the other, not yet picked up
+    incoming: [Option<u8>; 2],
+}
+
+pub struct LinkPort {
+    side: usize,
+    state: Rc<RefCell<LinkState>>,
+}
+
+impl serial::SerialDevice for LinkPort {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        let mut state = self.state.borrow_mut();
+        let other = 1 - self.side;
+        state.waiting[self.side] = None;
+
+        match state.waiting[other].take() {
+            Some(reply) => {
+                state.incoming[other] = Some(outgoing);
+                reply
+            },
+            None => 0xFF,
+        }
+    }
+
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        let mut state = self.state.borrow_mut();
+
+        match state.incoming[self.side].take() {
+            Some(incoming) => Some(incoming),
+            None => {
+                state.waiting[self.side] = Some(outgoing);
+                None
+            }
+        }
+    }
+
+    fn cancel(&mut self) {
+        let mut state = self.state.borrow_mut();
+        state.waiting[self.side] = None;
+        // A byte clocked in before the game stopped listening never reaches it
+        state.incoming[self.side] = None;
+    }
+}
+
+const MESSAGE_TRANSFER: u8 = 0x01;
+const MESSAGE_REPLY: u8 = 0x02;
+const MESSAGE_WAITING: u8 = 0x03;
+const MESSAGE_CANCEL: u8 = 0x04;
+const MESSAGE_SIZE: usize = 3;
+
+/// A link cable to another emulator process over TCP.
+///
+/// A Game Boy waiting on an external clock tells the other side, and takes
+/// it back if the game stops waiting. The side driving the clock only sends
+/// its byte once it knows the other is waiting, then blocks for the reply,
+/// which costs a round trip. Otherwise it reads 0xFF straight away, as if
+/// the other Game Boy weren't listening yet.
+///
+/// Each wait has a sequence number. A transfer that crosses a cancel is
+/// answered by the cancel and ignored when it arrives, so either both
+/// machines see the byte or neither does.
+///
+/// Messages are three bytes: kind, sequence number and data.
+pub struct TcpLink {
+    stream: Option<TcpStream>,
+    buffer: Vec<u8>,
+    sequence: u8,
+    // Our wait the other side has been told about
+    waiting: Option<u8>,
+    // The other side's wait, as far as we've heard
+    peer_waiting: Option<u8>,
+}
+
+impl TcpLink {
+    /// Waits for the other emulator to connect to `listener`.
+    pub fn accept(listener: &TcpListener) -> io::Result<Self> {
+        let (stream, _) = listener.accept()?;
+        TcpLink::from_stream(stream)
+    }
+
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        TcpLink::from_stream(TcpStream::connect(addr)?)
+    }
+
+    fn from_stream(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+
+        Ok(TcpLink {
+            stream: Some(stream),
+            buffer: Vec::new(),
+            sequence: 0,
+            waiting: None,
+            peer_waiting: None,
+        })
+    }
+
+    fn send(&mut self, kind: u8, sequence: u8, data: u8) {
+        let result = match self.stream {
+            Some(ref mut stream) => write_all_nonblocking(stream, &[kind, sequence, data]),
+            None => return,
+        };
+        if let Err(err) = result {
+            self.disconnect(err);
+        }
+    }
+
+    /// Reads the next whole message if one has arrived.
+    fn receive(&mut self) -> Option<(u8, u8, u8)> {
+        loop {
+            if self.buffer.len() >= MESSAGE_SIZE {
+                let message: Vec<u8> = self.buffer.drain(..MESSAGE_SIZE).collect();
+                return Some((message[0], message[1], message[2]));
+            }
+
+            let mut chunk = [0; 64];
+            let result = match self.stream {
+                Some(ref mut stream) => stream.read(&mut chunk),
+                None => return None,
+            };
+
+            match result {
+                Ok(0) => {
+                    self.disconnect(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+                    return None;
+                },
+                Ok(count) => self.buffer.extend_from_slice(&chunk[..count]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(err) => {
+                    self.disconnect(err);
+                    return None;
+                }
+            }
+        }
+    }
+
+    // Keeps track of the other side's waits. Anything else arriving here is
+    // a transfer for a wait we already cancelled, or a reply we no longer
+    // expect, and is dropped.
+    fn track(&mut self, message: (u8, u8, u8)) {
+        match message {
+            (MESSAGE_WAITING, sequence, _) => self.peer_waiting = Some(sequence),
+            (MESSAGE_CANCEL, sequence, _) if self.peer_waiting == Some(sequence) => self.peer_waiting = None,
+            _ => {},
+        }
+    }
+
+    fn catch_up(&mut self) {
+        while let Some(message) = self.receive() {
+            self.track(message);
+        }
+    }
+
+    fn disconnect(&mut self, err: io::Error) {
+        if self.stream.take().is_some() {
+            eprintln!("Link cable disconnected: {}", err);
+        }
+    }
+}
+
+impl serial::SerialDevice for TcpLink {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        self.catch_up();
+        let sequence = match self.peer_waiting.take() {
+            Some(sequence) => sequence,
+            None => return 0xFF,
+        };
+        self.send(MESSAGE_TRANSFER, sequence, outgoing);
+
+        // The other side is polling while it waits, so it answers one way or
+        // the other as soon as our byte arrives
+        while self.stream.is_some() {
+            match self.receive() {
+                Some((MESSAGE_REPLY, reply_sequence, data)) if reply_sequence == sequence => return data,
+                Some((MESSAGE_CANCEL, cancel_sequence, _)) if cancel_sequence == sequence => return 0xFF,
+                Some(message) => self.track(message),
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+
+        0xFF
+    }
+
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        let sequence = match self.waiting {
+            Some(sequence) => sequence,
+            None => {
+                self.sequence = self.sequence.wrapping_add(1);
+                let sequence = self.sequence;
+                self.send(MESSAGE_WAITING, sequence, 0);
+                self.waiting = Some(sequence);
+                sequence
+            }
+        };
+
+        while let Some(message) = self.receive() {
+            match message {
+                (MESSAGE_TRANSFER, transfer_sequence, incoming) if transfer_sequence == sequence => {
+                    self.send(MESSAGE_REPLY, sequence, outgoing);
+                    self.waiting = None;
+                    return Some(incoming);
+                },
+                message => self.track(message),
+            }
+        }
+
+        None
+    }
+
+    fn cancel(&mut self) {
+        if let Some(sequence) = self.waiting.take() {
+            self.send(MESSAGE_CANCEL, sequence, 0);
+        }
+    }
+}
+
+fn write_all_nonblocking(stream: &mut TcpStream, data: &[u8]) -> io::Result<()> {
+    let mut written = 0;
+    while written < data.len() {
+        match stream.write(&data[written..]) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed")),
+            Ok(count) => written += count,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use gameboy::GameBoy;
+    use gameboy::tests::counting_game_boy;
+    use mmu::Interrupts;
+    use serial::SerialDevice;
+
+    // Boxed, as two machines don't fit on a test thread's stack
+    fn linked_pair() -> (Box<GameBoy>, Box<GameBoy>) {
+        let (port, peer_port) = cable();
+        let mut master = Box::new(counting_game_boy("MASTER"));
+        let mut slave = Box::new(counting_game_boy("SLAVE"));
+        master.connect_serial(Box::new(port));
+        slave.connect_serial(Box::new(peer_port));
+        (master, slave)
+    }
+
+    fn start(game_boy: &mut GameBoy, data: u8, control: u8) {
+        assert!(game_boy.poke(0xFF01, data));
+        assert!(game_boy.poke(0xFF02, control));
+    }
+
+    fn run(game_boy: &mut GameBoy) {
+        game_boy.run_frame().unwrap();
+    }
+
+    #[test]
+    fn linked_game_boys_exchange_bytes() {
+        let (mut master, mut slave) = linked_pair();
+        start(&mut slave, 0x42, 0x80);
+        run(&mut slave);
+        start(&mut master, 0x24, 0x81);
+        run(&mut master);
+        run(&mut slave);
+
+        assert_eq!(master.peek(0xFF01), 0x42);
+        assert_eq!(slave.peek(0xFF01), 0x24);
+        for game_boy in [&*master, &*slave].iter() {
+            assert_eq!(game_boy.peek(0xFF02) & 0x80, 0);
+            assert!(game_boy.interrupt_flag().contains(Interrupts::SERIAL));
+        }
+    }
+
+    #[test]
+    fn transfer_with_no_peer_waiting_reads_ff() {
+        let (mut master, mut slave) = linked_pair();
+        start(&mut master, 0x24, 0x81);
+        run(&mut master);
+        assert_eq!(master.peek(0xFF01), 0xFF);
+
+        // The byte sent into nothing doesn't turn up later
+        start(&mut slave, 0x42, 0x80);
+        run(&mut slave);
+        assert_eq!(slave.peek(0xFF01), 0x42);
+        assert_eq!(slave.peek(0xFF02) & 0x80, 0x80);
+    }
+
+    #[test]
+    fn a_game_that_stops_waiting_is_not_clocked() {
+        for &control in [0x00, 0x01].iter() {
+            let (mut master, mut slave) = linked_pair();
+            start(&mut slave, 0x42, 0x80);
+            run(&mut slave);
+            assert!(slave.poke(0xFF02, control));
+
+            start(&mut master, 0x24, 0x81);
+            run(&mut master);
+            run(&mut slave);
+            assert_eq!(master.peek(0xFF01), 0xFF);
+            assert_eq!(slave.peek(0xFF01), 0x42);
+        }
+    }
+
+    fn loopback() -> (TcpLink, TcpLink) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let slave = TcpLink::connect(listener.local_addr().unwrap()).unwrap();
+        let master = TcpLink::accept(&listener).unwrap();
+        (master, slave)
+    }
+
+    // Retries the master's transfer, as a game would, while the slave waits
+    // with `reply`. Returns both ends and what each received.
+    fn exchange(master: TcpLink, mut slave: TcpLink, outgoing: u8, reply: u8) -> (TcpLink, TcpLink, u8, u8) {
+        let master = thread::spawn(move || {
+            let mut master = master;
+            loop {
+                let incoming = master.transfer(outgoing);
+                if incoming != 0xFF {
+                    return (master, incoming);
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+        let incoming = loop {
+            if let Some(incoming) = slave.poll_external(reply) {
+                break incoming;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        let (master, reply) = master.join().unwrap();
+        (master, slave, reply, incoming)
+    }
+
+    // Gives the slave time to take a transfer that shouldn't be there
+    fn poll_for_a_while(slave: &mut TcpLink, outgoing: u8) -> Option<u8> {
+        for _ in 0..50 {
+            if let Some(incoming) = slave.poll_external(outgoing) {
+                return Some(incoming);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        None
+    }
+
+    #[test]
+    fn tcp_links_exchange_bytes() {
+        let (master, slave) = loopback();
+        let (master, slave, reply, incoming) = exchange(master, slave, 0x24, 0x42);
+        assert_eq!((reply, incoming), (0x42, 0x24));
+
+        let (_, _, reply, incoming) = exchange(master, slave, 0x99, 0x66);
+        assert_eq!((reply, incoming), (0x66, 0x99));
+    }
+
+    #[test]
+    fn tcp_transfer_with_no_peer_waiting_reads_ff() {
+        let (mut master, mut slave) = loopback();
+        assert_eq!(master.transfer(0x24), 0xFF);
+        assert_eq!(poll_for_a_while(&mut slave, 0x42), None);
+    }
+
+    #[test]
+    fn tcp_transfer_crossing_a_cancel_reaches_neither_side() {
+        let (mut master, mut slave) = loopback();
+        assert_eq!(slave.poll_external(0x42), None);
+        while master.peer_waiting.is_none() {
+            master.catch_up();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // The slave gives up with the master's byte already on its way
+        let master = thread::spawn(move || {
+            let mut master = master;
+            let incoming = master.transfer(0x24);
+            (master, incoming)
+        });
+        let mut peeked = [0; MESSAGE_SIZE];
+        while slave.stream.as_ref().unwrap().peek(&mut peeked).unwrap_or(0) < MESSAGE_SIZE {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(peeked[0], MESSAGE_TRANSFER);
+        slave.cancel();
+
+        let (master, incoming) = master.join().unwrap();
+        assert_eq!(incoming, 0xFF);
+        assert_eq!(poll_for_a_while(&mut slave, 0x42), None);
+
+        // The next transfer is the one the slave sees
+        let (_, _, reply, incoming) = exchange(master, slave, 0x99, 0x42);
+        assert_eq!((reply, incoming), (0x42, 0x99));
+    }
+}
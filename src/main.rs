@@ -1,34 +1,280 @@
+extern crate ctrlc;
 #[macro_use]
-extern crate bitflags;
 extern crate glium;
+extern crate png;
+extern crate rustboy;
+extern crate sdl2;
 
-mod cpu;
-mod gameboy;
-mod mmu;
-mod apu;
-mod gpu;
+mod audio;
+mod cli;
+mod frontend;
+mod input;
+mod pacing;
+mod palette;
+mod repl;
+mod video;
 
 use std::env;
-use std::fs;
-use std::io::Read;
-use std::path::Path;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
 
+use rustboy::{cartridge, disasm, gdb, link, printer, serial, wav};
+use rustboy::movie::Movie;
+use rustboy::symbols::Symbols;
+use rustboy::trace::Tracer;
+use rustboy::{Cartridge, GameBoy, Model};
 
 fn main() {
-    let boot_file_name = env::args().nth(1).unwrap();
-    let rom_file_name = env::args().nth(2).unwrap();
+    let result = match cli::parse(env::args().skip(1)) {
+        Ok(cli::Command::Run(options)) => run(*options),
+        Ok(cli::Command::Disasm(options)) => disassemble(options),
+        Ok(cli::Command::Help) => {
+            println!("{}", cli::USAGE);
+            return;
+        },
+        Err(err) => {
+            eprintln!("rustboy: {}", err);
+            eprintln!("Try 'rustboy --help' for more information.");
+            process::exit(2);
+        }
+    };
 
-    let boot_rom = read_bin(boot_file_name);
-    let game_rom = read_bin(rom_file_name);
+    if let Err(err) = result {
+        eprintln!("rustboy: {}", err);
+        process::exit(1);
+    }
+}
+
+fn run(options: cli::Options) -> Result<(), String> {
+    let cartridge = load_cartridge(&options.rom)?;
+    let boot_rom = match options.boot_rom {
+        Some(ref path) => Some(cartridge::read_rom(path)
+            .map_err(|err| format!("can't read boot ROM {}: {}", path.display(), err))?),
+        None => None,
+    };
+
+    if options.model == Model::Cgb {
+        println!("Game Boy Color hardware is not emulated yet; only the boot state matches a CGB");
+    }
+
+    let symbols = load_symbols(&options.rom, &options.symbols)?;
+
+    let mut game_boy = GameBoy::new();
+    game_boy.set_model(options.model);
+
+    if let Some(ref output) = options.trace {
+        let mut tracer = match output.as_str() {
+            "stdout" => Tracer::new(Box::new(io::stdout())),
+            path => Tracer::create(path).map_err(|err| format!("can't create trace {}: {}", path, err))?,
+        };
+        if let Some((start, end)) = options.trace_range {
+            tracer.set_range(start, end);
+        }
+        if let Some(bank) = options.trace_bank {
+            tracer.set_bank(bank);
+        }
+        if options.trace_symbols {
+            tracer.set_symbols(symbols.clone());
+        }
+        game_boy.set_tracer(Some(tracer));
+    }
+
+    match options.serial.as_deref() {
+        Some("stdout") => game_boy.connect_serial(Box::new(serial::StdoutLogger)),
+        Some(path) => {
+            let logger = serial::FileLogger::create(path)
+                .map_err(|err| format!("can't create serial log {}: {}", path, err))?;
+            game_boy.connect_serial(Box::new(logger));
+        },
+        None => {}
+    }
+
+    if let Some(ref dir) = options.printer {
+        let printer = printer::Printer::new(dir)
+            .map_err(|err| format!("can't use printer directory {}: {}", dir.display(), err))?;
+        game_boy.connect_serial(Box::new(printer));
+    }
+
+    if let Some(ref path) = options.link_rom {
+        game_boy.link(load_cartridge(path)?);
+    }
+
+    if let Some(port) = options.link_listen {
+        let listen_failed = |err| format!("can't listen for a link partner on port {}: {}", port, err);
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(&listen_failed)?;
+        println!("Waiting for link partner on port {}", port);
+        let link = link::TcpLink::accept(&listener).map_err(&listen_failed)?;
+        println!("Link partner connected");
+        game_boy.connect_serial(Box::new(link));
+    }
+
+    if let Some(port) = options.link_connect {
+        let link = link::TcpLink::connect(("127.0.0.1", port))
+            .map_err(|err| format!("can't connect to a link partner on port {}: {}", port, err))?;
+        game_boy.connect_serial(Box::new(link));
+    }
+
+    if let Some(ref path) = options.record_audio {
+        let recorder = wav::AudioRecorder::create(path, options.record_voices)
+            .map_err(|err| format!("can't record audio to {}: {}", path.display(), err))?;
+        game_boy.record_audio(recorder);
+    }
+
+    for &voice in options.muted_voices.iter() {
+        game_boy.set_voice_muted(voice, true);
+    }
+    for &(voice, volume) in options.voice_volumes.iter() {
+        game_boy.set_voice_volume(voice, volume);
+    }
+
+    game_boy.power_on(boot_rom, cartridge);
+
+    let playback = match options.play_movie {
+        Some(ref path) => {
+            let movie = Movie::load(path)
+                .map_err(|err| format!("can't load movie {}: {}", path.display(), err))?;
+            movie.start(&mut game_boy)
+                .map_err(|err| format!("can't play movie {}: {}", path.display(), err))?;
+            Some(movie)
+        },
+        None => None,
+    };
+    let recording = options.record_movie.as_ref().map(|_| Movie::record_from_power_on(&mut game_boy));
+
+    if options.debug {
+        return repl::run(game_boy, symbols);
+    }
+    if let Some(port) = options.gdb {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .map_err(|err| format!("can't listen for GDB on port {}: {}", port, err))?;
+        println!("Waiting for GDB on port {}, halted at {:04X}", port, game_boy.pc());
+        gdb::serve(&mut game_boy, &listener).map_err(|err| format!("GDB connection failed: {}", err))?;
+        println!("GDB detached");
+        return Ok(());
+    }
 
-    let mut game_boy = gameboy::GameBoy::new();
+    if options.headless {
+        let mut recording = recording;
+        let mut frames = 0;
+        while options.frames.is_none_or(|limit| frames < limit) {
+            if let Some(ref movie) = playback {
+                if !movie.play_frame(frames as usize, &mut game_boy) && options.frames.is_none() {
+                    break;
+                }
+            }
+            if let Some(ref mut movie) = recording {
+                movie.record_frame(&game_boy);
+            }
 
-    game_boy.power_on(boot_rom, game_rom);
+            game_boy.run_frame().map_err(|err| err.to_string())?;
+            game_boy.take_samples();
+            frames += 1;
+        }
+
+        if let (Some(movie), Some(path)) = (recording, options.record_movie.as_ref()) {
+            movie.save(path).map_err(|err| format!("can't save movie {}: {}", path.display(), err))?;
+        }
+        return Ok(());
+    }
+
+    let bindings = match options.input_config {
+        Some(ref path) => input::Bindings::from_file(path)
+            .map_err(|err| format!("can't load input config {}: {}", path.display(), err))?,
+        None => input::Bindings::new(),
+    };
+
+    let mut frontend = frontend::Frontend::new(game_boy, bindings, &options)?;
+    if let Some(movie) = playback {
+        frontend.play_movie(movie);
+    }
+    if let (Some(movie), Some(path)) = (recording, options.record_movie.clone()) {
+        frontend.record_movie(movie, path);
+    }
+    frontend.run().map_err(|err| err.to_string())
+}
+
+fn disassemble(options: cli::DisasmOptions) -> Result<(), String> {
+    let cartridge = load_cartridge(&options.rom)?;
+    let symbols = load_symbols(&options.rom, &options.symbols)?;
+    let labels = symbols.labels();
+    match options.output {
+        Some(ref path) => File::create(path)
+            .and_then(|file| disasm::disassemble_rom(cartridge.rom(), labels, &mut BufWriter::new(file)))
+            .map_err(|err| format!("can't write {}: {}", path.display(), err)),
+        None => {
+            let stdout = io::stdout();
+            disasm::disassemble_rom(cartridge.rom(), labels, &mut stdout.lock())
+                .map_err(|err| err.to_string())
+        },
+    }
+}
+
+// An explicit symbol file has to load; the one next to the ROM is optional
+fn load_symbols(rom: &Path, path: &Option<PathBuf>) -> Result<Symbols, String> {
+    let (path, required) = match *path {
+        Some(ref path) => (path.clone(), true),
+        None => (rom.with_extension("sym"), false),
+    };
+    if !required && !path.exists() {
+        return Ok(Symbols::new());
+    }
+    Symbols::load(&path).map_err(|err| format!("can't read symbols {}: {}", path.display(), err))
+}
+
+fn load_cartridge(path: &Path) -> Result<Cartridge, String> {
+    Cartridge::load(path).map_err(|err| format!("can't read ROM {}: {}", path.display(), err))
 }
 
-fn read_bin<P: AsRef<Path>>(path: P) -> Vec<u8> {
-    let mut file = fs::File::open(path).unwrap();
-    let mut file_buf = Vec::new();
-    file.read_to_end(&mut file_buf).unwrap();
-    file_buf
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::thread;
+
+    // `run` keeps whole machines on the stack, more than a test thread has
+    // in a debug build
+    fn headless(rom: &Path) -> Result<(), String> {
+        let args = ["--headless", "--frames", "2", rom.to_str().unwrap()];
+        let options = match cli::parse(args.iter().map(|arg| arg.to_string()))? {
+            cli::Command::Run(options) => options,
+            _ => panic!("--headless didn't parse to a run"),
+        };
+        thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(move || run(*options))
+            .unwrap()
+            .join()
+            .unwrap()
+    }
+
+    fn write_rom(name: &str, code: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("rustboy-main-{}-{}", process::id(), name));
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+        fs::write(&path, rom).unwrap();
+        path
+    }
+
+    #[test]
+    fn headless_reports_an_invalid_opcode() {
+        let path = write_rom("bad.gb", &[0x04, 0xD3]);
+        let result = headless(&path);
+        fs::remove_file(&path).unwrap();
+
+        let err = result.unwrap_err();
+        assert!(err.contains("0xD3 at 0x0101"), "{}", err);
+    }
+
+    #[test]
+    fn headless_runs_valid_code() {
+        let path = write_rom("count.gb", &[0x04, 0x0C, 0x18, 0xFC]);
+        let result = headless(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(result, Ok(()));
+    }
 }
@@ -1,16 +1,55 @@
+use std::cell::Cell;
+
 use super::apu;
 use super::gpu;
+use super::joypad;
+use super::savestate::{Savable, StateError, StateReader, StateWriter};
+use super::serial;
+use super::timer;
 
 pub const ROM_BANK_SIZE: usize = 0x4000;
 pub const WRAM_SIZE: usize = 0x2000;
 pub const HIRAM_SIZE: usize = 0xFF;
 
+/// A range of addresses, inclusive, whose reads or writes are reported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    fn matches(&self, addr: u16, write: bool) -> bool {
+        addr >= self.start && addr <= self.end && if write { self.write } else { self.read }
+    }
+}
+
+/// A memory access that hit a watchpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct Access {
+    pub addr: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
 pub struct Mmu {
     rom: [u8; 2 * ROM_BANK_SIZE],
     wram: [u8; WRAM_SIZE],
     hram: [u8; HIRAM_SIZE],
     gpu: gpu::Gpu,
-    apu: apu::Apu,
+    pub(crate) apu: apu::Apu,
+    timer: timer::Timer,
+    joypad: joypad::Joypad,
+    pub(crate) serial: serial::Serial,
+
+    pub(crate) interrupt_flag: Interrupts,
+    pub(crate) interrupt_enable: u8,
+
+    watchpoints: Vec<Watchpoint>,
+    // First watched access since the last `take_watch_hit`
+    watch_hit: Cell<Option<Access>>,
 }
 
 impl Mmu {
@@ -21,43 +60,211 @@ impl Mmu {
             hram: [0; HIRAM_SIZE],
             gpu: gpu::Gpu::new(),
             apu: apu::Apu::new(),
+            timer: timer::Timer::new(),
+            joypad: joypad::Joypad::new(),
+            serial: serial::Serial::new(),
+
+            interrupt_flag: Interrupts::empty(),
+            interrupt_enable: 0,
+
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        for _ in 0..self.timer.step(cycles) {
+            self.apu.clock_frame_sequencer();
         }
+        self.apu.step(cycles);
+
+        let interrupts = self.gpu.step(cycles);
+        self.interrupt_flag.insert(interrupts);
+
+        if self.serial.step(cycles) {
+            self.interrupt_flag.insert(Interrupts::SERIAL);
+        }
+    }
+
+    pub fn press_button(&mut self, button: joypad::Button) {
+        if self.joypad.press(button) {
+            self.interrupt_flag.insert(Interrupts::JOYPAD);
+        }
+    }
+
+    pub fn release_button(&mut self, button: joypad::Button) {
+        self.joypad.release(button);
+    }
+
+    pub fn pressed_buttons(&self) -> u8 {
+        self.joypad.pressed()
+    }
+
+    pub fn frame(&self) -> &[u8] {
+        self.gpu.frame()
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.gpu.frame_count()
+    }
+
+    pub fn set_watchpoints(&mut self, watchpoints: Vec<Watchpoint>) {
+        self.watchpoints = watchpoints;
+    }
+
+    pub fn take_watch_hit(&mut self) -> Option<Access> {
+        self.watch_hit.take()
     }
 
     pub fn read_byte(&self, addr: u16) -> u8 {
-        match addr >> 8 {
-            0x00...0x7F => self.rom[addr as usize],
-            0x80...0xFE => self.hram[addr as usize],
+        let value = match addr >> 8 {
+            0x00..=0x7F => self.rom[addr as usize],
+            0x80..=0x9F => self.gpu.read_byte(addr),
+            0xA0..=0xFE => self.hram[addr as usize],
             0xFF if addr < 0xFF80 => {
                 match addr & 0xFF {
-                    0x40...0x4B => self.gpu.read_byte(addr),
+                    0x00 => self.joypad.read_byte(),
+                    0x01..=0x02 => self.serial.read_byte(addr),
+                    0x04 => self.timer.read_div(),
+                    0x0F => self.interrupt_flag.bits() | 0xE0,
+                    0x10..=0x3F => self.apu.read_byte(addr),
+                    0x40..=0x4B => self.gpu.read_byte(addr),
                     _ => panic!("Unimplemented read at IO address: {:#X}", addr)
                 }
             },
             0xFF if addr > 0xFF7F && addr < 0xFFFF => {
                 self.hram[(addr & 0xFF) as usize]
             },
+            0xFF => self.interrupt_enable,
             _ => panic!("Unimplemented read address: {:#x}", addr)
+        };
+
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, value, false);
+        }
+        value
+    }
+
+    /// Reads without side effects, for debuggers. Anything not mapped reads
+    /// as 0xFF rather than stopping the emulator.
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr >> 8 {
+            0x00..=0x7F => self.rom[addr as usize],
+            0x80..=0x9F => self.gpu.read_byte(addr),
+            0xFF if addr < 0xFF80 => {
+                match addr & 0xFF {
+                    0x00 => self.joypad.read_byte(),
+                    0x01..=0x02 => self.serial.read_byte(addr),
+                    0x04 => self.timer.read_div(),
+                    0x0F => self.interrupt_flag.bits() | 0xE0,
+                    0x10..=0x3F => self.apu.read_byte(addr),
+                    0x40..=0x45 | 0x47 | 0x4A | 0x4B => self.gpu.read_byte(addr),
+                    _ => 0xFF,
+                }
+            },
+            0xFF if addr < 0xFFFF => self.hram[(addr & 0xFF) as usize],
+            0xFF => self.interrupt_enable,
+            _ => 0xFF,
+        }
+    }
+
+    /// Writes like the CPU would, but refuses addresses `write_byte` has no
+    /// mapping for rather than panicking. Returns whether it wrote.
+    pub fn poke(&mut self, addr: u16, value: u8) -> bool {
+        let mapped = match addr >> 8 {
+            0x00..=0x9F => true,
+            0xFF if addr < 0xFF80 => matches!(addr & 0xFF, 0x00..=0x02 | 0x04 | 0x0F | 0x10..=0x4B),
+            0xFF => true,
+            _ => false,
+        };
+        if mapped {
+            self.write_byte(addr, value);
+        }
+        mapped
+    }
+
+    fn watch(&self, addr: u16, value: u8, write: bool) {
+        let hit = self.watchpoints.iter().any(|watchpoint| watchpoint.matches(addr, write));
+        if hit && self.watch_hit.get().is_none() {
+            self.watch_hit.set(Some(Access { addr, value, write }));
         }
     }
 
     pub fn write_byte(&mut self, addr: u16, value: u8) {
-        if addr == 0xFFFF { panic!("This shouldn't happen yet!")};
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, value, true);
+        }
+
         match addr >> 8 {
-            0x00...0x7F => self.rom[addr as usize] = value,
-            0x80...0x9F => self.gpu.write_byte(addr, value),
+            0x00..=0x7F => self.rom[addr as usize] = value,
+            0x80..=0x9F => self.gpu.write_byte(addr, value),
             0xFF if addr < 0xFF80 => {
                 match addr & 0xFF {
-                    0x10...0x3F => self.apu.write_byte(addr, value),
-                    0x40...0x4B => self.gpu.write_byte(addr, value),
+                    0x00 => {
+                        if self.joypad.write_byte(value) {
+                            self.interrupt_flag.insert(Interrupts::JOYPAD);
+                        }
+                    },
+                    0x01..=0x02 => self.serial.write_byte(addr, value),
+                    0x04 => {
+                        if self.timer.reset_div() {
+                            self.apu.clock_frame_sequencer();
+                        }
+                    },
+                    0x0F => self.interrupt_flag = Interrupts::from_bits_truncate(value),
+                    0x10..=0x3F => self.apu.write_byte(addr, value),
+                    0x40..=0x4B => self.gpu.write_byte(addr, value),
                     _ => panic!("Unimplemented write at IO address: {:#x}: {:#x}", addr, value)
                 }
             },
             0xFF if addr > 0xFF7F && addr < 0xFFFF => {
                 self.hram[(addr & 0xFF) as usize] = value;
             },
+            0xFF => self.interrupt_enable = value,
             _ => panic!("Unimplmented write address: {:#x}", addr)
         }
     }
 }
 
+// The ROM area is saved too, since the boot ROM and stray writes both land in
+// it
+impl Savable for Mmu {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.rom);
+        state.write_bytes(&self.wram);
+        state.write_bytes(&self.hram);
+        self.gpu.save(state);
+        self.apu.save(state);
+        self.timer.save(state);
+        self.joypad.save(state);
+        self.serial.save(state);
+
+        state.write_u8(self.interrupt_flag.bits());
+        state.write_u8(self.interrupt_enable);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.rom)?;
+        state.read_bytes(&mut self.wram)?;
+        state.read_bytes(&mut self.hram)?;
+        self.gpu.load(state)?;
+        self.apu.load(state)?;
+        self.timer.load(state)?;
+        self.joypad.load(state)?;
+        self.serial.load(state)?;
+
+        self.interrupt_flag = Interrupts::from_bits_truncate(state.read_u8()?);
+        self.interrupt_enable = state.read_u8()?;
+        Ok(())
+    }
+}
+
+bitflags! {
+    pub struct Interrupts: u8 {
+        const VBLANK   = 1 << 0;
+        const LCD_STAT = 1 << 1;
+        const TIMER    = 1 << 2;
+        const SERIAL   = 1 << 3;
+        const JOYPAD   = 1 << 4;
+    }
+}
@@ -0,0 +1,40 @@
+/// The hardware revision being emulated. Without a boot ROM this decides the
+/// register values games see at 0x100, which is how they tell models apart.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Model {
+    Dmg,
+    Mgb,
+    Cgb,
+    Sgb,
+}
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Model> {
+        match name {
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "cgb" => Some(Model::Cgb),
+            "sgb" => Some(Model::Sgb),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Model::Dmg => "dmg",
+            Model::Mgb => "mgb",
+            Model::Cgb => "cgb",
+            Model::Sgb => "sgb",
+        }
+    }
+
+    /// AF, BC, DE and HL as the model's boot ROM leaves them.
+    pub fn boot_registers(&self) -> [u16; 4] {
+        match *self {
+            Model::Dmg => [0x01B0, 0x0013, 0x00D8, 0x014D],
+            Model::Mgb => [0xFFB0, 0x0013, 0x00D8, 0x014D],
+            Model::Cgb => [0x1180, 0x0000, 0xFF56, 0x000D],
+            Model::Sgb => [0x0100, 0x0014, 0x0000, 0xC060],
+        }
+    }
+}
@@ -0,0 +1,501 @@
+//! Input movies: the buttons held at the start of every frame, replayed from
+//! power-on or an embedded save state to reproduce a run exactly.
+//!
+//! Native movies (`.rbm`) are, integers little-endian:
+//!
+//! ```text
+//! "RBMV" | format version (u16) | ROM title | ROM CRC-32 (u32) | model
+//!        | players (u8) | start (u8, 0 power-on, 1 save state)
+//!        | [state length (u32) | state] | frames (u32)
+//!        | one byte per player per frame, in `joypad::BUTTONS` bit order
+//! ```
+//!
+//! BizHawk `.bk2` and VBA `.vbm` input logs can be imported when they start
+//! from power-on. They carry no checksum we can verify, and other emulators
+//! split frames slightly differently, so long imports may desync.
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use crc32fast;
+use zip;
+
+use super::gameboy::GameBoy;
+use super::model::Model;
+use super::savestate::{StateError, StateReader, StateWriter};
+
+const MAGIC: &[u8; 4] = b"RBMV";
+const VERSION: u16 = 1;
+
+const VBM_MAGIC: &[u8; 4] = b"VBM\x1A";
+const VBM_HEADER_SIZE: usize = 0x40;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    Invalid(String),
+    Unsupported(String),
+    RomMismatch { movie_title: String, rom_title: String },
+    PlayerMismatch { players: usize },
+    State(StateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::Io(ref err) => write!(f, "{}", err),
+            MovieError::Invalid(ref what) => write!(f, "invalid movie: {}", what),
+            MovieError::Unsupported(ref what) => write!(f, "unsupported movie: {}", what),
+            MovieError::RomMismatch { ref movie_title, ref rom_title } => {
+                write!(f, "movie was recorded with '{}', not the loaded ROM '{}'", movie_title, rom_title)
+            },
+            MovieError::PlayerMismatch { players: 2 } => {
+                write!(f, "movie was recorded with a linked Game Boy, run with --link to play it")
+            },
+            MovieError::PlayerMismatch { .. } => write!(f, "movie was recorded without a linked Game Boy"),
+            MovieError::State(ref err) => write!(f, "movie's save state: {}", err),
+        }
+    }
+}
+
+impl error::Error for MovieError {}
+
+impl From<io::Error> for MovieError {
+    fn from(err: io::Error) -> Self {
+        MovieError::Io(err)
+    }
+}
+
+/// Where playback begins.
+pub enum Start {
+    PowerOn,
+    SaveState(Vec<u8>),
+}
+
+pub struct Movie {
+    rom_title: String,
+    rom_checksum: Option<u32>,
+    model: Option<Model>,
+    players: usize,
+    start: Start,
+    inputs: Vec<u8>,
+}
+
+impl Movie {
+    /// Resets `game_boy` and starts a movie from power-on.
+    pub fn record_from_power_on(game_boy: &mut GameBoy) -> Self {
+        game_boy.reset();
+        Movie::record(game_boy, Start::PowerOn)
+    }
+
+    /// Starts a movie from `game_boy`'s current state.
+    pub fn record_from_state(game_boy: &GameBoy) -> Self {
+        Movie::record(game_boy, Start::SaveState(game_boy.save_state()))
+    }
+
+    fn record(game_boy: &GameBoy, start: Start) -> Self {
+        let (rom_title, rom_checksum) = match game_boy.cartridge() {
+            Some(cartridge) => (cartridge.title(), Some(crc32fast::hash(cartridge.rom()))),
+            None => (String::new(), None),
+        };
+
+        Movie {
+            rom_title,
+            rom_checksum,
+            model: Some(game_boy.model()),
+            players: if game_boy.is_linked() { 2 } else { 1 },
+            start,
+            inputs: Vec::new(),
+        }
+    }
+
+    /// Call before running each frame while recording.
+    pub fn record_frame(&mut self, game_boy: &GameBoy) {
+        for player in 0..self.players {
+            self.inputs.push(game_boy.buttons(player));
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.inputs.len() / self.players
+    }
+
+    /// Puts `game_boy` where the movie starts, after checking it was
+    /// recorded on the same ROM and number of machines.
+    pub fn start(&self, game_boy: &mut GameBoy) -> Result<(), MovieError> {
+        if let (Some(checksum), Some(cartridge)) = (self.rom_checksum, game_boy.cartridge()) {
+            if crc32fast::hash(cartridge.rom()) != checksum {
+                return Err(MovieError::RomMismatch {
+                    movie_title: self.rom_title.clone(),
+                    rom_title: cartridge.title(),
+                });
+            }
+        }
+
+        let players = if game_boy.is_linked() { 2 } else { 1 };
+        if players != self.players {
+            return Err(MovieError::PlayerMismatch { players: self.players });
+        }
+
+        match self.start {
+            Start::PowerOn => {
+                if let Some(model) = self.model {
+                    game_boy.set_model(model);
+                }
+                game_boy.reset();
+            },
+            Start::SaveState(ref state) => game_boy.load_state(state).map_err(MovieError::State)?,
+        }
+        Ok(())
+    }
+
+    /// Sets the buttons for `frame`, to call before running it. Returns
+    /// false once the movie has ended.
+    pub fn play_frame(&self, frame: usize, game_boy: &mut GameBoy) -> bool {
+        if frame >= self.frames() {
+            return false;
+        }
+
+        for player in 0..self.players {
+            game_boy.set_buttons(player, self.inputs[frame * self.players + player]);
+        }
+        true
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut movie = StateWriter::new();
+        movie.write_bytes(MAGIC);
+        movie.write_u16(VERSION);
+        movie.write_string(&self.rom_title);
+        movie.write_u32(self.rom_checksum.unwrap_or(0));
+        movie.write_string(self.model.map_or("", |model| model.name()));
+        movie.write_u8(self.players as u8);
+
+        match self.start {
+            Start::PowerOn => movie.write_u8(0),
+            Start::SaveState(ref state) => {
+                movie.write_u8(1);
+                movie.write_u32(state.len() as u32);
+                movie.write_bytes(state);
+            },
+        }
+
+        movie.write_u32(self.frames() as u32);
+        movie.write_bytes(&self.inputs);
+        movie.into_bytes()
+    }
+
+    /// Loads a native movie, or imports a `.bk2` or `.vbm` one.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MovieError> {
+        let path = path.as_ref();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("bk2") => import_bk2(fs::File::open(path)?),
+            Some("vbm") => import_vbm(&fs::read(path)?),
+            _ => Movie::read(&fs::read(path)?),
+        }
+    }
+
+    fn read(data: &[u8]) -> Result<Self, MovieError> {
+        let mut movie = StateReader::new(data);
+
+        let mut magic = [0; 4];
+        movie.read_bytes(&mut magic).map_err(truncated)?;
+        if &magic != MAGIC {
+            return Err(MovieError::Invalid("not a Rust Boy movie".to_string()));
+        }
+        let version = movie.read_u16().map_err(truncated)?;
+        if version != VERSION {
+            return Err(MovieError::Unsupported(format!("format {}, expected {}", version, VERSION)));
+        }
+
+        let rom_title = movie.read_string().map_err(truncated)?;
+        let rom_checksum = movie.read_u32().map_err(truncated)?;
+        let model = movie.read_string().map_err(truncated)?;
+        let players = movie.read_u8().map_err(truncated)? as usize;
+        if players != 1 && players != 2 {
+            return Err(MovieError::Invalid(format!("{} players", players)));
+        }
+
+        // Lengths come from the file, so they're checked before allocating
+        let start = match movie.read_u8().map_err(truncated)? {
+            0 => Start::PowerOn,
+            1 => {
+                let length = movie.read_u32().map_err(truncated)? as usize;
+                if length > movie.remaining() {
+                    return Err(truncated(StateError::Truncated));
+                }
+                let mut state = vec![0; length];
+                movie.read_bytes(&mut state).map_err(truncated)?;
+                Start::SaveState(state)
+            },
+            _ => return Err(MovieError::Invalid("unknown start".to_string())),
+        };
+
+        let frames = movie.read_u32().map_err(truncated)? as usize;
+        if frames.saturating_mul(players) > movie.remaining() {
+            return Err(truncated(StateError::Truncated));
+        }
+        let mut inputs = vec![0; frames * players];
+        movie.read_bytes(&mut inputs).map_err(truncated)?;
+
+        Ok(Movie {
+            rom_title,
+            rom_checksum: Some(rom_checksum),
+            model: Model::from_name(&model),
+            players,
+            start,
+            inputs,
+        })
+    }
+}
+
+fn truncated(_: StateError) -> MovieError {
+    MovieError::Invalid("truncated".to_string())
+}
+
+fn imported(title: String, model: Option<Model>, players: usize, inputs: Vec<u8>) -> Movie {
+    Movie {
+        rom_title: title,
+        rom_checksum: None,
+        model,
+        players,
+        start: Start::PowerOn,
+        inputs,
+    }
+}
+
+// A BizHawk movie is a zip holding a header and a text input log. The log's
+// `LogKey` line names the buttons, and each frame is a line with one
+// character per button, `.` meaning released.
+fn import_bk2<R: Read + Seek>(reader: R) -> Result<Movie, MovieError> {
+    let mut archive = zip::ZipArchive::new(reader)
+        .map_err(|err| MovieError::Invalid(format!("can't open bk2 archive: {}", err)))?;
+
+    let header = read_zip_text(&mut archive, "Header.txt")?;
+    let mut title = String::new();
+    for line in header.lines() {
+        let mut parts = line.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some("StartsFromSavestate"), Some(value)) |
+            (Some("StartsFromSaveRam"), Some(value)) if value.trim() == "True" => {
+                return Err(MovieError::Unsupported("bk2 movies starting from a save are not supported".to_string()));
+            },
+            (Some("GameName"), Some(value)) => title = value.trim().to_string(),
+            _ => {},
+        }
+    }
+
+    let log = read_zip_text(&mut archive, "Input Log.txt")?;
+    let mut keys = Vec::new();
+    let mut players = 1;
+    let mut inputs = Vec::new();
+    for line in log.lines() {
+        if let Some(names) = line.strip_prefix("LogKey:") {
+            keys = names.split(['#', '|'])
+                .filter(|name| !name.is_empty())
+                .map(bk2_button)
+                .collect();
+            players = if keys.iter().any(|key| key.is_some_and(|(player, _)| player == 1)) { 2 } else { 1 };
+        } else if line.starts_with('|') {
+            let mut frame = [0; 2];
+            let states = line.chars().filter(|&c| c != '|');
+            for (key, state) in keys.iter().zip(states) {
+                if let Some((player, bit)) = *key {
+                    if state != '.' && state != ' ' {
+                        frame[player] |= 1 << bit;
+                    }
+                }
+            }
+            inputs.extend_from_slice(&frame[..players]);
+        }
+    }
+
+    if keys.is_empty() {
+        return Err(MovieError::Invalid("bk2 input log has no LogKey".to_string()));
+    }
+    Ok(imported(title, None, players, inputs))
+}
+
+fn read_zip_text<R: Read + Seek>(archive: &mut zip::ZipArchive<R>, name: &str) -> Result<String, MovieError> {
+    let mut entry = archive.by_name(name)
+        .map_err(|_| MovieError::Invalid(format!("bk2 archive has no {}", name)))?;
+    let mut text = String::new();
+    entry.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+// Player and bit for a BizHawk button name such as `P1 Up` or `A`
+fn bk2_button(name: &str) -> Option<(usize, usize)> {
+    let (player, button) = if let Some(button) = name.strip_prefix("P2 ") {
+        (1, button)
+    } else if let Some(button) = name.strip_prefix("P1 ") {
+        (0, button)
+    } else {
+        (0, name)
+    };
+
+    let bit = match button {
+        "Right" => 0,
+        "Left" => 1,
+        "Up" => 2,
+        "Down" => 3,
+        "A" => 4,
+        "B" => 5,
+        "Select" => 6,
+        "Start" => 7,
+        _ => return None,
+    };
+    Some((player, bit))
+}
+
+// A VBA movie has a 64 byte header, then two bytes per controller per frame
+// with A, B, Select, Start, Right, Left, Up and Down in the low byte
+fn import_vbm(data: &[u8]) -> Result<Movie, MovieError> {
+    if data.len() < VBM_HEADER_SIZE || &data[0..4] != VBM_MAGIC {
+        return Err(MovieError::Invalid("not a VBA movie".to_string()));
+    }
+
+    let read_u32 = |offset: usize| {
+        data[offset] as u32 | (data[offset + 1] as u32) << 8 | (data[offset + 2] as u32) << 16 | (data[offset + 3] as u32) << 24
+    };
+    let frames = read_u32(0x0C) as usize;
+    let start_flags = data[0x14];
+    let controller_flags = data[0x15];
+    let type_flags = data[0x16];
+    let controller_data = read_u32(0x3C) as usize;
+
+    if start_flags & 0x03 != 0 {
+        return Err(MovieError::Unsupported("vbm movies starting from a save are not supported".to_string()));
+    }
+    let model = match type_flags {
+        flags if flags & 0x01 != 0 => {
+            return Err(MovieError::Unsupported("Game Boy Advance movies can't be played".to_string()));
+        },
+        flags if flags & 0x02 != 0 => Model::Cgb,
+        flags if flags & 0x04 != 0 => Model::Sgb,
+        _ => Model::Dmg,
+    };
+
+    let controllers = (controller_flags & 0x0F).count_ones() as usize;
+    if controllers == 0 {
+        return Err(MovieError::Invalid("vbm movie has no controllers".to_string()));
+    }
+    let frame_size = controllers * 2;
+    let end = controller_data + frames * frame_size;
+    if controller_data < VBM_HEADER_SIZE || end > data.len() {
+        return Err(MovieError::Invalid("truncated".to_string()));
+    }
+
+    // Only the first controller drives a Game Boy
+    let inputs = data[controller_data..end].chunks(frame_size)
+        .map(|frame| (frame[0] & 0x0F) << 4 | frame[0] >> 4)
+        .collect();
+
+    let title = data[0x24..0x30].iter()
+        .take_while(|&&byte| byte != 0)
+        .map(|&byte| byte as char)
+        .collect();
+
+    Ok(imported(title, Some(model), 1, inputs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    use gameboy::tests::counting_game_boy;
+    use joypad::Button;
+
+    fn vbm(start_flags: u8, frames: &[[u8; 2]]) -> Vec<u8> {
+        let mut data = vec![0; VBM_HEADER_SIZE];
+        data[0..4].copy_from_slice(VBM_MAGIC);
+        data[0x0C] = frames.len() as u8;
+        data[0x14] = start_flags;
+        data[0x15] = 0x01;
+        data[0x24..0x2B].copy_from_slice(b"VBMTEST");
+        data[0x3C] = VBM_HEADER_SIZE as u8;
+        for frame in frames {
+            data.extend_from_slice(frame);
+        }
+        data
+    }
+
+    #[test]
+    fn imports_vbm_inputs_in_button_order() {
+        let movie = import_vbm(&vbm(0, &[[0x01, 0x00], [0x80, 0x00], [0x13, 0x00]])).unwrap();
+        assert_eq!(movie.rom_title, "VBMTEST");
+        assert_eq!(movie.model, Some(Model::Dmg));
+        assert_eq!(movie.players, 1);
+        assert_eq!(movie.inputs, vec![0x10, 0x08, 0x31]);
+    }
+
+    #[test]
+    fn rejects_vbm_movies_it_cant_play() {
+        assert!(import_vbm(&vbm(1, &[[0x00, 0x00]])).is_err());
+
+        let mut truncated = vbm(0, &[[0x00, 0x00], [0x00, 0x00]]);
+        truncated.pop();
+        assert!(import_vbm(&truncated).is_err());
+        assert!(import_vbm(b"VBM\x1A").is_err());
+    }
+
+    #[test]
+    fn imports_bk2_input_logs() {
+        let mut data = Vec::new();
+        {
+            let mut archive = zip::ZipWriter::new(Cursor::new(&mut data));
+            let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+            archive.start_file("Header.txt", options).unwrap();
+            archive.write_all(b"GameName Test Game\nStartsFromSavestate False\n").unwrap();
+            archive.start_file("Input Log.txt", options).unwrap();
+            archive.write_all(b"[Input]\nLogKey:#P1 Up|P1 Down|P1 Left|P1 Right|P1 Start|P1 Select|P1 B|P1 A|P1 Power|\n\
+                                |U......A.|\n|...R.s...|\n|.........|\n[/Input]\n").unwrap();
+            archive.finish().unwrap();
+        }
+
+        let movie = import_bk2(Cursor::new(data)).unwrap();
+        assert_eq!(movie.rom_title, "Test Game");
+        assert_eq!(movie.players, 1);
+        assert_eq!(movie.inputs, vec![0x14, 0x41, 0x00]);
+    }
+
+    #[test]
+    fn native_movies_round_trip() {
+        let mut game_boy = counting_game_boy("MOVIE");
+        let mut movie = Movie::record_from_power_on(&mut game_boy);
+        for &button in [Button::A, Button::Start, Button::Left].iter() {
+            game_boy.press(0, button);
+            movie.record_frame(&game_boy);
+            game_boy.run_frame().unwrap();
+        }
+
+        let loaded = Movie::read(&movie.to_bytes()).unwrap();
+        assert_eq!(loaded.frames(), 3);
+        assert_eq!(loaded.inputs, movie.inputs);
+        assert!(loaded.start(&mut game_boy).is_ok());
+        assert!(loaded.start(&mut counting_game_boy("OTHER")).is_err());
+    }
+
+    #[test]
+    fn rejects_movies_longer_than_the_file() {
+        let mut movie = StateWriter::new();
+        movie.write_bytes(MAGIC);
+        movie.write_u16(VERSION);
+        movie.write_string("TITLE");
+        movie.write_u32(0);
+        movie.write_string("dmg");
+        movie.write_u8(1);
+        movie.write_u8(0);
+        movie.write_u32(u32::MAX);
+        assert!(Movie::read(&movie.into_bytes()).is_err());
+    }
+}
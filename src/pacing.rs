@@ -0,0 +1,73 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustboy::gameboy::CYCLES_PER_FRAME;
+
+const CLOCK_RATE: f64 = 4_194_304.0;
+
+// About 59.7275 Hz
+pub const FRAME_RATE: f64 = CLOCK_RATE / CYCLES_PER_FRAME as f64;
+
+// Falling further behind than this resets the schedule rather than running
+// flat out to catch up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+// Sleeping is only accurate to a millisecond or so; the rest is spun away
+const SPIN_TIME: Duration = Duration::from_millis(1);
+
+/// What the emulator waits on between frames.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Sync {
+    // Wait for the audio queue to drain, falling back to the timer when
+    // there is no audio or the speed isn't normal
+    Audio,
+    Timer,
+}
+
+impl Sync {
+    pub fn from_name(name: &str) -> Option<Sync> {
+        match name {
+            "audio" => Some(Sync::Audio),
+            "timer" => Some(Sync::Timer),
+            _ => None,
+        }
+    }
+}
+
+/// Schedules frames against a high resolution timer.
+pub struct FramePacer {
+    next_frame: Instant,
+}
+
+impl FramePacer {
+    pub fn new() -> Self {
+        FramePacer {
+            next_frame: Instant::now(),
+        }
+    }
+
+    /// Waits until the next frame is due when running at `speed` times the
+    /// Game Boy's frame rate.
+    pub fn wait(&mut self, speed: f64) {
+        let frame_time = Duration::from_secs_f64(1.0 / (FRAME_RATE * speed));
+        self.next_frame += frame_time;
+
+        let now = Instant::now();
+        if now > self.next_frame + MAX_LAG {
+            self.next_frame = now;
+            return;
+        }
+
+        if self.next_frame > now + SPIN_TIME {
+            thread::sleep(self.next_frame - now - SPIN_TIME);
+        }
+        while Instant::now() < self.next_frame {
+            thread::yield_now();
+        }
+    }
+
+    /// Starts the schedule afresh, after a pause.
+    pub fn reset(&mut self) {
+        self.next_frame = Instant::now();
+    }
+}
@@ -0,0 +1,48 @@
+/// The RGB colors the four shades are shown in, lightest first.
+#[derive(Clone, Copy)]
+pub struct Palette {
+    colors: [[u8; 3]; 4],
+}
+
+impl Palette {
+    pub fn grey() -> Self {
+        Palette {
+            colors: [[0xFF, 0xFF, 0xFF], [0xAA, 0xAA, 0xAA], [0x55, 0x55, 0x55], [0x00, 0x00, 0x00]],
+        }
+    }
+
+    /// A named palette, or four `RRGGBB` colors separated by commas.
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        let colors = match name {
+            "grey" | "gray" => return Ok(Palette::grey()),
+            "green" => "9BBC0F,8BAC0F,306230,0F380F",
+            "pocket" => "C4CFA1,8B956D,4D533C,1F1F1F",
+            _ => name,
+        };
+
+        let mut palette = Palette::grey();
+        let parts: Vec<&str> = colors.split(',').map(|part| part.trim().trim_start_matches('#')).collect();
+        if parts.len() != 4 {
+            return Err(format!("unknown palette '{}', expected grey, green, pocket or four RRGGBB colors", name));
+        }
+
+        for (color, part) in palette.colors.iter_mut().zip(parts) {
+            let rgb = match u32::from_str_radix(part, 16) {
+                Ok(rgb) if part.len() == 6 => rgb,
+                _ => return Err(format!("invalid color '{}' in palette, expected RRGGBB", part)),
+            };
+            *color = [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8];
+        }
+
+        Ok(palette)
+    }
+
+    pub fn rgb(&self, shade: u8) -> [u8; 3] {
+        self.colors[(shade & 0x03) as usize]
+    }
+
+    /// Converts a frame of shades to packed RGB.
+    pub fn frame_to_rgb(&self, frame: &[u8]) -> Vec<u8> {
+        frame.iter().flat_map(|&shade| self.rgb(shade).to_vec()).collect()
+    }
+}
@@ -0,0 +1,371 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use png;
+
+use super::serial;
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+const DEVICE_ID: u8 = 0x81;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 1 << 0;
+const STATUS_BUSY: u8 = 1 << 1;
+const STATUS_IMAGE_FULL: u8 = 1 << 2;
+const STATUS_UNPROCESSED: u8 = 1 << 3;
+
+// The printer's 8KB of RAM holds nine full data packets
+const PACKET_DATA_SIZE: usize = 0x280;
+const BUFFER_SIZE: usize = PACKET_DATA_SIZE * 9;
+
+// Number of status queries the printer stays busy for after a print
+const PRINT_BUSY_POLLS: u8 = 4;
+
+const WIDTH: usize = 160;
+const TILES_PER_ROW: usize = WIDTH / 8;
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+#[derive(Clone, Copy, PartialEq)]
+enum PacketState {
+    Magic(usize),
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    DeviceId,
+    Status,
+}
+
+/// The Game Boy Printer. Packets are sent with the Game Boy driving the
+/// clock:
+///
+/// `0x88 0x33 | command | compression | length (LE) | data | checksum (LE) | 0x00 0x00`
+///
+/// The printer answers the last two bytes with its device ID and status.
+/// Printed strips are stacked into a page, which is written out as a PNG in
+/// `dir` once the paper is fed past it.
+pub struct Printer {
+    dir: PathBuf,
+    state: PacketState,
+
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    expected_checksum: u16,
+
+    status: u8,
+    busy_polls: u8,
+    buffer: Vec<u8>,
+    page: Vec<u8>,
+    pages_printed: u32,
+}
+
+impl Printer {
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+
+        Ok(Printer {
+            dir: dir.as_ref().to_path_buf(),
+            state: PacketState::Magic(0),
+
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            expected_checksum: 0,
+
+            status: 0,
+            busy_polls: 0,
+            buffer: Vec::new(),
+            page: Vec::new(),
+            pages_printed: 0,
+        })
+    }
+
+    fn receive(&mut self, value: u8) -> u8 {
+        match self.state {
+            PacketState::Magic(index) => {
+                self.state = if value == MAGIC[index] {
+                    if index + 1 == MAGIC.len() { PacketState::Command } else { PacketState::Magic(index + 1) }
+                } else if value == MAGIC[0] {
+                    PacketState::Magic(1)
+                } else {
+                    PacketState::Magic(0)
+                };
+            },
+            PacketState::Command => {
+                self.command = value;
+                self.checksum = value as u16;
+                self.data.clear();
+                self.state = PacketState::Compression;
+            },
+            PacketState::Compression => {
+                self.compressed = value & 0x01 != 0;
+                self.add_to_checksum(value);
+                self.state = PacketState::LengthLow;
+            },
+            PacketState::LengthLow => {
+                self.length = value as u16;
+                self.add_to_checksum(value);
+                self.state = PacketState::LengthHigh;
+            },
+            PacketState::LengthHigh => {
+                self.length |= (value as u16) << 8;
+                self.add_to_checksum(value);
+                self.state = if self.length == 0 { PacketState::ChecksumLow } else { PacketState::Data };
+            },
+            PacketState::Data => {
+                self.data.push(value);
+                self.add_to_checksum(value);
+                if self.data.len() == self.length as usize {
+                    self.state = PacketState::ChecksumLow;
+                }
+            },
+            PacketState::ChecksumLow => {
+                self.expected_checksum = value as u16;
+                self.state = PacketState::ChecksumHigh;
+            },
+            PacketState::ChecksumHigh => {
+                self.expected_checksum |= (value as u16) << 8;
+                self.process_packet();
+                self.state = PacketState::DeviceId;
+            },
+            PacketState::DeviceId => {
+                self.state = PacketState::Status;
+                return DEVICE_ID;
+            },
+            PacketState::Status => {
+                self.state = PacketState::Magic(0);
+                return self.status;
+            },
+        }
+
+        0x00
+    }
+
+    fn add_to_checksum(&mut self, value: u8) {
+        self.checksum = self.checksum.wrapping_add(value as u16);
+    }
+
+    fn process_packet(&mut self) {
+        if self.checksum != self.expected_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            },
+            COMMAND_DATA => {
+                let data = if self.compressed { decompress(&self.data) } else { self.data.clone() };
+                let space = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend(data.into_iter().take(space));
+
+                // An empty data packet just marks the end of the image
+                self.status |= STATUS_UNPROCESSED;
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= STATUS_IMAGE_FULL;
+                }
+            },
+            COMMAND_PRINT if self.data.len() == 4 => {
+                let margins = self.data[1];
+                let palette = self.data[2];
+                self.print(palette);
+
+                // The lower nibble is the feed after printing; a non-zero feed
+                // ends the page
+                if margins & 0x0F != 0 {
+                    self.save_page();
+                }
+
+                self.status = (self.status & !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL)) | STATUS_BUSY;
+                self.busy_polls = PRINT_BUSY_POLLS;
+            },
+            COMMAND_STATUS if self.busy_polls > 0 => {
+                self.busy_polls -= 1;
+                if self.busy_polls == 0 {
+                    self.status &= !STATUS_BUSY;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    /// Renders the buffered tiles onto the current page.
+    fn print(&mut self, palette: u8) {
+        // Some games send a zero palette and expect the default
+        let palette = if palette == 0 { 0xE4 } else { palette };
+
+        let tile_rows = self.buffer.len() / (TILES_PER_ROW * 16);
+        for tile_row in 0..tile_rows {
+            for y in 0..8 {
+                for x in 0..WIDTH {
+                    let tile = tile_row * TILES_PER_ROW + x / 8;
+                    let low = self.buffer[tile * 16 + y * 2];
+                    let high = self.buffer[tile * 16 + y * 2 + 1];
+                    let bit = 7 - x % 8;
+
+                    let color = (high >> bit & 1) << 1 | low >> bit & 1;
+                    let shade = palette >> (color * 2) & 0x03;
+                    self.page.push(SHADES[shade as usize]);
+                }
+            }
+        }
+
+        self.buffer.clear();
+    }
+
+    fn save_page(&mut self) {
+        if self.page.is_empty() {
+            return;
+        }
+
+        let path = self.next_path();
+        match write_png(&path, &self.page) {
+            Ok(()) => eprintln!("Printed {}", path.display()),
+            Err(err) => eprintln!("Failed to save print to {}: {}", path.display(), err),
+        }
+        self.page.clear();
+    }
+
+    // Picks the first unused file name so earlier prints are never overwritten
+    fn next_path(&mut self) -> PathBuf {
+        loop {
+            self.pages_printed += 1;
+            let path = self.dir.join(format!("print_{:03}.png", self.pages_printed));
+            if !path.exists() {
+                return path;
+            }
+        }
+    }
+}
+
+impl serial::SerialDevice for Printer {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        self.receive(outgoing)
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.save_page();
+    }
+}
+
+/// Expands run-length encoded packet data. A control byte with bit 7 set is
+/// followed by one byte repeated `(control & 0x7F) + 2` times, otherwise by
+/// `control + 1` literal bytes.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut bytes = data.iter();
+
+    while let Some(&control) = bytes.next() {
+        if control & 0x80 != 0 {
+            if let Some(&value) = bytes.next() {
+                let count = (control & 0x7F) as usize + 2;
+                output.extend(std::iter::repeat_n(value, count));
+            }
+        } else {
+            let count = control as usize + 1;
+            output.extend(bytes.by_ref().take(count));
+        }
+    }
+
+    output
+}
+
+fn write_png(path: &Path, pixels: &[u8]) -> io::Result<()> {
+    let file = fs::File::create(path)?;
+    let height = pixels.len() / WIDTH;
+
+    let mut encoder = png::Encoder::new(io::BufWriter::new(file), WIDTH as u32, height as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn printer(name: &str) -> Printer {
+        Printer::new(env::temp_dir().join(format!("rustboy-printer-{}", name))).unwrap()
+    }
+
+    // Sends a whole packet, returning the device ID and status bytes
+    fn send(printer: &mut Printer, command: u8, compression: u8, data: &[u8], checksum: u16) -> (u8, u8) {
+        let length = data.len() as u16;
+        let mut packet = vec![MAGIC[0], MAGIC[1], command, compression, length as u8, (length >> 8) as u8];
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(&[checksum as u8, (checksum >> 8) as u8]);
+        for &byte in packet.iter() {
+            assert_eq!(printer.receive(byte), 0x00);
+        }
+        (printer.receive(0x00), printer.receive(0x00))
+    }
+
+    fn checksum(command: u8, compression: u8, data: &[u8]) -> u16 {
+        let length = data.len() as u16;
+        data.iter().fold(command as u16 + compression as u16 + (length & 0xFF) + (length >> 8),
+                         |sum, &byte| sum.wrapping_add(byte as u16))
+    }
+
+    #[test]
+    fn decompresses_runs_and_literals() {
+        assert_eq!(decompress(&[0x81, 0xAA, 0x02, 1, 2, 3]), vec![0xAA, 0xAA, 0xAA, 1, 2, 3]);
+        assert_eq!(decompress(&[0xFF, 0x11]), vec![0x11; 129]);
+        assert_eq!(decompress(&[0x00, 0x42, 0x80]), vec![0x42]);
+    }
+
+    #[test]
+    fn answers_packets_with_id_and_status() {
+        let mut printer = printer("status");
+        let init = checksum(COMMAND_INIT, 0, &[]);
+        assert_eq!(send(&mut printer, COMMAND_INIT, 0, &[], init), (DEVICE_ID, 0x00));
+
+        let data = [0x12; 16];
+        let sum = checksum(COMMAND_DATA, 0, &data);
+        assert_eq!(send(&mut printer, COMMAND_DATA, 0, &data, sum), (DEVICE_ID, STATUS_UNPROCESSED));
+        assert_eq!(printer.buffer.len(), 16);
+    }
+
+    #[test]
+    fn bad_checksums_are_flagged_and_ignored() {
+        let mut printer = printer("checksum");
+        let data = [0x34; 8];
+        let sum = checksum(COMMAND_DATA, 0, &data);
+        assert_eq!(send(&mut printer, COMMAND_DATA, 0, &data, sum + 1), (DEVICE_ID, STATUS_CHECKSUM_ERROR));
+        assert!(printer.buffer.is_empty());
+
+        assert_eq!(send(&mut printer, COMMAND_DATA, 0, &data, sum), (DEVICE_ID, STATUS_UNPROCESSED));
+    }
+
+    #[test]
+    fn compressed_data_is_expanded_into_the_buffer() {
+        let mut printer = printer("compressed");
+        let data = [0x8E, 0xFF, 0x01, 0x00, 0x7E];
+        let sum = checksum(COMMAND_DATA, 1, &data);
+        send(&mut printer, COMMAND_DATA, 1, &data, sum);
+        assert_eq!(printer.buffer.len(), 18);
+        assert_eq!(&printer.buffer[16..], &[0x00, 0x7E]);
+    }
+}
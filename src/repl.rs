@@ -0,0 +1,422 @@
+//! `--debug`: a debugger driven by commands typed at the terminal, with the
+//! machine running headless between them.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufWriter, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use ctrlc;
+
+use rustboy::{GameBoy, Interrupts, Watchpoint};
+use rustboy::debugger::{parse_hex, Breakpoint, Debugger, Stop};
+use rustboy::disasm;
+use rustboy::symbols::Symbols;
+
+const HELP: &str = "\
+Commands:
+    s, step [N]               Execute N instructions, following calls
+    n, next                   Execute one instruction, running calls through
+    f, finish                 Run until the current function returns
+    c, continue               Run until something stops the machine
+    b, break <[BANK:]ADDR>    Stop when PC reaches ADDR
+    w, watch <ADDR[-END]> [r|w|rw]
+                              Stop when the range is read or written
+                              [default: rw]
+    i, info                   List breakpoints and watchpoints
+    d, delete <bN|wN>         Remove a breakpoint or watchpoint
+    interrupt <NAME...>       Stop when vblank, stat, timer, serial or
+                              joypad is requested, or all or none
+    r, regs                   Show registers and flags
+    x <ADDR> [LENGTH]         Dump memory [default: 64 bytes]
+    set <ADDR> <BYTE...>      Write memory
+    l, list [ADDR] [N]        Disassemble N instructions from ADDR
+                              [default: around PC]
+    bt, backtrace             Show the calls made since the debugger started
+    disasm <FILE>             Disassemble the whole ROM to FILE
+    reset                     Reset the machine
+    q, quit                   Exit
+
+Numbers are hex, and addresses can also be symbol names. An empty line repeats the last command, Ctrl-C stops a
+running machine, and an opcode the CPU can't execute always stops it.";
+
+// Instructions shown by `list`, and how many of them come before PC
+const LIST_LENGTH: usize = 10;
+const LIST_HISTORY: usize = 4;
+
+pub fn run(mut game_boy: GameBoy, symbols: Symbols) -> Result<(), String> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_interrupted = interrupted.clone();
+    ctrlc::set_handler(move || handler_interrupted.store(true, Ordering::Relaxed))
+        .map_err(|err| format!("can't catch Ctrl-C: {}", err))?;
+
+    let mut debugger = Debugger::new();
+    println!("Rust Boy debugger, 'help' lists commands");
+    show_pc(&game_boy, &symbols);
+
+    let stdin = io::stdin();
+    let mut last_command = String::new();
+    loop {
+        print!("(rustboy) ");
+        io::stdout().flush().map_err(|err| err.to_string())?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).map_err(|err| err.to_string())? == 0 {
+            println!();
+            return Ok(());
+        }
+        if !line.trim().is_empty() {
+            last_command = line.trim().to_string();
+        }
+
+        let words: Vec<&str> = last_command.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+
+        interrupted.store(false, Ordering::Relaxed);
+        match execute(&mut game_boy, &mut debugger, &symbols, &words, &interrupted) {
+            Ok(true) => {},
+            Ok(false) => return Ok(()),
+            Err(err) => println!("{}", err),
+        }
+    }
+}
+
+// Returns false to quit
+fn execute(game_boy: &mut GameBoy,
+           debugger: &mut Debugger,
+           symbols: &Symbols,
+           words: &[&str],
+           interrupted: &AtomicBool) -> Result<bool, String> {
+    let args = &words[1..];
+    match words[0] {
+        "s" | "step" => {
+            let count = match args.first() {
+                Some(count) => parse_hex(count).ok_or_else(|| format!("invalid count '{}'", count))?,
+                None => 1,
+            };
+            let mut stop = Stop::Done;
+            for _ in 0..count {
+                stop = debugger.step(game_boy);
+                if let Stop::Done = stop {} else { break; }
+                if interrupted.swap(false, Ordering::Relaxed) {
+                    stop = Stop::Interrupted;
+                    break;
+                }
+            }
+            report(game_boy, symbols, stop);
+        },
+        "n" | "next" => {
+            let stop = debugger.step_over(game_boy, interrupted);
+            report(game_boy, symbols, stop);
+        },
+        "f" | "finish" => {
+            let stop = debugger.step_out(game_boy, interrupted);
+            report(game_boy, symbols, stop);
+        },
+        "c" | "continue" => {
+            let stop = debugger.resume(game_boy, interrupted);
+            report(game_boy, symbols, stop);
+        },
+        "b" | "break" => {
+            let text = args.first().ok_or("break needs an address")?;
+            let breakpoint = parse_breakpoint(text, symbols)?;
+            debugger.breakpoints.push(breakpoint);
+            println!("Breakpoint b{} at {}", debugger.breakpoints.len(), describe_breakpoint(&breakpoint, symbols));
+        },
+        "w" | "watch" => {
+            let watchpoint = parse_watchpoint(args, symbols)?;
+            debugger.watchpoints.push(watchpoint);
+            println!("Watchpoint w{} on {}", debugger.watchpoints.len(), describe_watchpoint(&watchpoint));
+        },
+        "i" | "info" => {
+            for (index, breakpoint) in debugger.breakpoints.iter().enumerate() {
+                println!("b{}  {}", index + 1, describe_breakpoint(breakpoint, symbols));
+            }
+            for (index, watchpoint) in debugger.watchpoints.iter().enumerate() {
+                println!("w{}  {}", index + 1, describe_watchpoint(watchpoint));
+            }
+            if !debugger.break_interrupts.is_empty() {
+                println!("Interrupts: {:?}", debugger.break_interrupts);
+            }
+        },
+        "d" | "delete" => {
+            let text = args.first().ok_or("delete needs bN or wN")?;
+            let index: usize = text.get(1..).and_then(|index| index.parse().ok())
+                .ok_or_else(|| format!("invalid breakpoint '{}'", text))?;
+            let removed = match text.chars().next() {
+                Some('b') if index >= 1 && index <= debugger.breakpoints.len() => {
+                    debugger.breakpoints.remove(index - 1);
+                    true
+                },
+                Some('w') if index >= 1 && index <= debugger.watchpoints.len() => {
+                    debugger.watchpoints.remove(index - 1);
+                    true
+                },
+                _ => false,
+            };
+            if !removed {
+                return Err(format!("no breakpoint or watchpoint '{}'", text));
+            }
+        },
+        "interrupt" => {
+            if !args.is_empty() {
+                debugger.break_interrupts = parse_interrupts(args)?;
+            }
+            println!("Stopping on interrupts: {:?}", debugger.break_interrupts);
+        },
+        "r" | "regs" => show_registers(game_boy),
+        "x" => {
+            let addr = parse_addr(args.first(), symbols)?;
+            let length = match args.get(1) {
+                Some(length) => parse_hex(length).ok_or_else(|| format!("invalid number '{}'", length))? as usize,
+                None => 64,
+            };
+            dump(game_boy, addr, length);
+        },
+        "set" => {
+            let addr = parse_addr(args.first(), symbols)?;
+            let bytes = args[1..].iter()
+                .map(|byte| parse_hex(byte).filter(|&value| value <= 0xFF).ok_or_else(|| format!("invalid byte '{}'", byte)))
+                .collect::<Result<Vec<u16>, String>>()?;
+            if bytes.is_empty() {
+                return Err("set needs at least one byte".to_string());
+            }
+            for (offset, &byte) in bytes.iter().enumerate() {
+                let addr = addr.wrapping_add(offset as u16);
+                if !game_boy.poke(addr, byte as u8) {
+                    return Err(format!("{:04X} can't be written", addr));
+                }
+            }
+        },
+        "l" | "list" => {
+            match args.first() {
+                Some(_) => {
+                    let addr = parse_addr(args.first(), symbols)?;
+                    let count = match args.get(1) {
+                        Some(count) => parse_hex(count).ok_or_else(|| format!("invalid count '{}'", count))? as usize,
+                        None => LIST_LENGTH,
+                    };
+                    list(game_boy, symbols, addr, count);
+                },
+                None => list_around_pc(game_boy, debugger, symbols),
+            }
+        },
+        "bt" | "backtrace" => {
+            println!("#0  {}", location(game_boy.pc(), symbols));
+            for (depth, frame) in debugger.call_stack().iter().rev().enumerate() {
+                println!("#{}  {}", depth + 1, location(frame.call_site, symbols));
+            }
+        },
+        "disasm" => {
+            let path = args.first().ok_or("disasm needs a file")?;
+            let rom = game_boy.cartridge().ok_or("no cartridge is inserted")?.rom();
+            File::create(path)
+                .and_then(|file| disasm::disassemble_rom(rom, symbols.labels(), &mut BufWriter::new(file)))
+                .map_err(|err| format!("can't write {}: {}", path, err))?;
+            println!("Wrote {}", path);
+        },
+        "reset" => {
+            game_boy.reset();
+            debugger.forget();
+            show_pc(game_boy, symbols);
+        },
+        "q" | "quit" => return Ok(false),
+        "h" | "help" => println!("{}", HELP),
+        command => return Err(format!("unknown command '{}', try 'help'", command)),
+    }
+
+    Ok(true)
+}
+
+fn report(game_boy: &GameBoy, symbols: &Symbols, stop: Stop) {
+    if let Stop::Done = stop {} else {
+        println!("{}", stop);
+    }
+    show_pc(game_boy, symbols);
+}
+
+// Symbol names win over hex, so a label like `Add` isn't read as a number
+fn parse_addr(text: Option<&&str>, symbols: &Symbols) -> Result<u16, String> {
+    let text = text.ok_or("missing address")?;
+    symbols.lookup(text).map(|(_, addr)| addr)
+        .or_else(|| parse_hex(text))
+        .ok_or_else(|| format!("invalid address or unknown symbol '{}'", text))
+}
+
+fn parse_breakpoint(text: &str, symbols: &Symbols) -> Result<Breakpoint, String> {
+    match symbols.lookup(text) {
+        Some((bank, addr)) if (0x4000..0x8000).contains(&addr) => Ok(Breakpoint { bank: Some(bank), addr }),
+        Some((_, addr)) => Ok(Breakpoint { bank: None, addr }),
+        None => Breakpoint::parse(text).ok_or_else(|| format!("invalid address or unknown symbol '{}'", text)),
+    }
+}
+
+fn describe_breakpoint(breakpoint: &Breakpoint, symbols: &Symbols) -> String {
+    let name = match breakpoint.bank {
+        Some(bank) => symbols.labels().get(&(bank, breakpoint.addr)).cloned(),
+        None => symbols.describe(breakpoint.addr),
+    };
+    match name {
+        Some(name) => format!("{} ({})", breakpoint, name),
+        None => breakpoint.to_string(),
+    }
+}
+
+fn location(addr: u16, symbols: &Symbols) -> String {
+    match symbols.describe(addr) {
+        Some(name) => format!("{:04X} in {}", addr, name),
+        None => format!("{:04X}", addr),
+    }
+}
+
+fn parse_watchpoint(args: &[&str], symbols: &Symbols) -> Result<Watchpoint, String> {
+    let range = args.first().ok_or("watch needs an address or range")?;
+    let mut bounds = range.splitn(2, '-');
+    let start = parse_addr(bounds.next().as_ref(), symbols)?;
+    let end = match bounds.next() {
+        Some(end) => parse_addr(Some(&end), symbols)?,
+        None => start,
+    };
+    if end < start {
+        return Err(format!("range '{}' ends before it starts", range));
+    }
+
+    let (read, write) = match args.get(1).copied() {
+        Some("r") => (true, false),
+        Some("w") => (false, true),
+        Some("rw") | None => (true, true),
+        Some(kind) => return Err(format!("unknown access '{}', expected r, w or rw", kind)),
+    };
+
+    Ok(Watchpoint {
+        start,
+        end,
+        read,
+        write,
+    })
+}
+
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    let access = match (watchpoint.read, watchpoint.write) {
+        (true, true) => "rw",
+        (true, false) => "r",
+        _ => "w",
+    };
+    format!("{:04X}-{:04X} {}", watchpoint.start, watchpoint.end, access)
+}
+
+fn parse_interrupts(names: &[&str]) -> Result<Interrupts, String> {
+    let mut interrupts = Interrupts::empty();
+    for name in names {
+        interrupts |= match *name {
+            "vblank" => Interrupts::VBLANK,
+            "stat" => Interrupts::LCD_STAT,
+            "timer" => Interrupts::TIMER,
+            "serial" => Interrupts::SERIAL,
+            "joypad" => Interrupts::JOYPAD,
+            "all" => Interrupts::all(),
+            "none" => Interrupts::empty(),
+            _ => return Err(format!("unknown interrupt '{}'", name)),
+        };
+    }
+    Ok(interrupts)
+}
+
+fn show_registers(game_boy: &GameBoy) {
+    let registers = game_boy.registers();
+    let flag = |bit: u8, name: char| if registers.f & bit != 0 { name } else { '-' };
+    println!("AF={:02X}{:02X} BC={:02X}{:02X} DE={:02X}{:02X} HL={:02X}{:02X} SP={:04X} PC={:04X}",
+             registers.a, registers.f, registers.b, registers.c, registers.d, registers.e,
+             registers.h, registers.l, registers.sp, registers.pc);
+
+    println!("Flags: {}{}{}{}  IF={:02X} IE={:02X}",
+             flag(0x80, 'Z'), flag(0x40, 'N'), flag(0x20, 'H'), flag(0x10, 'C'),
+             game_boy.interrupt_flag().bits(), game_boy.interrupt_enable());
+}
+
+fn dump(game_boy: &GameBoy, addr: u16, length: usize) {
+    let bytes: Vec<u8> = (0..length).map(|offset| game_boy.peek(addr.wrapping_add(offset as u16))).collect();
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let text: String = chunk.iter()
+            .map(|&byte| if (0x20..0x7F).contains(&byte) { byte as char } else { '.' })
+            .collect();
+        println!("{:04X}: {:<48} {}", addr.wrapping_add(row as u16 * 16), hex.join(" "), text);
+    }
+}
+
+// Prints `count` instructions from `addr`, marking PC
+fn list(game_boy: &GameBoy, symbols: &Symbols, addr: u16, count: usize) {
+    let pc = game_boy.pc();
+    let mut addr = addr;
+    for _ in 0..count {
+        if let Some(name) = symbols.name(addr) {
+            println!("{}:", name);
+        }
+        let instruction = disasm::decode(addr, |addr| game_boy.peek(addr));
+        let label = instruction.target.and_then(|target| symbols.name(target));
+        let marker = if addr == pc { "=>" } else { "  " };
+        println!("{} {}", marker, instruction.line(addr, label));
+        addr = addr.wrapping_add(instruction.length());
+    }
+}
+
+// Code can't be decoded backwards reliably, so what comes before PC is the
+// instructions that actually ran
+fn list_around_pc(game_boy: &GameBoy, debugger: &Debugger, symbols: &Symbols) {
+    let history = debugger.history();
+    let pc = game_boy.pc();
+    let previous: Vec<u16> = history.iter().skip(history.len().saturating_sub(LIST_HISTORY)).cloned().collect();
+    for addr in previous {
+        if addr != pc {
+            list(game_boy, symbols, addr, 1);
+        }
+    }
+    list(game_boy, symbols, pc, LIST_LENGTH - LIST_HISTORY);
+}
+
+fn show_pc(game_boy: &GameBoy, symbols: &Symbols) {
+    list(game_boy, symbols, game_boy.pc(), 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_watchpoints() {
+        let symbols = Symbols::parse("00:C000 wBuffer\n").unwrap();
+        let watchpoint = parse_watchpoint(&["wBuffer-C00F", "w"], &symbols).unwrap();
+        assert_eq!((watchpoint.start, watchpoint.end), (0xC000, 0xC00F));
+        assert_eq!((watchpoint.read, watchpoint.write), (false, true));
+
+        let watchpoint = parse_watchpoint(&["$FF80"], &symbols).unwrap();
+        assert_eq!((watchpoint.start, watchpoint.end), (0xFF80, 0xFF80));
+        assert_eq!((watchpoint.read, watchpoint.write), (true, true));
+
+        assert!(parse_watchpoint(&["C00F-C000"], &symbols).is_err());
+        assert!(parse_watchpoint(&["C000", "x"], &symbols).is_err());
+        assert!(parse_watchpoint(&[], &symbols).is_err());
+    }
+
+    #[test]
+    fn symbols_win_over_hex() {
+        let symbols = Symbols::parse("00:0150 Add\n01:4000 Banked\n").unwrap();
+        assert_eq!(parse_addr(Some(&"Add"), &symbols), Ok(0x0150));
+        assert_eq!(parse_addr(Some(&"ADD"), &symbols), Ok(0x0ADD));
+        assert_eq!(parse_breakpoint("Banked", &symbols), Ok(Breakpoint { bank: Some(1), addr: 0x4000 }));
+        assert_eq!(parse_breakpoint("Add", &symbols), Ok(Breakpoint { bank: None, addr: 0x0150 }));
+        assert!(parse_breakpoint("Missing", &symbols).is_err());
+    }
+
+    #[test]
+    fn parses_interrupt_names() {
+        assert_eq!(parse_interrupts(&["vblank", "timer"]), Ok(Interrupts::VBLANK | Interrupts::TIMER));
+        assert_eq!(parse_interrupts(&["all"]), Ok(Interrupts::all()));
+        assert_eq!(parse_interrupts(&["none"]), Ok(Interrupts::empty()));
+        assert!(parse_interrupts(&["hblank"]).is_err());
+    }
+}
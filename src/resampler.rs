@@ -0,0 +1,258 @@
+use std::f64::consts::PI;
+use std::mem;
+
+use super::apu;
+
+const PHASES: usize = 32;
+const KERNEL_WIDTH: usize = 16;
+
+// Fraction of the output Nyquist frequency the kernel lets through
+const CUTOFF: f64 = 0.9;
+
+// Charge factor of the DMG's output capacitor per T-cycle
+const CAPACITOR_CHARGE: f64 = 0.999958;
+const CPU_CLOCK: f64 = 4_194_304.0;
+
+/// Converts the APU's 1 MHz output to the host sample rate using
+/// band-limited step synthesis: every change in amplitude is added to a delta
+/// buffer as a windowed-sinc step, which is then integrated into output
+/// samples and run through the DMG's high-pass filter.
+pub struct Resampler {
+    output_rate: u32,
+    step: f64,
+    time: f64,
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    charge_factor: f32,
+
+    left: Side,
+    right: Side,
+
+    output: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(output_rate: u32) -> Self {
+        Resampler {
+            output_rate,
+            step: output_rate as f64 / apu::SAMPLE_RATE as f64,
+            time: 0.0,
+            kernel: build_kernel(),
+            charge_factor: CAPACITOR_CHARGE.powf(CPU_CLOCK / output_rate as f64) as f32,
+
+            left: Side::new(),
+            right: Side::new(),
+
+            output: Vec::new(),
+        }
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    /// Scales the resampling ratio by `factor`, producing that many times
+    /// more output samples per input sample.
+    pub fn set_rate_adjust(&mut self, factor: f64) {
+        self.step = self.output_rate as f64 / apu::SAMPLE_RATE as f64 * factor;
+    }
+
+    /// Feeds APU samples in, producing every output sample that can no
+    /// longer be affected by later input.
+    pub fn push(&mut self, samples: &[apu::StereoSample]) {
+        for sample in samples {
+            let phase = self.time.fract();
+            let kernel = &self.kernel[(phase * PHASES as f64) as usize];
+            let index = self.time as usize;
+
+            self.left.add_delta(index, kernel, sample.left);
+            self.right.add_delta(index, kernel, sample.right);
+
+            self.time += self.step;
+        }
+
+        let complete = self.time as usize;
+        for i in 0..complete {
+            let left = self.left.output(i, self.charge_factor);
+            let right = self.right.output(i, self.charge_factor);
+            self.output.push(left);
+            self.output.push(right);
+        }
+
+        self.left.discard(complete);
+        self.right.discard(complete);
+        self.time -= complete as f64;
+    }
+
+    /// Number of stereo frames waiting to be read.
+    pub fn available(&self) -> usize {
+        self.output.len() / 2
+    }
+
+    /// Takes the pending frames as interleaved left/right `f32` samples.
+    pub fn read_f32(&mut self) -> Vec<f32> {
+        mem::take(&mut self.output)
+    }
+
+    /// Takes the pending frames as interleaved left/right `i16` samples.
+    pub fn read_i16(&mut self) -> Vec<i16> {
+        self.read_f32()
+            .iter()
+            .map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+            .collect()
+    }
+}
+
+struct Side {
+    deltas: Vec<f32>,
+    last: f32,
+    integrator: f32,
+    capacitor: f32,
+}
+
+impl Side {
+    fn new() -> Self {
+        Side {
+            deltas: Vec::new(),
+            last: 0.0,
+            integrator: 0.0,
+            capacitor: 0.0,
+        }
+    }
+
+    fn add_delta(&mut self, index: usize, kernel: &[f32; KERNEL_WIDTH], amplitude: f32) {
+        let delta = amplitude - self.last;
+        if delta == 0.0 {
+            return;
+        }
+        self.last = amplitude;
+
+        if self.deltas.len() < index + KERNEL_WIDTH {
+            self.deltas.resize(index + KERNEL_WIDTH, 0.0);
+        }
+        for (tap, weight) in kernel.iter().enumerate() {
+            self.deltas[index + tap] += delta * weight;
+        }
+    }
+
+    fn output(&mut self, index: usize, charge_factor: f32) -> f32 {
+        if let Some(delta) = self.deltas.get(index) {
+            self.integrator += *delta;
+        }
+
+        let out = self.integrator - self.capacitor;
+        self.capacitor = self.integrator - out * charge_factor;
+        out
+    }
+
+    fn discard(&mut self, count: usize) {
+        let count = count.min(self.deltas.len());
+        self.deltas.drain(..count);
+    }
+}
+
+/// Builds a Blackman-windowed sinc impulse for each sub-sample phase, each
+/// normalized so a step integrates to exactly its height.
+fn build_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+    let half_width = KERNEL_WIDTH as f64 / 2.0;
+
+    (0..PHASES).map(|phase| {
+        let offset = phase as f64 / PHASES as f64;
+
+        let mut taps = [0.0f64; KERNEL_WIDTH];
+        for (tap, weight) in taps.iter_mut().enumerate() {
+            let x = tap as f64 - half_width + 1.0 - offset;
+            let sinc = if x == 0.0 {
+                CUTOFF
+            } else {
+                (PI * CUTOFF * x).sin() / (PI * x)
+            };
+            let window = 0.42 + 0.5 * (PI * x / half_width).cos() +
+                0.08 * (2.0 * PI * x / half_width).cos();
+            *weight = sinc * window;
+        }
+
+        let sum: f64 = taps.iter().sum();
+        let mut kernel = [0.0f32; KERNEL_WIDTH];
+        for (out, weight) in kernel.iter_mut().zip(taps.iter()) {
+            *out = (weight / sum) as f32;
+        }
+        kernel
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use apu::{Apu, StereoSample, Voice};
+
+    fn constant(level: f32, count: usize) -> Vec<StereoSample> {
+        vec![StereoSample { left: level, right: -level }; count]
+    }
+
+    #[test]
+    fn produces_the_output_rate() {
+        for &rate in [44_100, 48_000].iter() {
+            let mut resampler = Resampler::new(rate);
+            // A second of input, in uneven chunks
+            for chunk in constant(0.0, apu::SAMPLE_RATE as usize).chunks(17_556) {
+                resampler.push(chunk);
+            }
+            let frames = resampler.available() as i64;
+            assert!((frames - rate as i64).abs() <= 1, "{} frames at {} Hz", frames, rate);
+            assert_eq!(resampler.read_f32().len(), frames as usize * 2);
+            assert_eq!(resampler.available(), 0);
+        }
+    }
+
+    #[test]
+    fn rate_adjust_scales_the_output() {
+        let mut resampler = Resampler::new(48_000);
+        resampler.set_rate_adjust(1.5);
+        resampler.push(&constant(0.0, apu::SAMPLE_RATE as usize / 10));
+        assert!((resampler.available() as i64 - 7_200).abs() <= 1);
+    }
+
+    #[test]
+    fn high_pass_decays_dc_to_silence() {
+        let mut resampler = Resampler::new(48_000);
+        resampler.push(&constant(0.5, apu::SAMPLE_RATE as usize / 10));
+        let output = resampler.read_f32();
+        assert!(output[0..64].chunks(2).any(|frame| frame[0] > 0.45 && frame[1] < -0.45));
+        resampler.push(&constant(0.5, apu::SAMPLE_RATE as usize));
+        let output = resampler.read_f32();
+        assert!(output[output.len() - 2..].iter().all(|sample| sample.abs() < 1e-4), "{:?}", &output[output.len() - 2..]);
+    }
+
+    // A second of pulse A, square at full volume, through a resampler
+    fn pulse_a(configure: &dyn Fn(&mut Apu)) -> Vec<f32> {
+        let mut apu = Apu::new();
+        for &(addr, value) in [(0xFF26, 0x80), (0xFF24, 0x77), (0xFF25, 0x11), (0xFF11, 0x80),
+                                (0xFF12, 0xF0), (0xFF13, 0x00), (0xFF14, 0x87)].iter() {
+            apu.write_byte(addr, value);
+        }
+        configure(&mut apu);
+        apu.step(4 * apu::SAMPLE_RATE / 10);
+
+        let mut resampler = Resampler::new(48_000);
+        resampler.push(&apu.take_samples());
+        resampler.read_f32()
+    }
+
+    #[test]
+    fn muted_voices_are_silent() {
+        assert!(pulse_a(&|_| {}).iter().any(|&sample| sample.abs() > 0.1));
+        assert!(pulse_a(&|apu| apu.set_voice_muted(Voice::PulseA, true)).iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn voice_volume_scales_the_output() {
+        let normal = pulse_a(&|_| {});
+        let doubled = pulse_a(&|apu| apu.set_voice_volume(Voice::PulseA, 2.0));
+        let halved = pulse_a(&|apu| apu.set_voice_volume(Voice::PulseA, 0.5));
+        for ((&normal, &doubled), &halved) in normal.iter().zip(doubled.iter()).zip(halved.iter()) {
+            assert!((doubled - normal * 2.0).abs() < 1e-4);
+            assert!((halved - normal * 0.5).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Rewind history: a ring of recent save states. Only the newest is kept
+//! whole; each older one is stored as its XOR difference from the next, with
+//! runs of unchanged bytes collapsed, so a frame's worth of change costs a
+//! few kilobytes rather than a whole state.
+
+use std::collections::VecDeque;
+
+use super::gameboy::GameBoy;
+use super::savestate::StateError;
+
+pub struct Rewind {
+    interval: u32,
+    capacity: usize,
+    frames: u32,
+
+    newest: Option<Vec<u8>>,
+    // Oldest first; the last one turns `newest` into the snapshot before it
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    /// Keeps up to `capacity` snapshots, taken every `interval` frames.
+    pub fn new(capacity: usize, interval: u32) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            frames: 0,
+
+            newest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Frames between snapshots.
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    /// Call once per emulated frame.
+    pub fn record(&mut self, game_boy: &GameBoy) {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return;
+        }
+        self.frames = 0;
+
+        let state = game_boy.save_state();
+        if let Some(newest) = self.newest.take() {
+            if newest.len() == state.len() {
+                self.deltas.push_back(encode_delta(&newest, &state));
+                while self.deltas.len() >= self.capacity {
+                    self.deltas.pop_front();
+                }
+            } else {
+                self.deltas.clear();
+            }
+        }
+        self.newest = Some(state);
+    }
+
+    /// Restores the newest snapshot and forgets it, so repeated calls walk
+    /// backwards. Returns false once the history runs out. A snapshot that
+    /// fails to load clears the history.
+    pub fn step_back(&mut self, game_boy: &mut GameBoy) -> Result<bool, StateError> {
+        let state = match self.newest.take() {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+
+        if let Err(err) = game_boy.load_state(&state) {
+            self.clear();
+            return Err(err);
+        }
+
+        self.newest = self.deltas.pop_back().map(|delta| apply_delta(&state, &delta));
+        self.frames = 0;
+        Ok(true)
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.frames = 0;
+    }
+}
+
+// Alternating runs of unchanged and changed bytes: the length of each as a
+// varint, followed by the changed bytes XORed together
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut i = 0;
+    while i < new.len() {
+        let start = i;
+        while i < new.len() && old[i] == new[i] {
+            i += 1;
+        }
+        write_varint(&mut delta, i - start);
+
+        let start = i;
+        while i < new.len() && old[i] != new[i] {
+            i += 1;
+        }
+        write_varint(&mut delta, i - start);
+        delta.extend(old[start..i].iter().zip(&new[start..i]).map(|(a, b)| a ^ b));
+    }
+    delta
+}
+
+fn apply_delta(state: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut result = state.to_vec();
+    let mut i = 0;
+    let mut position = 0;
+    while position < delta.len() {
+        i += read_varint(delta, &mut position);
+        let changed = read_varint(delta, &mut position);
+        for byte in &mut result[i..i + changed] {
+            *byte ^= delta[position];
+            position += 1;
+        }
+        i += changed;
+    }
+    result
+}
+
+fn write_varint(out: &mut Vec<u8>, value: usize) {
+    let mut value = value;
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*position];
+        *position += 1;
+        value |= (byte as usize & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gameboy::tests::counting_game_boy;
+
+    #[test]
+    fn deltas_round_trip() {
+        let old: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let mut new = old.clone();
+        new[0] ^= 0xFF;
+        new[500..700].iter_mut().for_each(|byte| *byte = byte.wrapping_add(1));
+        new[999] = 0;
+
+        let delta = encode_delta(&old, &new);
+        assert!(delta.len() < 250);
+        assert_eq!(apply_delta(&new, &delta), old);
+        assert_eq!(apply_delta(&old, &delta), new);
+    }
+
+    #[test]
+    fn identical_states_have_an_empty_delta() {
+        let state = vec![0x5A; 300];
+        let delta = encode_delta(&state, &state);
+        assert_eq!(delta, vec![0xAC, 0x02, 0x00]);
+        assert_eq!(apply_delta(&state, &delta), state);
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for &value in [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, 0x12345678].iter() {
+            let mut data = Vec::new();
+            write_varint(&mut data, value);
+            let mut position = 0;
+            assert_eq!(read_varint(&data, &mut position), value);
+            assert_eq!(position, data.len());
+        }
+    }
+
+    #[test]
+    fn steps_back_through_snapshots() {
+        let mut game_boy = counting_game_boy("REWIND");
+        let mut rewind = Rewind::new(10, 2);
+        let mut states = Vec::new();
+        for frame in 1..=6 {
+            game_boy.run_frame().unwrap();
+            rewind.record(&game_boy);
+            if frame % 2 == 0 {
+                states.push(game_boy.save_state());
+            }
+        }
+
+        for state in states.iter().rev() {
+            assert!(rewind.step_back(&mut game_boy).unwrap());
+            assert_eq!(&game_boy.save_state(), state);
+        }
+        assert!(!rewind.step_back(&mut game_boy).unwrap());
+    }
+}
@@ -0,0 +1,368 @@
+//! Save states: a snapshot of the whole machine.
+//!
+//! A state file starts with a header, all integers little-endian:
+//!
+//! ```text
+//! "RBST" | format version (u16) | emulator version | ROM title
+//!        | ROM CRC-32 (u32) | thumbnail (2 bits per pixel, 160x144)
+//!        | CRC-32 of the rest of the file (u32)
+//! ```
+//!
+//! with strings stored as a u16 length followed by UTF-8. The machine
+//! follows, each component writing its fields in a fixed order. Bumping
+//! `VERSION` is required whenever that order changes.
+
+use std::error;
+use std::fmt;
+
+use crc32fast;
+
+use super::gpu::SCREEN_PIXELS;
+
+const MAGIC: &[u8; 4] = b"RBST";
+pub const VERSION: u16 = 2;
+
+#[derive(Debug)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u16),
+    RomMismatch { state_title: String, state_checksum: u32, rom_title: String, rom_checksum: u32 },
+    LinkMismatch { linked: bool },
+    Truncated,
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "save state format {} is not supported, expected {}", version, VERSION)
+            },
+            StateError::RomMismatch { ref state_title, state_checksum, ref rom_title, rom_checksum } => {
+                write!(f, "save state is for '{}' (CRC-32 {:08X}), not the loaded ROM '{}' (CRC-32 {:08X})",
+                       state_title, state_checksum, rom_title, rom_checksum)
+            },
+            StateError::LinkMismatch { linked: true } => {
+                write!(f, "save state was made with a linked Game Boy, run with --link to load it")
+            },
+            StateError::LinkMismatch { linked: false } => {
+                write!(f, "save state was made without a linked Game Boy")
+            },
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Corrupt(what) => write!(f, "save state is corrupt: invalid {}", what),
+        }
+    }
+}
+
+impl error::Error for StateError {}
+
+/// The part of a state file describing it, readable without loading it.
+pub struct Header {
+    pub version: u16,
+    pub emulator_version: String,
+    pub rom_title: String,
+    pub rom_checksum: u32,
+    /// The screen when the state was saved, one shade per pixel.
+    pub thumbnail: Vec<u8>,
+}
+
+impl Header {
+    pub fn new(rom_title: &str, rom: &[u8], frame: &[u8]) -> Self {
+        Header {
+            version: VERSION,
+            emulator_version: env!("CARGO_PKG_VERSION").to_string(),
+            rom_title: rom_title.to_string(),
+            rom_checksum: crc32fast::hash(rom),
+            thumbnail: frame.to_vec(),
+        }
+    }
+
+    pub fn write(&self, state: &mut StateWriter) {
+        state.write_bytes(MAGIC);
+        state.write_u16(self.version);
+        state.write_string(&self.emulator_version);
+        state.write_string(&self.rom_title);
+        state.write_u32(self.rom_checksum);
+
+        for pixels in self.thumbnail.chunks(4) {
+            let packed = pixels.iter().enumerate().fold(0, |packed, (i, &shade)| packed | (shade & 0x03) << (i * 2));
+            state.write_u8(packed);
+        }
+    }
+
+    pub fn read(state: &mut StateReader) -> Result<Self, StateError> {
+        let mut magic = [0; 4];
+        state.read_bytes(&mut magic).map_err(|_| StateError::NotAState)?;
+        if &magic != MAGIC {
+            return Err(StateError::NotAState);
+        }
+
+        let version = state.read_u16()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let emulator_version = state.read_string()?;
+        let rom_title = state.read_string()?;
+        let rom_checksum = state.read_u32()?;
+
+        let mut thumbnail = Vec::with_capacity(SCREEN_PIXELS);
+        for _ in 0..SCREEN_PIXELS / 4 {
+            let packed = state.read_u8()?;
+            for i in 0..4 {
+                thumbnail.push(packed >> (i * 2) & 0x03);
+            }
+        }
+
+        Ok(Header {
+            version,
+            emulator_version,
+            rom_title,
+            rom_checksum,
+            thumbnail,
+        })
+    }
+
+    /// Fails unless the state was saved with the same ROM.
+    pub fn check_rom(&self, rom_title: &str, rom: &[u8]) -> Result<(), StateError> {
+        let rom_checksum = crc32fast::hash(rom);
+        if rom_checksum != self.rom_checksum {
+            return Err(StateError::RomMismatch {
+                state_title: self.rom_title.clone(),
+                state_checksum: self.rom_checksum,
+                rom_title: rom_title.to_string(),
+                rom_checksum,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A component that can be saved to and restored from a state.
+pub trait Savable {
+    fn save(&self, state: &mut StateWriter);
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter {
+            data: Vec::new(),
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.write_bytes(&[value as u8, (value >> 8) as u8]);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.write_u16(value as u16);
+        self.write_u16((value >> 16) as u16);
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_u32(value as u32);
+        self.write_u32((value >> 32) as u32);
+    }
+
+    pub fn write_i32(&mut self, value: i32) {
+        self.write_u32(value as u32);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn write_string(&mut self, value: &str) {
+        self.write_u16(value.len() as u16);
+        self.write_bytes(value.as_bytes());
+    }
+
+    /// Writes `payload` after its CRC-32, for `verify_checksum` to check
+    /// before anything in it is decoded.
+    pub fn write_checksummed(&mut self, payload: &[u8]) {
+        self.write_u32(crc32fast::hash(payload));
+        self.write_bytes(payload);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader {
+            data,
+            position: 0,
+        }
+    }
+
+    /// Reads the CRC-32 written by `write_checksummed` and checks the rest
+    /// of the data against it.
+    pub fn verify_checksum(&mut self) -> Result<(), StateError> {
+        let checksum = self.read_u32()?;
+        if crc32fast::hash(&self.data[self.position..]) != checksum {
+            return Err(StateError::Corrupt("checksum"));
+        }
+        Ok(())
+    }
+
+    /// Bytes left to read, to check lengths against before allocating.
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        let value = *self.data.get(self.position).ok_or(StateError::Truncated)?;
+        self.position += 1;
+        Ok(value)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt("flag")),
+        }
+    }
+
+    /// Reads one of `count` enum variants.
+    pub fn read_variant(&mut self, count: u8, what: &'static str) -> Result<u8, StateError> {
+        let value = self.read_u8()?;
+        if value < count { Ok(value) } else { Err(StateError::Corrupt(what)) }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(self.read_u8()? as u16 | (self.read_u8()? as u16) << 8)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(self.read_u16()? as u32 | (self.read_u16()? as u32) << 16)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(self.read_u32()? as u64 | (self.read_u32()? as u64) << 32)
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32, StateError> {
+        Ok(self.read_u32()? as i32)
+    }
+
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        let end = self.position + bytes.len();
+        if end > self.data.len() {
+            return Err(StateError::Truncated);
+        }
+        bytes.copy_from_slice(&self.data[self.position..end]);
+        self.position = end;
+        Ok(())
+    }
+
+    pub fn read_string(&mut self) -> Result<String, StateError> {
+        let mut bytes = vec![0; self.read_u16()? as usize];
+        self.read_bytes(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|_| StateError::Corrupt("string"))
+    }
+
+    /// Fails if anything is left over, which means the state was written
+    /// by a different layout.
+    pub fn finish(&self) -> Result<(), StateError> {
+        if self.position == self.data.len() {
+            Ok(())
+        } else {
+            Err(StateError::Corrupt("length"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gameboy::tests::counting_game_boy;
+
+    #[test]
+    fn header_round_trips() {
+        let frame: Vec<u8> = (0..SCREEN_PIXELS).map(|i| (i % 4) as u8).collect();
+        let mut state = StateWriter::new();
+        Header::new("TITLE", b"rom", &frame).write(&mut state);
+        let data = state.into_bytes();
+
+        let header = Header::read(&mut StateReader::new(&data)).unwrap();
+        assert_eq!(header.version, VERSION);
+        assert_eq!(header.rom_title, "TITLE");
+        assert_eq!(header.thumbnail, frame);
+        assert!(header.check_rom("TITLE", b"rom").is_ok());
+        assert!(header.check_rom("TITLE", b"other rom").is_err());
+    }
+
+    #[test]
+    fn machine_round_trips() {
+        let mut game_boy = counting_game_boy("COUNT");
+        game_boy.run_frames(3).unwrap();
+        let state = game_boy.save_state();
+        let registers = game_boy.registers();
+
+        game_boy.run_frames(2).unwrap();
+        game_boy.load_state(&state).unwrap();
+        assert_eq!(game_boy.registers(), registers);
+        assert_eq!(game_boy.save_state(), state);
+    }
+
+    #[test]
+    fn corrupt_states_are_refused() {
+        let mut game_boy = counting_game_boy("COUNT");
+        game_boy.run_frames(1).unwrap();
+        let state = game_boy.save_state();
+
+        let mut flipped = state.clone();
+        let last = flipped.len() - 1;
+        flipped[last] ^= 0x01;
+        match game_boy.load_state(&flipped) {
+            Err(StateError::Corrupt("checksum")) => {},
+            other => panic!("expected a checksum error, got {:?}", other.err()),
+        }
+
+        assert!(game_boy.load_state(&state[..state.len() - 1]).is_err());
+        assert!(game_boy.load_state(&state[..10]).is_err());
+        match game_boy.load_state(b"not a state") {
+            Err(StateError::NotAState) => {},
+            other => panic!("expected NotAState, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn states_for_other_roms_are_refused() {
+        let state = counting_game_boy("ONE").save_state();
+        match counting_game_boy("TWO").load_state(&state) {
+            Err(StateError::RomMismatch { .. }) => {},
+            other => panic!("expected a ROM mismatch, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn reader_reports_truncation() {
+        let mut reader = StateReader::new(&[0x01]);
+        assert_eq!(reader.remaining(), 1);
+        assert!(reader.read_u16().is_err());
+    }
+}
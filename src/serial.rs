@@ -0,0 +1,319 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::path::Path;
+
+use super::savestate::{Savable, StateError, StateReader, StateWriter};
+
+// At 8192 Hz each bit takes 512 T-cycles to shift out
+const CYCLES_PER_BIT: u32 = 512;
+
+const CONTROL_TRANSFER: u8 = 1 << 7;
+const CONTROL_INTERNAL_CLOCK: u8 = 1 << 0;
+
+/// Whatever sits at the other end of the link port.
+pub trait SerialDevice {
+    /// The Game Boy drives the clock and shifts `outgoing` out. Returns the
+    /// byte shifted back in; an unconnected port reads 0xFF.
+    fn transfer(&mut self, outgoing: u8) -> u8;
+
+    /// Called while the Game Boy waits on an external clock with `outgoing`
+    /// in SB. Returns the incoming byte once the device has clocked a whole
+    /// transfer.
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        let _ = outgoing;
+        None
+    }
+
+    /// Called when the Game Boy stops waiting on an external clock before a
+    /// transfer arrived, so the device no longer offers its byte.
+    fn cancel(&mut self) {}
+}
+
+/// Nothing plugged in.
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn transfer(&mut self, _outgoing: u8) -> u8 {
+        0xFF
+    }
+}
+
+/// Prints every byte sent as a character, which is how test ROMs such as
+/// Blargg's report their results.
+pub struct StdoutLogger;
+
+impl SerialDevice for StdoutLogger {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        let _ = stdout.write_all(&[outgoing]);
+        let _ = stdout.flush();
+        0xFF
+    }
+}
+
+/// Writes every byte sent to a file.
+pub struct FileLogger {
+    file: fs::File,
+}
+
+impl FileLogger {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(FileLogger {
+            file: fs::File::create(path)?,
+        })
+    }
+}
+
+impl SerialDevice for FileLogger {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        if let Err(err) = self.file.write_all(&[outgoing]) {
+            eprintln!("Serial log write failed: {}", err);
+        }
+        0xFF
+    }
+}
+
+/// The SB (0xFF01) and SC (0xFF02) registers.
+pub struct Serial {
+    data: u8,
+    control: u8,
+    incoming: u8,
+    bits_remaining: u8,
+    timer: u32,
+    device: Box<dyn SerialDevice>,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Serial::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            data: 0,
+            control: 0,
+            incoming: 0,
+            bits_remaining: 0,
+            timer: 0,
+            device: Box::new(Disconnected),
+        }
+    }
+
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    /// Unplugs the current device, leaving the port disconnected.
+    pub fn disconnect(&mut self) -> Box<dyn SerialDevice> {
+        mem::replace(&mut self.device, Box::new(Disconnected))
+    }
+
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        match addr & 0xFF {
+            0x01 => self.data,
+            0x02 => self.control | 0x7E,
+            _ => panic!("Invalid serial read at {:#06x}", addr)
+        }
+    }
+
+    pub fn write_byte(&mut self, addr: u16, value: u8) {
+        match addr & 0xFF {
+            0x01 => self.data = value,
+            0x02 => {
+                let was_waiting = self.control == CONTROL_TRANSFER;
+                self.control = value & (CONTROL_TRANSFER | CONTROL_INTERNAL_CLOCK);
+                if was_waiting && self.control != CONTROL_TRANSFER {
+                    self.device.cancel();
+                }
+
+                if self.control == CONTROL_TRANSFER | CONTROL_INTERNAL_CLOCK {
+                    // The device answers straight away; the reply is then
+                    // shifted in a bit at a time as the clock runs.
+                    self.incoming = self.device.transfer(self.data);
+                    self.bits_remaining = 8;
+                    self.timer = CYCLES_PER_BIT;
+                }
+            },
+            _ => panic!("Invalid serial write at {:#06x}: {:#04x}", addr, value)
+        }
+    }
+
+    /// Advances an ongoing transfer. Returns true when one completes and the
+    /// serial interrupt should be requested.
+    pub fn step(&mut self, cycles: u32) -> bool {
+        if self.control & CONTROL_TRANSFER == 0 {
+            return false;
+        }
+
+        if self.control & CONTROL_INTERNAL_CLOCK == 0 {
+            return match self.device.poll_external(self.data) {
+                Some(incoming) => {
+                    self.data = incoming;
+                    self.control &= !CONTROL_TRANSFER;
+                    true
+                },
+                None => false,
+            };
+        }
+
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = CYCLES_PER_BIT;
+
+            self.data = self.data << 1 | self.incoming >> 7;
+            self.incoming <<= 1;
+            self.bits_remaining -= 1;
+
+            if self.bits_remaining == 0 {
+                self.control &= !CONTROL_TRANSFER;
+                return true;
+            }
+        }
+        self.timer -= cycles;
+
+        false
+    }
+}
+
+// Only the registers are saved; whatever is plugged in stays plugged in
+impl Savable for Serial {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(self.data);
+        state.write_u8(self.control);
+        state.write_u8(self.incoming);
+        state.write_u8(self.bits_remaining);
+        state.write_u32(self.timer);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let was_waiting = self.control == CONTROL_TRANSFER;
+        self.data = state.read_u8()?;
+        self.control = state.read_u8()? & (CONTROL_TRANSFER | CONTROL_INTERNAL_CLOCK);
+        if was_waiting && self.control != CONTROL_TRANSFER {
+            self.device.cancel();
+        }
+        self.incoming = state.read_u8()?;
+        self.bits_remaining = state.read_u8()?;
+        self.timer = state.read_u32()?;
+        if self.bits_remaining > 8 || self.timer > CYCLES_PER_BIT {
+            return Err(StateError::Corrupt("serial transfer"));
+        }
+        // A transfer on the internal clock always has a bit left to shift
+        let shifting = self.control == CONTROL_TRANSFER | CONTROL_INTERNAL_CLOCK;
+        if shifting && (self.bits_remaining == 0 || self.timer == 0) {
+            return Err(StateError::Corrupt("serial transfer"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Answers every transfer with the same byte
+    struct Echo(u8);
+
+    impl SerialDevice for Echo {
+        fn transfer(&mut self, _outgoing: u8) -> u8 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn shifts_a_bit_in_every_512_cycles() {
+        let mut serial = Serial::new();
+        serial.connect(Box::new(Echo(0x3C)));
+        serial.write_byte(0xFF01, 0xA5);
+        serial.write_byte(0xFF02, 0x81);
+
+        assert!(!serial.step(CYCLES_PER_BIT * 4 - 1));
+        assert_eq!(serial.read_byte(0xFF01), 0x29);
+        assert!(!serial.step(1));
+        assert_eq!(serial.read_byte(0xFF01), 0x53);
+        assert!(serial.step(CYCLES_PER_BIT * 4));
+        assert_eq!(serial.read_byte(0xFF01), 0x3C);
+        assert_eq!(serial.read_byte(0xFF02) & 0x80, 0);
+    }
+
+    #[test]
+    fn external_clock_waits_for_the_device() {
+        let mut serial = Serial::new();
+        serial.write_byte(0xFF01, 0x12);
+        serial.write_byte(0xFF02, 0x80);
+        assert!(!serial.step(CYCLES_PER_BIT * 16));
+        assert_eq!(serial.read_byte(0xFF01), 0x12);
+    }
+
+    // Counts the waits given up on
+    struct Cancels(Rc<Cell<u32>>);
+
+    impl SerialDevice for Cancels {
+        fn transfer(&mut self, _outgoing: u8) -> u8 {
+            0xFF
+        }
+
+        fn cancel(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn giving_up_an_external_wait_cancels_it() {
+        let cancels = Rc::new(Cell::new(0));
+        let mut serial = Serial::new();
+        serial.connect(Box::new(Cancels(cancels.clone())));
+
+        serial.write_byte(0xFF02, 0x80);
+        serial.write_byte(0xFF02, 0x00);
+        assert_eq!(cancels.get(), 1);
+
+        serial.write_byte(0xFF02, 0x80);
+        serial.write_byte(0xFF02, 0x80);
+        serial.write_byte(0xFF02, 0x81);
+        assert_eq!(cancels.get(), 2);
+
+        serial.write_byte(0xFF02, 0x01);
+        serial.write_byte(0xFF02, 0x00);
+        assert_eq!(cancels.get(), 2);
+    }
+
+    #[test]
+    fn load_rejects_a_transfer_with_nothing_left_to_shift() {
+        let mut state = StateWriter::new();
+        state.write_u8(0x00);
+        state.write_u8(0x81);
+        state.write_u8(0x00);
+        state.write_u8(0);
+        state.write_u32(CYCLES_PER_BIT);
+        let data = state.into_bytes();
+        assert!(Serial::new().load(&mut StateReader::new(&data)).is_err());
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let mut serial = Serial::new();
+        serial.connect(Box::new(Echo(0xFF)));
+        serial.write_byte(0xFF01, 0x42);
+        serial.write_byte(0xFF02, 0x81);
+        serial.step(CYCLES_PER_BIT * 3 + 100);
+
+        let mut state = StateWriter::new();
+        serial.save(&mut state);
+        let data = state.into_bytes();
+        let mut loaded = Serial::new();
+        loaded.load(&mut StateReader::new(&data)).unwrap();
+        assert_eq!(loaded.read_byte(0xFF01), serial.read_byte(0xFF01));
+        assert_eq!(loaded.bits_remaining, 5);
+        assert_eq!(loaded.timer, serial.timer);
+    }
+}
@@ -0,0 +1,199 @@
+//! Symbol files as written by RGBDS and WLA-DX: one `BANK:ADDR Name` per
+//! line, in hex, with `;` comments. WLA's other sections are skipped.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::debugger::rom_bank;
+use super::disasm::Labels;
+
+#[derive(Debug)]
+pub enum SymbolError {
+    Io(io::Error),
+    Invalid { line: usize, text: String },
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SymbolError::Io(ref err) => write!(f, "{}", err),
+            SymbolError::Invalid { line, ref text } => write!(f, "line {}: expected BANK:ADDR NAME, got '{}'", line, text),
+        }
+    }
+}
+
+impl error::Error for SymbolError {}
+
+impl From<io::Error> for SymbolError {
+    fn from(err: io::Error) -> Self {
+        SymbolError::Io(err)
+    }
+}
+
+/// Names for addresses, and addresses for names. Only the switchable ROM
+/// area has more than one bank on a DMG, so everywhere else is bank 0.
+#[derive(Clone, Default)]
+pub struct Symbols {
+    labels: Labels,
+    addrs: HashMap<String, (u16, u16)>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols {
+            labels: Labels::new(),
+            addrs: HashMap::new(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SymbolError> {
+        Symbols::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, SymbolError> {
+        let mut symbols = Symbols::new();
+        let mut in_labels = true;
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                in_labels = line == "[labels]";
+                continue;
+            }
+            if !in_labels {
+                continue;
+            }
+
+            let invalid = || SymbolError::Invalid { line: index + 1, text: line.to_string() };
+            let mut words = line.split_whitespace();
+            let location = words.next().ok_or_else(&invalid)?;
+            let name = words.next().ok_or_else(&invalid)?;
+            let mut parts = location.splitn(2, ':');
+            let bank = parts.next().and_then(|bank| u16::from_str_radix(bank, 16).ok()).ok_or_else(&invalid)?;
+            let addr = parts.next().and_then(|addr| u16::from_str_radix(addr, 16).ok()).ok_or_else(&invalid)?;
+            symbols.insert(bank, addr, name);
+        }
+        Ok(symbols)
+    }
+
+    pub fn insert(&mut self, bank: u16, addr: u16, name: &str) {
+        let key = (if is_banked(addr) { bank } else { 0 }, addr);
+        self.labels.entry(key).or_insert_with(|| name.to_string());
+        self.addrs.insert(name.to_string(), key);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    pub fn labels(&self) -> &Labels {
+        &self.labels
+    }
+
+    /// The bank and address of `name`.
+    pub fn lookup(&self, name: &str) -> Option<(u16, u16)> {
+        self.addrs.get(name).cloned()
+    }
+
+    /// The name of `addr` as the machine currently has it mapped.
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&key(addr)).map(|name| name.as_str())
+    }
+
+    /// `addr` as an offset from the nearest symbol at or before it in the
+    /// same bank, like `MainLoop+$12`.
+    pub fn describe(&self, addr: u16) -> Option<String> {
+        let (bank, addr) = key(addr);
+        // The nearest key below can belong to a lower bank at a higher address
+        let (&(symbol_bank, symbol_addr), name) = self.labels.range(..=(bank, addr)).next_back()?;
+        if symbol_bank != bank {
+            return None;
+        }
+        match addr.checked_sub(symbol_addr)? {
+            0 => Some(name.clone()),
+            offset if offset < 0x1000 => Some(format!("{}+${:X}", name, offset)),
+            _ => None,
+        }
+    }
+}
+
+fn is_banked(addr: u16) -> bool {
+    (0x4000..0x8000).contains(&addr)
+}
+
+fn key(addr: u16) -> (u16, u16) {
+    (rom_bank(addr), addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "; File created by rgblink\n\
+                       00:0150 Start\n\
+                       00:0160 MainLoop ; comment\n\
+                       01:4000 BankedCode\n\
+                       02:4000 OtherBank\n\
+                       00:C000 wBuffer\n";
+
+    #[test]
+    fn parses_rgbds_files() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.lookup("Start"), Some((0, 0x0150)));
+        assert_eq!(symbols.lookup("MainLoop"), Some((0, 0x0160)));
+        assert_eq!(symbols.lookup("OtherBank"), Some((2, 0x4000)));
+        assert_eq!(symbols.lookup("Missing"), None);
+        assert_eq!(symbols.name(0x0160), Some("MainLoop"));
+        assert_eq!(symbols.name(0x4000), Some("BankedCode"));
+        assert_eq!(symbols.name(0x0161), None);
+    }
+
+    #[test]
+    fn only_rom_is_banked() {
+        let symbols = Symbols::parse("03:C000 wBuffer\n").unwrap();
+        assert_eq!(symbols.lookup("wBuffer"), Some((0, 0xC000)));
+        assert_eq!(symbols.name(0xC000), Some("wBuffer"));
+    }
+
+    #[test]
+    fn skips_wla_sections() {
+        let text = "[information]\nversion 2\n\n[labels]\n00:0150 Start\n[definitions]\n0000000a CONSTANT\n";
+        let symbols = Symbols::parse(text).unwrap();
+        assert_eq!(symbols.lookup("Start"), Some((0, 0x0150)));
+        assert_eq!(symbols.lookup("CONSTANT"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        for text in ["00:0150\n", "0150 Start\n", "zz:0150 Start\n", "00:10000 Start\n"].iter() {
+            match Symbols::parse(&format!("00:0100 Entry\n{}", text)) {
+                Err(SymbolError::Invalid { line: 2, .. }) => {}
+                _ => panic!("accepted {:?}", text),
+            }
+        }
+    }
+
+    #[test]
+    fn describes_offsets() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.describe(0x0150).unwrap(), "Start");
+        assert_eq!(symbols.describe(0x015F).unwrap(), "Start+$F");
+        assert_eq!(symbols.describe(0x4012).unwrap(), "BankedCode+$12");
+        assert_eq!(symbols.describe(0x0100), None);
+        assert_eq!(symbols.describe(0x1160), None);
+    }
+
+    #[test]
+    fn describe_stays_in_the_mapped_bank() {
+        let symbols = Symbols::parse("02:4000 OtherBank\n").unwrap();
+        assert_eq!(symbols.describe(0x4000), None);
+        let symbols = Symbols::parse("00:0150 Start\n").unwrap();
+        assert_eq!(symbols.describe(0x4000), None);
+    }
+}
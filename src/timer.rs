@@ -0,0 +1,83 @@
+use super::savestate::{Savable, StateError, StateReader, StateWriter};
+
+// The APU's frame sequencer runs off the falling edge of DIV bit 4, which is
+// bit 12 of the internal counter.
+const FRAME_SEQUENCER_BIT: u32 = 12;
+
+pub struct Timer {
+    div: u16,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Timer {
+            div: 0,
+        }
+    }
+
+    /// Advances the divider by `cycles` T-cycles and returns how many times
+    /// the frame sequencer should be clocked.
+    pub fn step(&mut self, cycles: u32) -> u32 {
+        let old = self.div as u32;
+        let new = old + cycles;
+        self.div = new as u16;
+
+        (new >> (FRAME_SEQUENCER_BIT + 1)) - (old >> (FRAME_SEQUENCER_BIT + 1))
+    }
+
+    pub fn read_div(&self) -> u8 {
+        (self.div >> 8) as u8
+    }
+
+    /// Any write to DIV resets it. Returns true if that reset produced a
+    /// falling edge for the frame sequencer.
+    pub fn reset_div(&mut self) -> bool {
+        let falling_edge = self.div & (1 << FRAME_SEQUENCER_BIT) != 0;
+        self.div = 0;
+        falling_edge
+    }
+}
+
+impl Savable for Timer {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u16(self.div);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.div = state.read_u16()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_sequencer_clocks_on_div_bit_4_falling() {
+        let mut timer = Timer::new();
+        assert_eq!(timer.step(8191), 0);
+        assert_eq!(timer.step(1), 1);
+        assert_eq!(timer.step(8192 * 3), 3);
+        assert_eq!(timer.read_div(), 0x80);
+    }
+
+    #[test]
+    fn div_wraps_around() {
+        let mut timer = Timer::new();
+        timer.step(0xFFFF);
+        assert_eq!(timer.read_div(), 0xFF);
+        assert_eq!(timer.step(1), 1);
+        assert_eq!(timer.read_div(), 0x00);
+    }
+
+    #[test]
+    fn resetting_div_with_bit_4_set_is_a_falling_edge() {
+        let mut timer = Timer::new();
+        timer.step(0x1000);
+        assert!(timer.reset_div());
+        assert_eq!(timer.read_div(), 0);
+        timer.step(0x0FFF);
+        assert!(!timer.reset_div());
+    }
+}
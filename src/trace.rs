@@ -0,0 +1,87 @@
+//! Instruction traces in the Gameboy Doctor format: one line per
+//! instruction with the registers as they are before it runs, so a run can
+//! be diffed line by line against a reference emulator's.
+
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use super::cpu::Cpu;
+use super::debugger::{parse_hex, rom_bank};
+use super::symbols::Symbols;
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+    // Inclusive PC range to log
+    range: Option<(u16, u16)>,
+    // ROM bank to log, which leaves out code running from RAM
+    bank: Option<u16>,
+    // Names appended to each line, which reference traces won't have
+    symbols: Option<Symbols>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Tracer {
+            out,
+            range: None,
+            bank: None,
+            symbols: None,
+        }
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Tracer::new(Box::new(BufWriter::new(fs::File::create(path)?))))
+    }
+
+    /// Only logs instructions with PC between `start` and `end` inclusive.
+    pub fn set_range(&mut self, start: u16, end: u16) {
+        self.range = Some((start, end));
+    }
+
+    /// Only logs instructions running from this ROM bank.
+    pub fn set_bank(&mut self, bank: u16) {
+        self.bank = Some(bank);
+    }
+
+    /// Ends each line with where PC is in terms of `symbols`.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = Some(symbols);
+    }
+
+    /// Logs the instruction `cpu` is about to execute, if it passes the
+    /// filters.
+    pub(crate) fn log(&mut self, cpu: &Cpu) -> io::Result<()> {
+        let registers = cpu.registers();
+        let pc = registers.pc;
+        if let Some((start, end)) = self.range {
+            if pc < start || pc > end {
+                return Ok(());
+            }
+        }
+        if let Some(bank) = self.bank {
+            if pc >= 0x8000 || rom_bank(pc) != bank {
+                return Ok(());
+            }
+        }
+
+        let pcmem: Vec<String> = (0..4)
+            .map(|offset| format!("{:02X}", cpu.mmu.peek(pc.wrapping_add(offset))))
+            .collect();
+        write!(self.out, "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+               registers.a, registers.f, registers.b, registers.c, registers.d, registers.e,
+               registers.h, registers.l, registers.sp, pc, pcmem.join(","))?;
+        match self.symbols.as_ref().and_then(|symbols| symbols.describe(pc)) {
+            Some(name) => writeln!(self.out, " ; {}", name),
+            None => writeln!(self.out),
+        }
+    }
+}
+
+/// Parses a `START-END` PC range in hex.
+pub fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let mut parts = text.splitn(2, '-');
+    let start = parse_hex(parts.next()?)?;
+    let end = parse_hex(parts.next()?)?;
+    if start <= end { Some((start, end)) } else { None }
+}
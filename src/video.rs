@@ -0,0 +1,299 @@
+use std::mem;
+
+use glium;
+use glium::glutin::surface::WindowSurface;
+use glium::index::{NoIndices, PrimitiveType};
+use glium::texture::{MipmapsOption, RawImage2d, Texture2d, UncompressedFloatFormat};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, Sampler};
+use glium::{Rect, Surface};
+
+use rustboy::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use super::palette;
+
+const VERTEX_SHADER: &str = "
+    #version 140
+
+    in vec2 position;
+    in vec2 tex_coords;
+    out vec2 v_tex_coords;
+
+    void main() {
+        v_tex_coords = tex_coords;
+        gl_Position = vec4(position, 0.0, 1.0);
+    }
+";
+
+const PLAIN_SHADER: &str = "
+    #version 140
+
+    in vec2 v_tex_coords;
+    out vec4 color;
+
+    uniform sampler2D screen;
+
+    void main() {
+        color = texture(screen, v_tex_coords);
+    }
+";
+
+// Darkens the gaps between the LCD's pixels
+const LCD_SHADER: &str = "
+    #version 140
+
+    in vec2 v_tex_coords;
+    out vec4 color;
+
+    uniform sampler2D screen;
+    uniform vec2 screen_size;
+
+    void main() {
+        vec2 cell = fract(v_tex_coords * screen_size);
+        float gap = step(0.15, cell.x) * step(0.15, cell.y);
+        color = texture(screen, v_tex_coords) * mix(0.8, 1.0, gap);
+    }
+";
+
+// The DMG's LCD takes about a frame to change, so moving sprites smear and
+// games flicker objects on alternate frames to make them translucent
+const GHOSTING_SHADER: &str = "
+    #version 140
+
+    in vec2 v_tex_coords;
+    out vec4 color;
+
+    uniform sampler2D screen;
+    uniform sampler2D previous;
+
+    void main() {
+        color = mix(texture(screen, v_tex_coords), texture(previous, v_tex_coords), 0.45);
+    }
+";
+
+// Screen curvature, scanlines and a vignette
+const CRT_SHADER: &str = "
+    #version 140
+
+    in vec2 v_tex_coords;
+    out vec4 color;
+
+    uniform sampler2D screen;
+    uniform vec2 screen_size;
+
+    void main() {
+        vec2 centered = v_tex_coords * 2.0 - 1.0;
+        centered *= 1.0 + 0.04 * (centered.yx * centered.yx);
+        vec2 uv = centered * 0.5 + 0.5;
+
+        if (uv.x < 0.0 || uv.x > 1.0 || uv.y < 0.0 || uv.y > 1.0) {
+            color = vec4(0.0, 0.0, 0.0, 1.0);
+            return;
+        }
+
+        float scanline = 0.75 + 0.25 * abs(sin(uv.y * screen_size.y * 3.14159));
+        float vignette = 1.0 - 0.25 * dot(centered, centered);
+        color = vec4(texture(screen, uv).rgb * scanline * vignette, 1.0);
+    }
+";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Linear,
+}
+
+impl Filter {
+    pub fn from_name(name: &str) -> Option<Filter> {
+        match name {
+            "nearest" => Some(Filter::Nearest),
+            "linear" => Some(Filter::Linear),
+            _ => None,
+        }
+    }
+}
+
+/// Post-processing applied when the frame is drawn.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Shader {
+    Plain,
+    Lcd,
+    Ghosting,
+    Crt,
+}
+
+impl Shader {
+    pub fn from_name(name: &str) -> Option<Shader> {
+        match name {
+            "none" => Some(Shader::Plain),
+            "lcd" => Some(Shader::Lcd),
+            "ghosting" => Some(Shader::Ghosting),
+            "crt" => Some(Shader::Crt),
+            _ => None,
+        }
+    }
+
+    fn source(&self) -> &'static str {
+        match *self {
+            Shader::Plain => PLAIN_SHADER,
+            Shader::Lcd => LCD_SHADER,
+            Shader::Ghosting => GHOSTING_SHADER,
+            Shader::Crt => CRT_SHADER,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Vertex {
+    position: [f32; 2],
+    tex_coords: [f32; 2],
+}
+
+implement_vertex!(Vertex, position, tex_coords);
+
+/// The newest frame of one screen and the one before it, for ghosting.
+struct ScreenTextures {
+    current: Texture2d,
+    previous: Texture2d,
+}
+
+/// Draws frames to the window, side by side for linked machines, scaled to
+/// fit while keeping the aspect ratio.
+pub struct Video {
+    display: glium::Display<WindowSurface>,
+    program: glium::Program,
+    quad: glium::VertexBuffer<Vertex>,
+    screens: Vec<ScreenTextures>,
+
+    palette: palette::Palette,
+    filter: Filter,
+    integer_scale: bool,
+}
+
+impl Video {
+    pub fn new(display: glium::Display<WindowSurface>,
+               screens: usize,
+               palette: palette::Palette,
+               filter: Filter,
+               shader: Shader,
+               integer_scale: bool) -> Result<Self, String> {
+        let program = glium::Program::from_source(&display, VERTEX_SHADER, shader.source(), None)
+            .map_err(|err| format!("Failed to compile shader: {}", err))?;
+
+        // Texture rows run from the top of the screen, GL's from the bottom
+        let quad = glium::VertexBuffer::new(&display, &[
+            Vertex { position: [-1.0, -1.0], tex_coords: [0.0, 1.0] },
+            Vertex { position: [ 1.0, -1.0], tex_coords: [1.0, 1.0] },
+            Vertex { position: [-1.0,  1.0], tex_coords: [0.0, 0.0] },
+            Vertex { position: [ 1.0,  1.0], tex_coords: [1.0, 0.0] },
+        ]).map_err(|err| format!("Failed to create vertex buffer: {:?}", err))?;
+
+        let mut textures = Vec::new();
+        for _ in 0..screens {
+            textures.push(ScreenTextures {
+                current: screen_texture(&display)?,
+                previous: screen_texture(&display)?,
+            });
+        }
+
+        Ok(Video {
+            display,
+            program,
+            quad,
+            screens: textures,
+
+            palette,
+            filter,
+            integer_scale,
+        })
+    }
+
+    pub fn resize(&self, width: u32, height: u32) {
+        self.display.resize((width, height));
+    }
+
+    /// Uploads a new frame for each screen, one shade per pixel.
+    pub fn upload(&mut self, frames: &[&[u8]]) {
+        for (index, frame) in frames.iter().enumerate().take(self.screens.len()) {
+            let pixels = self.palette.frame_to_rgb(frame);
+            let image = RawImage2d::from_raw_rgb(pixels, (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32));
+
+            let textures = &mut self.screens[index];
+            mem::swap(&mut textures.current, &mut textures.previous);
+            textures.current.write(screen_rect(), image);
+        }
+    }
+
+    /// Draws the uploaded frames.
+    pub fn draw(&self) {
+        let mut target = self.display.draw();
+        target.clear_color(0.0, 0.0, 0.0, 1.0);
+
+        let (window_width, window_height) = target.get_dimensions();
+        let screens = self.screens.len() as u32;
+        let (width, height) = self.fit(window_width, window_height, screens);
+        let left = (window_width - width * screens) / 2;
+        let bottom = (window_height - height) / 2;
+
+        let (magnify, minify) = match self.filter {
+            Filter::Nearest => (MagnifySamplerFilter::Nearest, MinifySamplerFilter::Nearest),
+            Filter::Linear => (MagnifySamplerFilter::Linear, MinifySamplerFilter::Linear),
+        };
+
+        for (index, textures) in self.screens.iter().enumerate() {
+            let uniforms = uniform! {
+                screen: Sampler::new(&textures.current).magnify_filter(magnify).minify_filter(minify),
+                previous: Sampler::new(&textures.previous).magnify_filter(magnify).minify_filter(minify),
+                screen_size: [SCREEN_WIDTH as f32, SCREEN_HEIGHT as f32],
+            };
+
+            let params = glium::DrawParameters {
+                viewport: Some(Rect {
+                    left: left + width * index as u32,
+                    bottom,
+                    width,
+                    height,
+                }),
+                ..Default::default()
+            };
+
+            let result = target.draw(&self.quad, NoIndices(PrimitiveType::TriangleStrip), &self.program, &uniforms, &params);
+            if let Err(err) = result {
+                println!("Failed to draw frame: {:?}", err);
+            }
+        }
+
+        if let Err(err) = target.finish() {
+            println!("Failed to present frame: {:?}", err);
+        }
+    }
+
+    // Size of one screen in the window, keeping the aspect ratio and, unless
+    // the window is too small, a whole number scale
+    fn fit(&self, window_width: u32, window_height: u32, screens: u32) -> (u32, u32) {
+        let total_width = SCREEN_WIDTH as f32 * screens as f32;
+        let mut scale = (window_width as f32 / total_width).min(window_height as f32 / SCREEN_HEIGHT as f32);
+        if self.integer_scale && scale >= 1.0 {
+            scale = scale.floor();
+        }
+
+        ((SCREEN_WIDTH as f32 * scale) as u32, (SCREEN_HEIGHT as f32 * scale) as u32)
+    }
+}
+
+fn screen_texture(display: &glium::Display<WindowSurface>) -> Result<Texture2d, String> {
+    Texture2d::empty_with_format(display,
+                                 UncompressedFloatFormat::U8U8U8,
+                                 MipmapsOption::NoMipmap,
+                                 SCREEN_WIDTH as u32,
+                                 SCREEN_HEIGHT as u32)
+        .map_err(|err| format!("Failed to create screen texture: {:?}", err))
+}
+
+fn screen_rect() -> Rect {
+    Rect {
+        left: 0,
+        bottom: 0,
+        width: SCREEN_WIDTH as u32,
+        height: SCREEN_HEIGHT as u32,
+    }
+}
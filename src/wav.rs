@@ -0,0 +1,291 @@
+use std::fs;
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::apu;
+use super::resampler;
+
+// Recordings always use the same rate so runs can be diffed byte for byte
+const RECORDING_SAMPLE_RATE: u32 = 48_000;
+
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+const HEADER_SIZE: u32 = 44;
+// The RIFF size field has to hold the data size plus the rest of the header
+const MAX_DATA_SIZE: u32 = u32::MAX - (HEADER_SIZE - 8);
+
+const VOICE_SUFFIXES: [&str; 4] = ["pulse_a", "pulse_b", "wave", "noise"];
+
+/// A 16-bit stereo PCM WAV file. The header is rewritten after every second
+/// of audio, and when the writer is dropped, so the file stays playable even
+/// if the emulator never exits cleanly. Writes that would take the file past
+/// the format's 4 GiB limit fail instead.
+pub struct WavWriter<W: Write + Seek = io::BufWriter<fs::File>> {
+    file: W,
+    data_size: u32,
+    header_interval: u32,
+    // Data size the header last recorded
+    header_size: u32,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<Self> {
+        WavWriter::new(io::BufWriter::new(fs::File::create(path)?), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(file: W, sample_rate: u32) -> io::Result<Self> {
+        let mut writer = WavWriter {
+            file,
+            data_size: 0,
+            header_interval: sample_rate * CHANNELS as u32 * BITS_PER_SAMPLE as u32 / 8,
+            header_size: 0,
+        };
+        writer.write_header(sample_rate)?;
+        Ok(writer)
+    }
+
+    /// Appends interleaved left/right samples.
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        let size = samples.len() as u64 * 2;
+        if self.data_size as u64 + size > MAX_DATA_SIZE as u64 {
+            self.finish()?;
+            return Err(io::Error::other("WAV file reached its 4 GiB limit"));
+        }
+
+        for sample in samples {
+            self.file.write_all(&le_u16(*sample as u16))?;
+        }
+        self.data_size += size as u32;
+
+        if self.data_size - self.header_size >= self.header_interval {
+            self.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Records the data written so far in the header and flushes it all.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&le_u32(HEADER_SIZE - 8 + self.data_size))?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&le_u32(self.data_size))?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.header_size = self.data_size;
+
+        self.file.flush()
+    }
+
+    fn write_header(&mut self, sample_rate: u32) -> io::Result<()> {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+
+        self.file.write_all(b"RIFF")?;
+        self.file.write_all(&le_u32(HEADER_SIZE - 8))?;
+        self.file.write_all(b"WAVE")?;
+
+        self.file.write_all(b"fmt ")?;
+        self.file.write_all(&le_u32(16))?;
+        self.file.write_all(&le_u16(1))?; // PCM
+        self.file.write_all(&le_u16(CHANNELS))?;
+        self.file.write_all(&le_u32(sample_rate))?;
+        self.file.write_all(&le_u32(sample_rate * block_align as u32))?;
+        self.file.write_all(&le_u16(block_align))?;
+        self.file.write_all(&le_u16(BITS_PER_SAMPLE))?;
+
+        self.file.write_all(b"data")?;
+        self.file.write_all(&le_u32(0))?;
+        self.file.flush()
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        if self.header_size != self.data_size {
+            let _ = self.finish();
+        }
+    }
+}
+
+fn le_u16(value: u16) -> [u8; 2] {
+    [value as u8, (value >> 8) as u8]
+}
+
+fn le_u32(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+struct Track {
+    resampler: resampler::Resampler,
+    writer: WavWriter,
+}
+
+impl Track {
+    fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Track {
+            resampler: resampler::Resampler::new(RECORDING_SAMPLE_RATE),
+            writer: WavWriter::create(path, RECORDING_SAMPLE_RATE)?,
+        })
+    }
+
+    fn push(&mut self, samples: &[apu::StereoSample]) -> io::Result<()> {
+        self.resampler.push(samples);
+        let output = self.resampler.read_i16();
+        self.writer.write_samples(&output)
+    }
+}
+
+/// Records the APU mix, and optionally every voice on its own, to WAV files.
+/// Voice files sit next to the mix: `out.wav` gets `out.pulse_a.wav`,
+/// `out.pulse_b.wav`, `out.wave.wav` and `out.noise.wav`.
+pub struct AudioRecorder {
+    mix: Track,
+    voices: Vec<Track>,
+}
+
+impl AudioRecorder {
+    pub fn create<P: AsRef<Path>>(path: P, record_voices: bool) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        let mut voices = Vec::new();
+        if record_voices {
+            for suffix in VOICE_SUFFIXES.iter() {
+                voices.push(Track::create(voice_path(path, suffix))?);
+            }
+        }
+
+        Ok(AudioRecorder {
+            mix: Track::create(path)?,
+            voices,
+        })
+    }
+
+    pub fn records_voices(&self) -> bool {
+        !self.voices.is_empty()
+    }
+
+    pub fn push(&mut self,
+                samples: &[apu::StereoSample],
+                voice_samples: &[[apu::StereoSample; 4]]) -> io::Result<()> {
+        self.mix.push(samples)?;
+
+        for (i, track) in self.voices.iter_mut().enumerate() {
+            let samples: Vec<apu::StereoSample> = voice_samples.iter()
+                .map(|voices| voices[i])
+                .collect();
+            track.push(&samples)?;
+        }
+        Ok(())
+    }
+}
+
+fn voice_path(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!("{}.{}.wav", stem, suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::io::Cursor;
+    use std::process;
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        data[offset] as u32 | (data[offset + 1] as u32) << 8 | (data[offset + 2] as u32) << 16 |
+            (data[offset + 3] as u32) << 24
+    }
+
+    // The sizes the RIFF and data headers record
+    fn header_sizes(data: &[u8]) -> (u32, u32) {
+        (u32_at(data, 4), u32_at(data, 40))
+    }
+
+    #[test]
+    fn writes_a_pcm_header() {
+        let writer = WavWriter::new(Cursor::new(Vec::new()), 48_000).unwrap();
+        let data = writer.file.get_ref();
+        assert_eq!(data.len(), HEADER_SIZE as usize);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(data, 16), 16);
+        assert_eq!(&data[20..24], &[1, 0, 2, 0]);
+        assert_eq!(u32_at(data, 24), 48_000);
+        assert_eq!(u32_at(data, 28), 48_000 * 4);
+        assert_eq!(&data[32..36], &[4, 0, 16, 0]);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(header_sizes(data), (HEADER_SIZE - 8, 0));
+    }
+
+    #[test]
+    fn rewrites_the_header_every_second() {
+        // 100 frames of four bytes each is a second at this rate
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 100).unwrap();
+        let chunk = vec![0x1234; 100];
+
+        writer.write_samples(&chunk).unwrap();
+        assert_eq!(header_sizes(writer.file.get_ref()), (36, 0));
+        writer.write_samples(&chunk).unwrap();
+        assert_eq!(header_sizes(writer.file.get_ref()), (36 + 400, 400));
+        writer.write_samples(&chunk).unwrap();
+        assert_eq!(header_sizes(writer.file.get_ref()), (36 + 400, 400));
+
+        writer.finish().unwrap();
+        let data = writer.file.get_ref();
+        assert_eq!(header_sizes(data), (36 + 600, 600));
+        assert_eq!(data.len(), HEADER_SIZE as usize + 600);
+        assert_eq!(&data[HEADER_SIZE as usize..HEADER_SIZE as usize + 2], &[0x34, 0x12]);
+    }
+
+    #[test]
+    fn dropping_finishes_the_header() {
+        let mut data = Vec::new();
+        {
+            let mut writer = WavWriter::new(Cursor::new(&mut data), 100).unwrap();
+            writer.write_samples(&[1, -1]).unwrap();
+        }
+        assert_eq!(header_sizes(&data), (36 + 4, 4));
+        assert_eq!(&data[HEADER_SIZE as usize..], &[1, 0, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn refuses_to_pass_4_gib() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 100).unwrap();
+        writer.data_size = MAX_DATA_SIZE - 2;
+        writer.header_size = writer.data_size;
+        assert!(writer.write_samples(&[0]).is_ok());
+        assert!(writer.write_samples(&[0]).is_err());
+        assert_eq!(writer.data_size, MAX_DATA_SIZE);
+        assert_eq!(header_sizes(writer.file.get_ref()), (u32::MAX, MAX_DATA_SIZE));
+    }
+
+    #[test]
+    fn voices_are_recorded_next_to_the_mix() {
+        let dir = env::temp_dir().join(format!("rustboy-wav-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.wav");
+
+        let mut recorder = AudioRecorder::create(&path, true).unwrap();
+        assert!(recorder.records_voices());
+        let sample = apu::StereoSample { left: 0.5, right: -0.5 };
+        recorder.push(&vec![sample; 4096], &vec![[sample; 4]; 4096]).unwrap();
+        drop(recorder);
+
+        let mut names: Vec<String> = fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        let sizes: Vec<(u32, u32)> = names.iter()
+            .map(|name| header_sizes(&fs::read(dir.join(name)).unwrap()))
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(names, ["out.noise.wav", "out.pulse_a.wav", "out.pulse_b.wav", "out.wav", "out.wave.wav"]);
+        assert!(sizes[0].1 > 0);
+        assert!(sizes.iter().all(|&size| size == sizes[0]));
+    }
+}